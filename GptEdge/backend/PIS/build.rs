@@ -0,0 +1,61 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Embeds what the binary was built from, see src/buildinfo.rs. Recipes that
+// build from a tarball or without git set PIS_GIT_COMMIT, usually to
+// ${SRCREV}; SOURCE_DATE_EPOCH keeps the build time reproducible.
+fn main() {
+    println!("cargo:rerun-if-env-changed=PIS_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let commit = env::var("PIS_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_string());
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs())
+        });
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=PIS_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=PIS_BUILT_AT={built_at}");
+    println!("cargo:rustc-env=PIS_TARGET={}", env::var("TARGET").unwrap());
+    println!("cargo:rustc-env=PIS_FEATURES={}", features.join(","));
+}
+
+fn git_commit() -> Option<String> {
+    let output = |args: &[&str]| {
+        let output = Command::new("git").args(args).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = output(&["rev-parse", "HEAD"])?;
+    // Rebuild when HEAD moves to another commit or branch.
+    if let Some(git_dir) = output(&["rev-parse", "--absolute-git-dir"]) {
+        let git_dir = PathBuf::from(git_dir);
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        if let Some(reference) = output(&["symbolic-ref", "-q", "HEAD"]) {
+            println!(
+                "cargo:rerun-if-changed={}",
+                git_dir.join(reference).display()
+            );
+        }
+    }
+    Some(commit)
+}
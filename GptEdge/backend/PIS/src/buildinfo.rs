@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde_derive::Serialize;
+
+// systemd looks at /etc first and falls back to /usr/lib, see os-release(5).
+const OS_RELEASE: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+// Written by Yocto's image-buildinfo class.
+const YOCTO_BUILDINFO: &str = "/etc/buildinfo";
+
+// What the binary was built from, embedded by build.rs.
+#[derive(Debug, Clone, Serialize)]
+pub struct Build {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub built_at: DateTime<Utc>,
+    pub target: &'static str,
+    pub profile: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+// The image the binary runs on. Empty maps when the files are missing, e.g.
+// on a development machine without Yocto.
+#[derive(Debug, Clone, Serialize)]
+pub struct Image {
+    pub os_release: BTreeMap<String, String>,
+    // Build configuration and layer revisions from /etc/buildinfo.
+    pub yocto: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub build: Build,
+    pub image: Image,
+}
+
+impl Image {
+    // PRETTY_NAME, or NAME and VERSION when the image lacks it.
+    pub fn os(&self) -> Option<String> {
+        let value = |key: &str| self.os_release.get(key).cloned();
+        value("PRETTY_NAME").or_else(|| {
+            let name = value("NAME")?;
+            Some(match value("VERSION") {
+                Some(version) => format!("{name} {version}"),
+                None => name,
+            })
+        })
+    }
+}
+
+pub fn build() -> Build {
+    Build {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("PIS_GIT_COMMIT"),
+        built_at: env!("PIS_BUILT_AT")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+            .unwrap_or_default(),
+        target: env!("PIS_TARGET"),
+        profile: if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        },
+        features: env!("PIS_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+    }
+}
+
+// The image only changes with a reboot, so the files are read once.
+pub fn image() -> &'static Image {
+    static IMAGE: OnceLock<Image> = OnceLock::new();
+    IMAGE.get_or_init(|| Image {
+        os_release: OS_RELEASE
+            .iter()
+            .find_map(|path| read(Path::new(path)))
+            .map(|text| parse_os_release(&text))
+            .unwrap_or_default(),
+        yocto: read(Path::new(YOCTO_BUILDINFO))
+            .map(|text| parse_yocto(&text))
+            .unwrap_or_default(),
+    })
+}
+
+pub fn info() -> BuildInfo {
+    BuildInfo {
+        build: build(),
+        image: image().clone(),
+    }
+}
+
+fn read(path: &Path) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(text) => Some(text),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("cannot read {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+// KEY=value lines, with the value optionally quoted in shell style.
+fn parse_os_release(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), unquote(value)))
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+        {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' if quote == '"' => out.extend(chars.next()),
+                    c => out.push(c),
+                }
+            }
+            return out;
+        }
+    }
+    value.to_string()
+}
+
+// `KEY = value` lines between banner lines, e.g. `DISTRO = poky` or
+// `meta = HEAD:bba3233...`.
+fn parse_yocto(text: &str) -> BTreeMap<String, String> {
+    text.lines()
+        .filter_map(|line| line.split_once(" = "))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
@@ -0,0 +1,155 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Parser)]
+#[command(version, about = "PIS edge service")]
+pub struct Args {
+    /// Configuration file (.toml or .json), defaults to /etc/pis/config.toml
+    #[arg(short, long, env = "PIS_CONFIG", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Without a subcommand the service runs, taking the options of `run`
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, clap::Args)]
+pub struct RunArgs {
+    /// Tick period in milliseconds
+    #[arg(long, value_name = "MS")]
+    pub tick_interval_ms: Option<u64>,
+
+    /// Stop after this many ticks
+    #[arg(long, value_name = "N", conflicts_with = "forever")]
+    pub max_iterations: Option<u64>,
+
+    /// Keep ticking until stopped, ignoring any configured iteration limit
+    #[arg(long)]
+    pub forever: bool,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Run the service (the default)
+    Run(RunArgs),
+
+    /// Load and validate the configuration, then exit
+    CheckConfig {
+        /// Print the effective configuration after defaults and overrides
+        #[arg(long)]
+        print: bool,
+    },
+
+    /// Print the built-in default configuration
+    PrintDefaultConfig {
+        /// Print JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Print version information
+    Version {
+        /// Print JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Publish a single message with the configured broker settings
+    MqttPublish {
+        /// Topic, placeholders such as `{client_id}` or `{device_id}` are
+        /// replaced as in the configured topics
+        #[arg(long)]
+        topic: String,
+
+        /// QoS, defaults to mqtt.qos
+        #[arg(long, value_parser = clap::value_parser!(i32).range(0..=2))]
+        qos: Option<i32>,
+
+        /// Ask the broker to retain the message
+        #[arg(long)]
+        retain: bool,
+
+        /// Payload, `-` reads it from stdin
+        message: String,
+    },
+
+    /// Show the state of a running instance
+    Status {
+        /// Address of its HTTP API, defaults to http.listen
+        #[arg(long, value_name = "HOST:PORT")]
+        address: Option<SocketAddr>,
+    },
+
+    /// Check that the configured resources are usable on this device
+    SelfTest,
+
+    /// Print the device identity
+    Identity,
+
+    /// Assign the device to a fleet and vehicle; a running service picks
+    /// the change up on SIGHUP
+    Provision {
+        #[arg(long)]
+        fleet_id: String,
+
+        #[arg(long)]
+        vehicle_id: String,
+
+        /// MQTT user name for this device
+        #[arg(long)]
+        username: Option<String>,
+
+        /// File holding the MQTT password, `-` reads it from stdin
+        #[arg(long, value_name = "PATH", requires = "username")]
+        password_file: Option<PathBuf>,
+
+        /// Replace an existing assignment
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+impl Args {
+    // The service options before a subcommand would be ignored, so they are
+    // rejected unless no subcommand follows. The global --config is fine.
+    pub fn parse_checked() -> Result<Self, clap::Error> {
+        let args = Self::try_parse()?;
+        if args.command.is_some() && args.run != RunArgs::default() {
+            return Err(Self::command().error(
+                ErrorKind::ArgumentConflict,
+                "service options must follow `run`, e.g. `run --max-iterations 3`",
+            ));
+        }
+        Ok(args)
+    }
+
+    fn run_args(&self) -> &RunArgs {
+        match &self.command {
+            Some(Command::Run(run)) => run,
+            _ => &self.run,
+        }
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        let run = self.run_args();
+        if let Some(interval_ms) = run.tick_interval_ms {
+            config.ticker.interval_ms = interval_ms;
+        }
+        if let Some(max_iterations) = run.max_iterations {
+            config.ticker.max_iterations = Some(max_iterations);
+            config.ticker.forever = Some(false);
+        }
+        if run.forever {
+            config.ticker.max_iterations = None;
+            config.ticker.forever = Some(true);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Decompressed payloads larger than this are refused rather than filling up
+// memory.
+const MAX_DECOMPRESSED: u64 = 16 * 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    Msgpack,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+// How a payload is put into bytes. Identified on the wire by a media type
+// and an optional content encoding, as with HTTP.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Codec {
+    pub encoding: Encoding,
+    pub compression: Compression,
+}
+
+impl Codec {
+    pub const JSON: Codec = Codec {
+        encoding: Encoding::Json,
+        compression: Compression::None,
+    };
+
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        let encoded = match self.encoding {
+            Encoding::Json => serde_json::to_vec(value)?,
+            Encoding::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(value, &mut data)?;
+                data
+            }
+            // With field names, so that the receiver needs no schema.
+            Encoding::Msgpack => rmp_serde::to_vec_named(value)?,
+        };
+        self.compress(encoded)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        let data = self.decompress(data)?;
+        Ok(match self.encoding {
+            Encoding::Json => serde_json::from_slice(&data)?,
+            Encoding::Cbor => ciborium::from_reader(&data[..])?,
+            Encoding::Msgpack => rmp_serde::from_slice(&data)?,
+        })
+    }
+
+    // Payloads are JSON until they are sent, and once they are received.
+    pub fn encode_json(&self, json: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            Codec::JSON => Ok(json.to_vec()),
+            _ => self.encode(&serde_json::from_slice::<Value>(json)?),
+        }
+    }
+
+    pub fn decode_json(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            Codec::JSON => Ok(data.to_vec()),
+            _ => Ok(serde_json::to_vec(&self.decode::<Value>(data)?)?),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self.encoding {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+            Encoding::Msgpack => "application/msgpack",
+        }
+    }
+
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self.compression {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+
+    // The codec of a payload labelled with these headers. A missing content
+    // type means JSON.
+    pub fn from_content_type(
+        content_type: Option<&str>,
+        content_encoding: Option<&str>,
+    ) -> Result<Codec> {
+        let encoding = match content_type.map(media_type).as_deref() {
+            None | Some("application/json") => Encoding::Json,
+            Some("application/cbor") => Encoding::Cbor,
+            Some("application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack") => {
+                Encoding::Msgpack
+            }
+            Some(other) => bail!("unsupported content type {other}"),
+        };
+        let compression = match content_encoding.map(|value| value.trim().to_ascii_lowercase()) {
+            None => Compression::None,
+            Some(value) => match value.as_str() {
+                "" | "identity" => Compression::None,
+                "gzip" | "x-gzip" => Compression::Gzip,
+                "zstd" => Compression::Zstd,
+                other => bail!("unsupported content encoding {other}"),
+            },
+        };
+        Ok(Codec {
+            encoding,
+            compression,
+        })
+    }
+
+    // The codec for a reply to a request with these Accept and
+    // Accept-Encoding headers: the supported media type with the highest
+    // quality, and the compression with the highest quality, zstd over gzip
+    // over none when they are equal. Anything else gets JSON.
+    pub fn negotiate(accept: Option<&str>, accept_encoding: Option<&str>) -> Codec {
+        let encoding = accept
+            .into_iter()
+            .flat_map(accepted)
+            .find_map(|(media_type, _)| match media_type.as_str() {
+                "application/json" | "application/*" | "*/*" => Some(Encoding::Json),
+                "application/cbor" => Some(Encoding::Cbor),
+                "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                    Some(Encoding::Msgpack)
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+        let preference = |compression: &Compression| match compression {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+        };
+        let compression = accept_encoding
+            .into_iter()
+            .flat_map(accepted)
+            .filter_map(|(coding, q)| {
+                let compression = match coding.as_str() {
+                    "zstd" | "*" => Compression::Zstd,
+                    "gzip" | "x-gzip" => Compression::Gzip,
+                    "identity" => Compression::None,
+                    _ => return None,
+                };
+                Some((compression, q))
+            })
+            .max_by(|(a, qa), (b, qb)| qa.total_cmp(qb).then(preference(a).cmp(&preference(b))))
+            .map_or(Compression::None, |(compression, _)| compression);
+        Codec {
+            encoding,
+            compression,
+        }
+    }
+
+    fn compress(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        match self.compression {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(&data[..], ZSTD_LEVEL)?),
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self.compression {
+            Compression::None => return Ok(data.to_vec()),
+            Compression::Gzip => flate2::read::GzDecoder::new(data)
+                .take(MAX_DECOMPRESSED + 1)
+                .read_to_end(&mut decompressed)
+                .context("malformed gzip data")?,
+            Compression::Zstd => zstd::Decoder::new(data)?
+                .take(MAX_DECOMPRESSED + 1)
+                .read_to_end(&mut decompressed)
+                .context("malformed zstd data")?,
+        };
+        if decompressed.len() as u64 > MAX_DECOMPRESSED {
+            bail!("decompressed payload exceeds {MAX_DECOMPRESSED} bytes");
+        }
+        Ok(decompressed)
+    }
+}
+
+// Without parameters such as charset, in lower case.
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+// The entries of an Accept style header with their quality, best first and
+// otherwise in order. Those with a quality of 0, or one that cannot be
+// parsed, are left out.
+fn accepted(header: &str) -> Vec<(String, f32)> {
+    let mut entries: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let q = match entry
+                .split(';')
+                .skip(1)
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+            {
+                Some(q) => q.trim().parse::<f32>().ok()?,
+                None => 1.0,
+            };
+            let name = media_type(entry);
+            (!name.is_empty() && q > 0.0).then_some((name, q))
+        })
+        .collect();
+    entries.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Cbor, Encoding::Msgpack];
+    const COMPRESSIONS: [Compression; 3] =
+        [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        counter: u64,
+        name: String,
+        tags: Vec<String>,
+        ratio: Option<f64>,
+    }
+
+    fn codecs() -> impl Iterator<Item = Codec> {
+        ENCODINGS.into_iter().flat_map(|encoding| {
+            COMPRESSIONS.into_iter().map(move |compression| Codec {
+                encoding,
+                compression,
+            })
+        })
+    }
+
+    fn codec(encoding: Encoding, compression: Compression) -> Codec {
+        Codec {
+            encoding,
+            compression,
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let sample = Sample {
+            counter: 42,
+            name: "tick".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            ratio: Some(0.5),
+        };
+        for codec in codecs() {
+            let data = codec.encode(&sample).unwrap();
+            assert_eq!(codec.decode::<Sample>(&data).unwrap(), sample, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn transcodes_json() {
+        let json = br#"{"counter":7,"name":"x","nested":{"list":[1,2,3]}}"#;
+        for codec in codecs() {
+            let encoded = codec.encode_json(json).unwrap();
+            let decoded: Value =
+                serde_json::from_slice(&codec.decode_json(&encoded).unwrap()).unwrap();
+            assert_eq!(
+                decoded,
+                serde_json::from_slice::<Value>(json).unwrap(),
+                "{codec:?}"
+            );
+        }
+        assert_eq!(Codec::JSON.encode_json(b"not json").unwrap(), b"not json");
+        assert!(codec(Encoding::Cbor, Compression::None)
+            .encode_json(b"not json")
+            .is_err());
+    }
+
+    #[test]
+    fn compression_shrinks_repetitive_payloads() {
+        let json = serde_json::to_vec(&vec!["heartbeat"; 100]).unwrap();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = codec(Encoding::Json, compression)
+                .encode_json(&json)
+                .unwrap();
+            assert!(compressed.len() < json.len() / 4, "{compression:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_decompression_bombs() {
+        let zeros = vec![0u8; MAX_DECOMPRESSED as usize + 1];
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let codec = codec(Encoding::Cbor, compression);
+            let bomb = codec.compress(zeros.clone()).unwrap();
+            assert!(bomb.len() < 1024 * 1024, "{compression:?}");
+            let e = codec.decode::<Value>(&bomb).unwrap_err();
+            assert!(e.to_string().contains("exceeds"), "{compression:?}: {e:#}");
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_data() {
+        for codec in codecs().filter(|codec| *codec != Codec::JSON) {
+            assert!(
+                codec.decode::<Sample>(b"\xff\x00garbage").is_err(),
+                "{codec:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_content_types() {
+        assert_eq!(Codec::from_content_type(None, None).unwrap(), Codec::JSON);
+        assert_eq!(
+            Codec::from_content_type(Some("application/json; charset=utf-8"), Some("identity"))
+                .unwrap(),
+            Codec::JSON
+        );
+        assert_eq!(
+            Codec::from_content_type(Some("Application/CBOR"), Some(" gzip ")).unwrap(),
+            codec(Encoding::Cbor, Compression::Gzip)
+        );
+        assert_eq!(
+            Codec::from_content_type(Some("application/x-msgpack"), Some("zstd")).unwrap(),
+            codec(Encoding::Msgpack, Compression::Zstd)
+        );
+        assert!(Codec::from_content_type(Some("text/xml"), None).is_err());
+        assert!(Codec::from_content_type(None, Some("br")).is_err());
+    }
+
+    #[test]
+    fn content_types_parse_back() {
+        for codec in codecs() {
+            let parsed =
+                Codec::from_content_type(Some(codec.content_type()), codec.content_encoding());
+            assert_eq!(parsed.unwrap(), codec);
+        }
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(Codec::negotiate(None, None), Codec::JSON);
+        assert_eq!(
+            Codec::negotiate(Some("text/html, */*"), Some("br")),
+            Codec::JSON
+        );
+        assert_eq!(
+            Codec::negotiate(Some("application/cbor"), Some("gzip, zstd")),
+            codec(Encoding::Cbor, Compression::Zstd)
+        );
+        assert_eq!(
+            Codec::negotiate(
+                Some("application/json;q=0.5, application/msgpack"),
+                Some("zstd;q=0.2, gzip;q=0.8")
+            ),
+            codec(Encoding::Msgpack, Compression::Gzip)
+        );
+        assert_eq!(
+            Codec::negotiate(
+                Some("application/cbor;q=0, application/json"),
+                Some("zstd;q=0")
+            ),
+            Codec::JSON
+        );
+        assert_eq!(
+            Codec::negotiate(None, Some("identity, gzip;q=0.5")),
+            Codec::JSON
+        );
+        // Equal qualities keep the order of the header.
+        assert_eq!(
+            Codec::negotiate(Some("application/msgpack, application/cbor"), None),
+            codec(Encoding::Msgpack, Compression::None)
+        );
+    }
+}
@@ -0,0 +1,152 @@
+mod process;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use tokio::sync::broadcast;
+
+use crate::config::ComponentConfig;
+use crate::mqtt::Publication;
+use crate::supervisor::{Health, Shutdown, Subsystem};
+
+// What components may use of the rest of the service.
+pub struct Context {
+    pub publications: broadcast::Sender<Publication>,
+}
+
+// The `settings` table of a component kind.
+pub trait Settings: DeserializeOwned {
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+type Check = Box<dyn Fn(&Map<String, Value>) -> Result<()> + Send + Sync>;
+type Build = Box<
+    dyn Fn(&'static str, &Map<String, Value>, &Context) -> Result<Arc<dyn Subsystem>> + Send + Sync,
+>;
+
+// Component kinds by name. A kind is a settings type and a constructor for
+// the subsystem; the supervisor takes care of ordering, health checks and
+// restarts.
+pub struct Registry {
+    kinds: BTreeMap<&'static str, (Check, Build)>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            kinds: BTreeMap::new(),
+        }
+    }
+
+    pub fn register<S: Settings + 'static>(
+        &mut self,
+        kind: &'static str,
+        build: fn(&'static str, S, &Context) -> Result<Arc<dyn Subsystem>>,
+    ) {
+        let check: Check = Box::new(|settings| parse::<S>(settings).map(|_| ()));
+        let build: Build =
+            Box::new(move |name, settings, context| build(name, parse(settings)?, context));
+        self.kinds.insert(kind, (check, build));
+    }
+
+    pub fn validate(&self, name: &str, component: &ComponentConfig) -> Result<()> {
+        let Some((check, _)) = self.kinds.get(component.kind.as_str()) else {
+            let kinds: Vec<_> = self.kinds.keys().copied().collect();
+            bail!(
+                "unknown kind {:?}, expected one of {}",
+                component.kind,
+                kinds.join(", ")
+            );
+        };
+        if component
+            .depends_on
+            .iter()
+            .any(|dependency| dependency == name)
+        {
+            bail!("{name} depends on itself");
+        }
+        check(&component.settings).context("invalid settings")
+    }
+
+    // The enabled components, in no particular order.
+    pub fn build(
+        &self,
+        components: &BTreeMap<String, ComponentConfig>,
+        context: &Context,
+    ) -> Result<Vec<Arc<dyn Subsystem>>> {
+        let mut built = Vec::new();
+        for (name, component) in components.iter().filter(|(_, c)| c.enabled) {
+            self.validate(name, component)
+                .with_context(|| format!("invalid components.{name}"))?;
+            let (_, build) = &self.kinds[component.kind.as_str()];
+            // Built once per process, so leaking the names is fine.
+            let name: &'static str = Box::leak(name.clone().into_boxed_str());
+            let inner = build(name, &component.settings, context)
+                .with_context(|| format!("cannot create component {name}"))?;
+            built.push(Arc::new(Component {
+                inner,
+                dependencies: component
+                    .depends_on
+                    .iter()
+                    .map(|dependency| &*Box::leak(dependency.clone().into_boxed_str()))
+                    .collect(),
+            }) as Arc<dyn Subsystem>);
+        }
+        Ok(built)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The component kinds this build knows about.
+pub fn registry() -> Registry {
+    let mut registry = Registry::new();
+    registry.register("process", process::Process::build);
+    registry
+}
+
+fn parse<S: Settings>(settings: &Map<String, Value>) -> Result<S> {
+    let settings: S = serde_json::from_value(Value::Object(settings.clone()))?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+// A configured component: the kind's subsystem plus the configured
+// dependencies.
+struct Component {
+    inner: Arc<dyn Subsystem>,
+    dependencies: Vec<&'static str>,
+}
+
+#[async_trait]
+impl Subsystem for Component {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        self.dependencies.clone()
+    }
+
+    async fn start(&self, shutdown: Shutdown) -> Result<()> {
+        self.inner.start(shutdown).await
+    }
+
+    async fn health(&self) -> Health {
+        self.inner.health().await
+    }
+
+    async fn stop(&self) -> Result<()> {
+        self.inner.stop().await
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::time;
+
+use super::{Context, Settings};
+use crate::config;
+use crate::mqtt::Publication;
+use crate::supervisor::{Health, Shutdown, Subsystem};
+
+// A helper daemon kept running by the service: restarted with a backoff
+// when it exits, and when `health_command` fails `health_failures` times
+// in a row. Its output is logged line by line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessSettings {
+    pub command: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub working_dir: Option<PathBuf>,
+    pub health_command: Option<Vec<String>>,
+    pub health_failures: u32,
+    // Between SIGTERM and SIGKILL when the service stops.
+    pub stop_timeout_secs: u64,
+    // Where starts and exits are published, a topic template like the
+    // configured MQTT topics.
+    pub events_topic: Option<String>,
+}
+
+impl Default for ProcessSettings {
+    fn default() -> Self {
+        ProcessSettings {
+            command: Vec::new(),
+            env: BTreeMap::new(),
+            working_dir: None,
+            health_command: None,
+            health_failures: 3,
+            stop_timeout_secs: 5,
+            events_topic: None,
+        }
+    }
+}
+
+impl Settings for ProcessSettings {
+    fn validate(&self) -> Result<()> {
+        if self.command.is_empty() {
+            bail!("command must not be empty");
+        }
+        if self.health_command.as_ref().is_some_and(Vec::is_empty) {
+            bail!("health_command must not be empty");
+        }
+        if self.health_failures == 0 {
+            bail!("health_failures must be greater than 0");
+        }
+        if let Some(topic) = &self.events_topic {
+            config::validate_topic("events_topic", topic)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    component: &'a str,
+    event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+pub struct Process {
+    name: &'static str,
+    settings: ProcessSettings,
+    publications: broadcast::Sender<Publication>,
+    pid: Mutex<Option<u32>>,
+    failed_checks: AtomicU32,
+}
+
+impl Process {
+    pub fn build(
+        name: &'static str,
+        settings: ProcessSettings,
+        context: &Context,
+    ) -> Result<Arc<dyn Subsystem>> {
+        Ok(Arc::new(Process {
+            name,
+            settings,
+            publications: context.publications.clone(),
+            pid: Mutex::new(None),
+            failed_checks: AtomicU32::new(0),
+        }))
+    }
+
+    fn publish(&self, event: &str, pid: Option<u32>, status: Option<String>) {
+        let Some(topic) = &self.settings.events_topic else {
+            return;
+        };
+        let event = Event {
+            component: self.name,
+            event,
+            pid,
+            status,
+            timestamp: Utc::now(),
+        };
+        let Ok(payload) = serde_json::to_vec(&event) else {
+            return;
+        };
+        // Nobody listening is fine, e.g. when MQTT is disabled.
+        let _ = self.publications.send(Publication {
+            topic: topic.clone(),
+            payload,
+            retain: false,
+        });
+    }
+}
+
+#[async_trait]
+impl Subsystem for Process {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn start(&self, mut shutdown: Shutdown) -> Result<()> {
+        let (program, args) = self.settings.command.split_first().unwrap();
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .envs(&self.settings.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(dir) = &self.settings.working_dir {
+            command.current_dir(dir);
+        }
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("cannot run {program}"))?;
+        let pid = child.id();
+        *self.pid.lock().unwrap() = pid;
+        let _forget = ForgetPid(&self.pid);
+        self.failed_checks.store(0, Ordering::Relaxed);
+        info!(
+            "{} started {program}, pid {}",
+            self.name,
+            pid.unwrap_or_default()
+        );
+        self.publish("started", pid, None);
+        forward(self.name, child.stdout.take());
+        forward(self.name, child.stderr.take());
+
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            _ = shutdown.wait() => {
+                let timeout = Duration::from_secs(self.settings.stop_timeout_secs);
+                let status = match time::timeout(timeout, child.wait()).await {
+                    Ok(status) => status?,
+                    Err(_) => {
+                        warn!("{} did not exit within {timeout:?}, killing it", self.name);
+                        child.kill().await?;
+                        child.wait().await?
+                    }
+                };
+                info!("{} stopped, {status}", self.name);
+                self.publish("stopped", pid, Some(status.to_string()));
+                return Ok(());
+            }
+        };
+        self.publish("exited", pid, Some(status.to_string()));
+        bail!("{program} exited with {status}")
+    }
+
+    async fn health(&self) -> Health {
+        let Some((program, args)) = self
+            .settings
+            .health_command
+            .as_ref()
+            .and_then(|command| command.split_first())
+        else {
+            return Health::Healthy;
+        };
+        let healthy = Command::new(program)
+            .args(args)
+            .envs(&self.settings.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status()
+            .await
+            .is_ok_and(|status| status.success());
+        if healthy {
+            self.failed_checks.store(0, Ordering::Relaxed);
+            return Health::Healthy;
+        }
+        let failed = self.failed_checks.fetch_add(1, Ordering::Relaxed) + 1;
+        let limit = self.settings.health_failures;
+        match failed >= limit {
+            true => Health::Failed(format!("{failed} health checks failed")),
+            false => Health::Degraded(format!("health check failed, {failed} of {limit}")),
+        }
+    }
+
+    // Asks the process to exit; `start` waits for it and kills it when it
+    // does not.
+    async fn stop(&self) -> Result<()> {
+        if let Some(pid) = *self.pid.lock().unwrap() {
+            // SAFETY: kill has no memory-safety requirements.
+            if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("cannot signal pid {pid}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Forgets the pid when `start` returns or is dropped, e.g. aborted after
+// failed health checks, so that `stop` cannot signal another process that
+// got the pid after this one was reaped.
+struct ForgetPid<'a>(&'a Mutex<Option<u32>>);
+
+impl Drop for ForgetPid<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+// Logs what the process writes, one line at a time.
+fn forward(name: &'static str, output: Option<impl AsyncRead + Unpin + Send + 'static>) {
+    let Some(output) = output else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(output).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            info!("{name}: {line}");
+        }
+    });
+}
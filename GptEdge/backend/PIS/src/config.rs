@@ -0,0 +1,1425 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::watch;
+
+use crate::cli::Args;
+use crate::codec::{Codec, Compression, Encoding};
+use crate::{components, identity, logging, mqtt, scheduler, shadow, ticker, update};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/pis/config.toml";
+
+// Environment overrides look like PIS__TICKER__INTERVAL_MS=500, each `__`
+// separated segment selecting one level of the configuration tree.
+const ENV_PREFIX: &str = "PIS__";
+
+// The current configuration. A new value is published whenever SIGHUP
+// reloads it, so subsystems can apply changes without a restart.
+pub type ConfigWatch = watch::Receiver<Arc<Config>>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub service: ServiceConfig,
+    pub state: StateConfig,
+    pub ticker: TickerConfig,
+    pub scheduler: SchedulerConfig,
+    pub mqtt: MqttConfig,
+    pub http: HttpConfig,
+    pub shadow: ShadowConfig,
+    pub provisioning: ProvisioningConfig,
+    pub update: UpdateConfig,
+    pub log: LogConfig,
+    pub watchdog: WatchdogConfig,
+    pub components: BTreeMap<String, ComponentConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    // How long subsystems get to drain in-flight work on shutdown.
+    pub shutdown_timeout_ms: u64,
+    // Writable directory for everything that must survive a reboot.
+    pub state_dir: PathBuf,
+    // Run by the remote `reboot` command.
+    pub reboot_command: Vec<String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            shutdown_timeout_ms: 10_000,
+            state_dir: PathBuf::from("/var/lib/pis"),
+            reboot_command: vec!["systemctl".to_string(), "reboot".to_string()],
+        }
+    }
+}
+
+impl ServiceConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+}
+
+// The state store keeps its files in service.state_dir. Changes are written
+// at most this often, so that much is lost on power loss; a clean shutdown
+// always writes them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    pub flush_interval_secs: u64,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        StateConfig {
+            flush_interval_secs: 10,
+        }
+    }
+}
+
+impl StateConfig {
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.flush_interval_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.flush_interval_secs == 0 {
+            bail!("state.flush_interval_secs must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TickerConfig {
+    pub interval_ms: u64,
+    pub max_iterations: Option<u64>,
+    // When unset, the ticker runs forever unless max_iterations is given.
+    pub forever: Option<bool>,
+}
+
+impl Default for TickerConfig {
+    fn default() -> Self {
+        TickerConfig {
+            interval_ms: 3000,
+            max_iterations: None,
+            forever: None,
+        }
+    }
+}
+
+impl TickerConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    // None means run forever.
+    pub fn iteration_limit(&self) -> Option<u64> {
+        match self.forever {
+            Some(true) => None,
+            _ => self.max_iterations,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.interval_ms == 0 {
+            bail!("ticker.interval_ms must be greater than 0");
+        }
+        match (self.forever, self.max_iterations) {
+            (_, Some(0)) => bail!("ticker.max_iterations must be greater than 0"),
+            (Some(true), Some(_)) => {
+                bail!("ticker.max_iterations cannot be combined with ticker.forever = true")
+            }
+            (Some(false), None) => bail!("ticker.forever = false requires ticker.max_iterations"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overlap {
+    // Drop the new run while the previous one is still going.
+    Skip,
+    // Start it once the previous one finished.
+    Queue,
+    // Cancel the previous run and start the new one.
+    Cancel,
+}
+
+// Runs missed because the device was suspended or the clock jumped forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CatchUp {
+    Skip,
+    // Run once for all of them.
+    Once,
+    // Run each of them in order, up to the last ten.
+    All,
+}
+
+// Jobs the scheduler runs besides the built-in ones, keyed by name.
+// Reloaded on SIGHUP: changed jobs keep a run in progress and use their
+// new settings from the next run on, runs of removed jobs are cancelled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    // Finished runs kept per job for GET /jobs.
+    pub history: usize,
+    pub jobs: BTreeMap<String, JobConfig>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            history: 20,
+            jobs: BTreeMap::new(),
+        }
+    }
+}
+
+impl SchedulerConfig {
+    fn validate(&self) -> Result<()> {
+        for (name, job) in &self.jobs {
+            validate_name("scheduler job", name)?;
+            if name == ticker::JOB {
+                bail!("scheduler.jobs.{name} is reserved for the built-in job");
+            }
+            job.validate()
+                .with_context(|| format!("invalid scheduler.jobs.{name}"))?;
+        }
+        Ok(())
+    }
+}
+
+// `schedule` is `every <duration>` for a fixed rate, `after <duration>` for
+// a fixed delay between the end of one run and the start of the next, or a
+// cron expression in local time, see `scheduler::Cron`. Durations are
+// written like `500ms`, `30s`, `5m`, `2h` or `1d`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobConfig {
+    pub schedule: String,
+    pub command: Vec<String>,
+    pub timeout_secs: Option<u64>,
+    pub overlap: Overlap,
+    pub catch_up: CatchUp,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        JobConfig {
+            schedule: String::new(),
+            command: Vec::new(),
+            timeout_secs: None,
+            overlap: Overlap::Skip,
+            catch_up: CatchUp::Once,
+        }
+    }
+}
+
+impl JobConfig {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_secs.map(Duration::from_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.schedule.parse::<scheduler::Schedule>()?;
+        if self.command.is_empty() {
+            bail!("command must not be empty");
+        }
+        if self.timeout_secs == Some(0) {
+            bail!("timeout_secs must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub uri: String,
+    // "3.1.1", "5", or "auto" to try 5 first and fall back to 3.1.1 with
+    // brokers that refuse it.
+    pub protocol: Protocol,
+    // Tried in order when `uri` cannot be reached. Every reconnect starts
+    // over with `uri`.
+    pub failover_uris: Vec<String>,
+    pub reconnect: ReconnectConfig,
+    // Defaults to the device id.
+    pub client_id: Option<String>,
+    pub keep_alive_secs: u64,
+    pub clean_session: bool,
+    pub connect_timeout_secs: u64,
+    // Used unless provisioning supplied credentials for this device.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // In topics, `{client_id}` is replaced with the MQTT client id and
+    // `{device_id}`, `{fleet_id}`, `{vehicle_id}`, `{machine_id}` and
+    // `{serial_number}` with the device identity.
+    pub heartbeat_topic: String,
+    // Identity, build, image and network information, retained and
+    // republished on every connect. Payloads may be templates instead of
+    // the built-in JSON documents, with the placeholders of topics plus
+    // `{timestamp}`, `{version}` and `{boot_time}`.
+    pub birth_topic: String,
+    pub birth_payload: Option<String>,
+    // Published by the broker when the connection ends without a clean
+    // disconnect, e.g. when the service dies. Its `{timestamp}` is when the
+    // connection was set up.
+    pub will: PresenceConfig,
+    // Published before disconnecting cleanly, e.g. on shutdown.
+    pub offline: PresenceConfig,
+    // Commands arrive here; replies go to response_topic unless an MQTT v5
+    // request names its own response topic.
+    pub command_topic: String,
+    pub response_topic: String,
+    // Fleet-wide commands, subscribed as `$share/{share_group}/{topic}` so
+    // that each one reaches a single unit of the group. Most brokers also
+    // support shared subscriptions with 3.1.1.
+    pub shared_command_topic: Option<String>,
+    pub share_group: String,
+    // Connection state changes, published once the connection allows it.
+    pub events_topic: Option<String>,
+    pub qos: i32,
+    pub payload: PayloadConfig,
+    pub queue: QueueConfig,
+    // Required for client certificates or a private CA. An ssl:// or
+    // mqtts:// uri without it uses the system trust store.
+    pub tls: Option<TlsConfig>,
+    pub v5: V5Config,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    #[serde(rename = "3.1.1")]
+    V3,
+    #[serde(rename = "5")]
+    V5,
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+// Only used on MQTT v5 connections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct V5Config {
+    // Sent with every message, with the placeholders of topics.
+    pub user_properties: BTreeMap<String, String>,
+    // Adds a `trace_id` user property with a new id to every message.
+    // Command responses carry the trace id of their request instead.
+    pub trace_ids: bool,
+    // Seconds until the broker discards a message it could not deliver
+    // yet, by topic filter with the placeholders of topics. Queued messages
+    // that are older than that are not sent at all.
+    pub message_expiry: BTreeMap<String, u32>,
+    // Topics are sent as a number after their first message, for up to
+    // this many topics per connection and no more than the broker allows.
+    // 0 turns topic aliases off.
+    pub topic_aliases: u16,
+    // How long the broker keeps the session of a client that connected
+    // with clean_session off. Unset keeps it forever, as with 3.1.1.
+    pub session_expiry_secs: Option<u32>,
+}
+
+impl Default for V5Config {
+    fn default() -> Self {
+        V5Config {
+            user_properties: BTreeMap::from([("device_id".to_string(), "{device_id}".to_string())]),
+            trace_ids: true,
+            message_expiry: BTreeMap::new(),
+            topic_aliases: 16,
+            session_expiry_secs: None,
+        }
+    }
+}
+
+impl V5Config {
+    fn validate(&self) -> Result<()> {
+        for (name, value) in &self.user_properties {
+            if name.is_empty() {
+                bail!("mqtt.v5.user_properties must not have empty names");
+            }
+            validate_template(&format!("mqtt.v5.user_properties.{name}"), value)?;
+        }
+        for (filter, secs) in &self.message_expiry {
+            validate_filter("mqtt.v5.message_expiry", filter)?;
+            if *secs == 0 {
+                bail!("mqtt.v5.message_expiry for {filter} must be greater than 0");
+            }
+        }
+        Ok(())
+    }
+}
+
+// Delays between connection attempts double from `min_delay_ms` up to
+// `max_delay_ms`. Up to `jitter` of each delay is left out at random.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectConfig {
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter: f64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            min_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    pub fn min_delay(&self) -> Duration {
+        Duration::from_millis(self.min_delay_ms)
+    }
+
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.min_delay_ms == 0 {
+            bail!("mqtt.reconnect.min_delay_ms must be greater than 0");
+        }
+        if self.max_delay_ms < self.min_delay_ms {
+            bail!("mqtt.reconnect.max_delay_ms must not be less than min_delay_ms");
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            bail!("mqtt.reconnect.jitter must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+// An offline message. The topic defaults to mqtt.birth_topic so that the
+// retained message there always tells the current state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresenceConfig {
+    pub enabled: bool,
+    pub topic: Option<String>,
+    pub payload: Option<String>,
+    pub retain: bool,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            enabled: true,
+            topic: None,
+            payload: None,
+            retain: true,
+        }
+    }
+}
+
+impl PresenceConfig {
+    fn validate(&self, key: &str) -> Result<()> {
+        if let Some(topic) = &self.topic {
+            validate_topic(&format!("{key}.topic"), topic)?;
+        }
+        Ok(())
+    }
+}
+
+// How message payloads are encoded on the wire: by the first entry of
+// `topics` whose filter matches, otherwise as set here. On MQTT v5
+// connections payloads other than plain JSON carry `content-type` and
+// `content-encoding` user properties, and received ones are decoded by
+// theirs; without them the settings for the topic apply. Command responses
+// use the encoding of their request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PayloadConfig {
+    pub encoding: Encoding,
+    pub compression: Compression,
+    pub topics: Vec<TopicPayloadConfig>,
+}
+
+// `topic` is a topic filter with the placeholders of topics.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopicPayloadConfig {
+    pub topic: String,
+    pub encoding: Encoding,
+    pub compression: Compression,
+}
+
+impl PayloadConfig {
+    pub fn codec(&self) -> Codec {
+        Codec {
+            encoding: self.encoding,
+            compression: self.compression,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for topic in &self.topics {
+            validate_filter("mqtt.payload.topics", &topic.topic)?;
+        }
+        Ok(())
+    }
+}
+
+impl TopicPayloadConfig {
+    pub fn codec(&self) -> Codec {
+        Codec {
+            encoding: self.encoding,
+            compression: self.compression,
+        }
+    }
+}
+
+// Messages that cannot be published are kept on disk and sent, oldest first,
+// once the broker is reachable again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub enabled: bool,
+    pub max_messages: usize,
+    pub max_bytes: u64,
+    pub max_age_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            enabled: true,
+            max_messages: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+            max_age_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+impl QueueConfig {
+    fn validate(&self) -> Result<()> {
+        if self.max_messages == 0 || self.max_bytes == 0 || self.max_age_secs == 0 {
+            bail!("mqtt.queue limits must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM bundle of CAs trusted to sign the broker certificate.
+    pub ca_file: Option<PathBuf>,
+    // PEM client certificate and key for mutual authentication.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub key_password: Option<String>,
+    // Protocol version: "default", "1.2" or "1.3". TLS 1.3 is negotiated
+    // whenever the broker supports it, but a broker that only offers 1.2 is
+    // not refused. TLS 1.0 and 1.1 are not supported.
+    pub version: String,
+    pub verify_server_cert: bool,
+    pub verify_hostname: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            key_password: None,
+            version: "default".to_string(),
+            verify_server_cert: true,
+            verify_hostname: true,
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            uri: "tcp://localhost:1883".to_string(),
+            protocol: Protocol::V3,
+            failover_uris: Vec::new(),
+            reconnect: ReconnectConfig::default(),
+            client_id: None,
+            username: None,
+            password: None,
+            keep_alive_secs: 30,
+            clean_session: true,
+            connect_timeout_secs: 10,
+            heartbeat_topic: "pis/{client_id}/heartbeat".to_string(),
+            birth_topic: "pis/{client_id}/birth".to_string(),
+            birth_payload: None,
+            will: PresenceConfig::default(),
+            offline: PresenceConfig::default(),
+            command_topic: "pis/{client_id}/command".to_string(),
+            response_topic: "pis/{client_id}/response".to_string(),
+            shared_command_topic: None,
+            share_group: "pis-{fleet_id}".to_string(),
+            events_topic: None,
+            qos: 1,
+            payload: PayloadConfig::default(),
+            queue: QueueConfig::default(),
+            tls: None,
+            v5: V5Config::default(),
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.keep_alive_secs)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.uri.is_empty() {
+            bail!("mqtt.uri must not be empty");
+        }
+        if self.failover_uris.iter().any(String::is_empty) {
+            bail!("mqtt.failover_uris must not contain empty uris");
+        }
+        self.reconnect.validate()?;
+        if self.client_id.as_deref() == Some("") {
+            bail!("mqtt.client_id must not be empty, leave it unset to use the device id");
+        }
+        if self.password.is_some() && self.username.is_none() {
+            bail!("mqtt.password requires mqtt.username");
+        }
+        if self.keep_alive_secs == 0 {
+            bail!("mqtt.keep_alive_secs must be greater than 0");
+        }
+        if !(0..=2).contains(&self.qos) {
+            bail!("mqtt.qos must be 0, 1 or 2");
+        }
+        validate_topic("mqtt.heartbeat_topic", &self.heartbeat_topic)?;
+        validate_topic("mqtt.birth_topic", &self.birth_topic)?;
+        self.will.validate("mqtt.will")?;
+        self.offline.validate("mqtt.offline")?;
+        validate_topic("mqtt.command_topic", &self.command_topic)?;
+        validate_topic("mqtt.response_topic", &self.response_topic)?;
+        if let Some(topic) = &self.shared_command_topic {
+            validate_topic("mqtt.shared_command_topic", topic)?;
+            validate_topic("mqtt.share_group", &self.share_group)?;
+            if self.share_group.contains('/') {
+                bail!("mqtt.share_group must not contain '/'");
+            }
+        }
+        if let Some(topic) = &self.events_topic {
+            validate_topic("mqtt.events_topic", topic)?;
+        }
+        self.payload.validate()?;
+        self.queue.validate()?;
+        self.v5.validate()?;
+        if let Some(tls) = &self.tls {
+            if !self.uses_tls() {
+                bail!("mqtt.tls is set but no mqtt uri is an ssl://, mqtts:// or wss:// uri");
+            }
+            // Only checked when MQTT is actually used, so a disabled broker
+            // section does not need its certificate files on disk.
+            if self.enabled {
+                mqtt::tls::check(tls).context("invalid mqtt.tls")?;
+            }
+        }
+        Ok(())
+    }
+
+    // The primary server first, then the failover servers.
+    pub fn uris(&self) -> Vec<&str> {
+        std::iter::once(&self.uri)
+            .chain(&self.failover_uris)
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub fn uses_tls(&self) -> bool {
+        self.uris().iter().any(|uri| {
+            ["ssl://", "mqtts://", "wss://"]
+                .iter()
+                .any(|scheme| uri.starts_with(scheme))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflict {
+    Local,
+    Remote,
+}
+
+// Remote configuration through a retained desired document on
+// `desired_topic`, see `shadow`. Only the listed sections can be changed
+// remotely; `service` and `shadow` never can. `scheduler` and `components`
+// run commands, so listing them lets anyone who can publish the desired
+// document run programs on the unit. Requires MQTT.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowConfig {
+    pub enabled: bool,
+    pub desired_topic: String,
+    pub reported_topic: String,
+    pub sections: Vec<String>,
+    // Which value stays when the local configuration changes a setting
+    // that was also set remotely.
+    pub conflict: Conflict,
+    // Remote changes to settings that are only read at startup restart the
+    // service. Otherwise they wait for the next restart.
+    pub restart: bool,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        ShadowConfig {
+            enabled: false,
+            desired_topic: "pis/{client_id}/shadow/desired".to_string(),
+            reported_topic: "pis/{client_id}/shadow/reported".to_string(),
+            sections: ["ticker", "log", "watchdog"].map(String::from).to_vec(),
+            conflict: Conflict::Local,
+            restart: true,
+        }
+    }
+}
+
+impl ShadowConfig {
+    fn validate(&self) -> Result<()> {
+        validate_topic("shadow.desired_topic", &self.desired_topic)?;
+        validate_topic("shadow.reported_topic", &self.reported_topic)?;
+        let Value::Object(sections) = serde_json::to_value(Config::default())? else {
+            unreachable!("the configuration is a table");
+        };
+        for section in &self.sections {
+            if section == "service" || section == "shadow" {
+                bail!("shadow.sections must not contain {section}");
+            }
+            if !sections.contains_key(section) {
+                bail!("shadow.sections contains unknown section {section:?}");
+            }
+        }
+        Ok(())
+    }
+}
+
+// Names of jobs and components end up in URLs and topics.
+fn validate_name(what: &str, name: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+    if name.is_empty() || !name.chars().all(valid) {
+        bail!("{what} name {name:?} may only contain letters, digits, '-', '_' and '.'");
+    }
+    Ok(())
+}
+
+pub fn validate_topic(key: &str, topic: &str) -> Result<()> {
+    if topic.is_empty() {
+        bail!("{key} must not be empty");
+    }
+    if topic.contains(['+', '#']) {
+        bail!("{key} must not contain wildcards");
+    }
+    validate_template(key, topic)
+}
+
+// Wildcards may only take up whole levels, '#' only the last one.
+fn validate_filter(key: &str, filter: &str) -> Result<()> {
+    if filter.is_empty() {
+        bail!("{key} must not have empty topic filters");
+    }
+    let levels: Vec<_> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        let wildcard = *level == "+" || (*level == "#" && i == levels.len() - 1);
+        if !wildcard && level.contains(['+', '#']) {
+            bail!("{key} has an invalid topic filter {filter:?}");
+        }
+    }
+    validate_template(key, filter)
+}
+
+// Checks the placeholders of a template, see `Identity::render`.
+fn validate_template(key: &str, template: &str) -> Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}') else {
+            bail!("{key} has an unterminated placeholder");
+        };
+        let name = &rest[start + 1..start + end];
+        if !identity::PLACEHOLDERS.contains(&name) {
+            bail!(
+                "{key} uses unknown placeholder {{{name}}}, expected one of {}",
+                identity::PLACEHOLDERS.join(", ")
+            );
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+// Local API for health checks and diagnostics. The listen address is read
+// at startup; changing it requires a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    // Whether `listen` may be an address other hosts can reach. Status and
+    // identity are served without a token, so this is off by default.
+    pub allow_remote: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: true,
+            listen: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            allow_remote: false,
+        }
+    }
+}
+
+impl HttpConfig {
+    fn validate(&self) -> Result<()> {
+        if self.enabled && !self.listen.ip().is_loopback() && !self.allow_remote {
+            bail!(
+                "http.listen {} is reachable from other hosts, set http.allow_remote = true to allow it",
+                self.listen
+            );
+        }
+        Ok(())
+    }
+}
+
+// Assigning the unit to a fleet and vehicle, which can also be done with
+// `pis provision`. Provisioning over HTTP is off unless enabled, and then
+// requires `token`, sent as `Authorization: Bearer <token>`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProvisioningConfig {
+    pub http_enabled: bool,
+    pub token: Option<String>,
+}
+
+impl ProvisioningConfig {
+    fn validate(&self) -> Result<()> {
+        if self.token.as_deref() == Some("") {
+            bail!("provisioning.token must not be empty");
+        }
+        if self.http_enabled && self.token.is_none() {
+            bail!("provisioning.http_enabled requires provisioning.token");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallerKind {
+    Rauc,
+    Swupdate,
+    // A/B directories under `slots_dir`, for development and tests.
+    Directory,
+}
+
+// Over-the-air updates. Jobs arrive as the `update` MQTT command or on
+// POST /update, which requires `token` as `Authorization: Bearer <token>`.
+// They are only installed when signed with one of `public_keys` and newer
+// than the running version. Read at startup; changing it requires a
+// restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdateConfig {
+    pub enabled: bool,
+    // PEM Ed25519 public keys.
+    pub public_keys: Vec<PathBuf>,
+    pub installer: InstallerKind,
+    // Replace the installer's commands, `{artifact}` in install_command
+    // being the path of the verified artifact.
+    pub install_command: Option<Vec<String>>,
+    pub confirm_command: Option<Vec<String>>,
+    pub rollback_command: Option<Vec<String>>,
+    pub slots_dir: PathBuf,
+    // Trusted for https downloads in addition to the system store.
+    pub ca_file: Option<PathBuf>,
+    // Per read; a stalled download is retried and resumes.
+    pub download_timeout_secs: u64,
+    pub download_attempts: u32,
+    // How long the new version runs before it is confirmed. It must then be
+    // ready, including its broker connection, or it is rolled back.
+    pub health_check_secs: u64,
+    pub progress_topic: String,
+    // Required by POST /update, which is refused while it is unset.
+    pub token: Option<String>,
+}
+
+impl Default for UpdateConfig {
+    fn default() -> Self {
+        UpdateConfig {
+            enabled: false,
+            public_keys: vec![PathBuf::from("/etc/pis/update-key.pem")],
+            installer: InstallerKind::Rauc,
+            install_command: None,
+            confirm_command: None,
+            rollback_command: None,
+            slots_dir: PathBuf::from("/var/lib/pis/slots"),
+            ca_file: None,
+            download_timeout_secs: 30,
+            download_attempts: 5,
+            health_check_secs: 300,
+            progress_topic: "pis/{client_id}/update".to_string(),
+            token: None,
+        }
+    }
+}
+
+impl UpdateConfig {
+    pub fn download_timeout(&self) -> Duration {
+        Duration::from_secs(self.download_timeout_secs)
+    }
+
+    pub fn health_check(&self) -> Duration {
+        Duration::from_secs(self.health_check_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.download_timeout_secs == 0 || self.download_attempts == 0 {
+            bail!(
+                "update.download_timeout_secs and update.download_attempts must be greater than 0"
+            );
+        }
+        validate_topic("update.progress_topic", &self.progress_topic)?;
+        if self.token.as_deref() == Some("") {
+            bail!("update.token must not be empty");
+        }
+        for (key, command) in [
+            ("install_command", &self.install_command),
+            ("confirm_command", &self.confirm_command),
+            ("rollback_command", &self.rollback_command),
+        ] {
+            if command.as_ref().is_some_and(Vec::is_empty) {
+                bail!("update.{key} must not be empty");
+            }
+        }
+        // Like the TLS files, the keys only have to exist when used.
+        if self.enabled {
+            if self.public_keys.is_empty() {
+                bail!("update.public_keys must not be empty");
+            }
+            update::load_keys(&self.public_keys).context("invalid update.public_keys")?;
+        }
+        Ok(())
+    }
+}
+
+// Hardware watchdog for units without systemd. The device is armed as soon
+// as it is opened and reboots the unit unless it is petted at least every
+// `timeout_secs`. Read at startup; changing it requires a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchdogConfig {
+    pub enabled: bool,
+    pub device: PathBuf,
+    pub timeout_secs: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            enabled: false,
+            device: PathBuf::from("/dev/watchdog"),
+            timeout_secs: 30,
+        }
+    }
+}
+
+impl WatchdogConfig {
+    fn validate(&self) -> Result<()> {
+        if self.timeout_secs < 3 {
+            bail!("watchdog.timeout_secs must be at least 3");
+        }
+        Ok(())
+    }
+}
+
+// A background component built by `components::registry()`. Read at
+// startup; changing it requires a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComponentConfig {
+    pub kind: String,
+    pub enabled: bool,
+    // Subsystems or components, e.g. "mqtt", that are started before this
+    // one and stopped after it.
+    pub depends_on: Vec<String>,
+    // Specific to the kind.
+    pub settings: Map<String, Value>,
+}
+
+impl Default for ComponentConfig {
+    fn default() -> Self {
+        ComponentConfig {
+            kind: String::new(),
+            enabled: true,
+            depends_on: Vec::new(),
+            settings: Map::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogBackend {
+    Stderr,
+    Journald,
+    Syslog,
+    File,
+}
+
+// Levels are "off", "error", "warn", "info", "debug" or "trace". Reloaded
+// on SIGHUP like everything else.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub backend: LogBackend,
+    pub level: String,
+    // Per-module levels, e.g. `mqtt = "debug"` or `warp = "warn"`. The most
+    // specific module wins.
+    pub modules: BTreeMap<String, String>,
+    pub syslog_socket: PathBuf,
+    pub file: LogFileConfig,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            backend: LogBackend::Stderr,
+            level: "info".to_string(),
+            modules: BTreeMap::new(),
+            syslog_socket: PathBuf::from("/dev/log"),
+            file: LogFileConfig::default(),
+        }
+    }
+}
+
+impl LogConfig {
+    fn validate(&self) -> Result<()> {
+        logging::validate(self).context("invalid log configuration")?;
+        if self.backend == LogBackend::File
+            && (self.file.max_bytes == 0 || self.file.max_age_secs == 0)
+        {
+            bail!("log.file.max_bytes and log.file.max_age_secs must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+// Used by the file backend. `keep` rotated files are kept next to `path`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    pub max_age_secs: u64,
+    pub keep: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        LogFileConfig {
+            path: PathBuf::from("/var/log/pis/pis.log"),
+            max_bytes: 10 * 1024 * 1024,
+            max_age_secs: 24 * 3600,
+            keep: 5,
+        }
+    }
+}
+
+// Stands in for secrets in printed configurations.
+const REDACTED: &str = "<redacted>";
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        self.state.validate()?;
+        self.ticker.validate()?;
+        self.scheduler.validate()?;
+        self.mqtt.validate()?;
+        self.http.validate()?;
+        self.shadow.validate()?;
+        if self.shadow.enabled && !self.mqtt.enabled {
+            bail!("shadow requires mqtt.enabled");
+        }
+        self.provisioning.validate()?;
+        self.update.validate()?;
+        self.log.validate()?;
+        self.watchdog.validate()?;
+        let registry = components::registry();
+        for (name, component) in &self.components {
+            validate_name("component", name)?;
+            registry
+                .validate(name, component)
+                .with_context(|| format!("invalid components.{name}"))?;
+        }
+        Ok(())
+    }
+
+    // A copy with passwords and tokens masked, for printing.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        let tls = config.mqtt.tls.as_mut();
+        for secret in [
+            Some(&mut config.mqtt.password),
+            tls.map(|tls| &mut tls.key_password),
+            Some(&mut config.update.token),
+            Some(&mut config.provisioning.token),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if secret.is_some() {
+                *secret = Some(REDACTED.to_string());
+            }
+        }
+        config
+    }
+}
+
+// Builds the configuration from, in increasing priority: built-in defaults,
+// the config file, settings received through the device shadow, PIS__*
+// environment variables and command-line flags. Remote settings that no
+// longer make a valid configuration, e.g. after an upgrade, are ignored.
+pub fn load(args: &Args) -> Result<Config> {
+    load_with(args, None)
+}
+
+// Like `load`, with `remote`, when given, in place of the stored remote
+// settings.
+pub fn load_with(args: &Args, remote: Option<&Value>) -> Result<Config> {
+    let local = local_tree(args)?;
+    let config = resolve(args, &local, None)?;
+    if !config.shadow.enabled {
+        return Ok(config);
+    }
+    let stored;
+    let remote = match remote {
+        Some(remote) => remote,
+        None => match shadow::overlay(&config.service.state_dir) {
+            Some(remote) => {
+                stored = remote;
+                &stored
+            }
+            None => return Ok(config),
+        },
+    };
+    match resolve(args, &local, Some(remote)) {
+        Ok(config) => Ok(config),
+        Err(e) => {
+            warn!("ignoring remote configuration: {e:#}");
+            Ok(config)
+        }
+    }
+}
+
+// The built-in defaults with the config file on top, for `resolve`.
+pub fn local_tree(args: &Args) -> Result<Value> {
+    let mut tree = serde_json::to_value(Config::default())?;
+    match &args.config {
+        Some(path) => merge(&mut tree, read_file(path)?),
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            merge(&mut tree, read_file(Path::new(DEFAULT_CONFIG_PATH))?)
+        }
+        None => {}
+    }
+    Ok(tree)
+}
+
+// The configuration made of `local` and `remote`, with environment
+// variables and flags on top. Reads nothing from disk, so the device shadow
+// can try remote settings cheaply.
+pub fn resolve(args: &Args, local: &Value, remote: Option<&Value>) -> Result<Config> {
+    resolve_with(args, local, remote, env::vars())
+}
+
+fn resolve_with(
+    args: &Args,
+    local: &Value,
+    remote: Option<&Value>,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Config> {
+    let mut tree = local.clone();
+    if let Some(remote) = remote {
+        merge(&mut tree, remote.clone());
+    }
+
+    let mut overrides = Vec::new();
+    for (key, value) in vars {
+        if let Some(path) = key.strip_prefix(ENV_PREFIX) {
+            apply_env(&mut tree, path, &value).with_context(|| format!("invalid {key}"))?;
+            overrides.push(Override {
+                setting: path.to_lowercase().replace("__", "."),
+                path: path.to_string(),
+                key,
+                value,
+            });
+        }
+    }
+
+    let mut config = deserialize(tree, &overrides)?;
+    args.apply(&mut config);
+    config.validate()?;
+    Ok(config)
+}
+
+// A PIS__* variable applied to the tree.
+struct Override {
+    key: String,
+    path: String,
+    // The dotted path of the setting, as deserialization errors name it.
+    setting: String,
+    value: String,
+}
+
+impl Override {
+    fn covers(&self, setting: &str) -> bool {
+        setting
+            .strip_prefix(&self.setting)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    }
+}
+
+// Errors name the offending setting and, when a variable set it, the
+// variable. A variable that was parsed as a number or boolean where the
+// setting takes a string, e.g. a numeric token for a setting that is unset by
+// default, is retried as a string.
+fn deserialize(mut tree: Value, overrides: &[Override]) -> Result<Config> {
+    loop {
+        let e = match serde_path_to_error::deserialize(&tree) {
+            Ok(config) => return Ok(config),
+            Err(e) => e,
+        };
+        let setting = e.path().to_string();
+        let Some(var) = overrides.iter().rev().find(|var| var.covers(&setting)) else {
+            bail!("{e}");
+        };
+        let entry = env_entry(&mut tree, &var.path)?;
+        if entry.is_string() {
+            bail!("invalid {}: {e}", var.key);
+        }
+        *entry = Value::String(var.value.clone());
+    }
+}
+fn read_file(path: &Path) -> Result<Value> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("cannot read config file {}", path.display()))?;
+    let value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&text)
+            .with_context(|| format!("cannot parse {}", path.display()))?,
+        Some("toml") => {
+            toml::from_str(&text).with_context(|| format!("cannot parse {}", path.display()))?
+        }
+        _ => bail!(
+            "unsupported config file {}, expected a .toml or .json file",
+            path.display()
+        ),
+    };
+    Ok(value)
+}
+
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                merge(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// Values are parsed as JSON scalars so that numbers and booleans get their
+// type, but stay strings where the setting is one. Optional strings are unset
+// by default, so `deserialize` puts those back.
+fn apply_env(tree: &mut Value, path: &str, raw: &str) -> Result<()> {
+    let entry = env_entry(tree, path)?;
+    *entry = match entry {
+        Value::String(_) => None,
+        _ => serde_json::from_str(raw).ok(),
+    }
+    .unwrap_or_else(|| Value::String(raw.to_string()));
+    Ok(())
+}
+
+fn env_entry<'a>(tree: &'a mut Value, path: &str) -> Result<&'a mut Value> {
+    let mut node = tree;
+    for segment in path.split("__") {
+        if segment.is_empty() {
+            bail!("empty key segment");
+        }
+        if node.is_null() {
+            *node = Value::Object(Map::new());
+        }
+        let Value::Object(map) = node else {
+            bail!("{} is not a table", segment);
+        };
+        node = map.entry(segment.to_lowercase()).or_insert(Value::Null);
+    }
+    Ok(node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::RunArgs;
+    use serde_json::json;
+
+    // The tree `resolve` starts from, without a config file.
+    fn defaults() -> Value {
+        serde_json::to_value(Config::default()).unwrap()
+    }
+
+    #[test]
+    fn numbers_keep_their_type() {
+        let mut tree = defaults();
+        apply_env(&mut tree, "TICKER__INTERVAL_MS", "500").unwrap();
+        assert_eq!(tree["ticker"]["interval_ms"], 500);
+        let config: Config = serde_json::from_value(tree).unwrap();
+        assert_eq!(config.ticker.interval_ms, 500);
+    }
+
+    // `resolve` with `vars` in place of the environment.
+    fn resolve_vars(local: Value, vars: &[(&str, &str)]) -> Result<Config> {
+        let args = Args {
+            config: None,
+            run: RunArgs::default(),
+            command: None,
+        };
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()));
+        resolve_with(&args, &local, None, vars)
+    }
+
+    #[test]
+    fn strings_that_look_like_numbers_stay_strings() {
+        let mut tree = defaults();
+        apply_env(&mut tree, "LOG__LEVEL", "1").unwrap();
+        assert_eq!(tree["log"]["level"], "1");
+        // Unset by default, so only deserializing tells it is a string.
+        let config = resolve_vars(defaults(), &[("PIS__UPDATE__TOKEN", "123")]).unwrap();
+        assert_eq!(config.update.token.as_deref(), Some("123"));
+    }
+
+    #[test]
+    fn type_errors_name_the_setting() {
+        let mut local = defaults();
+        local["ticker"]["interval_ms"] = json!("often");
+        let e = resolve_vars(local, &[]).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "ticker.interval_ms: invalid type: string \"often\", expected u64"
+        );
+    }
+
+    #[test]
+    fn type_errors_name_the_variable() {
+        let e = resolve_vars(
+            defaults(),
+            &[
+                ("PIS__LOG__LEVEL", "debug"),
+                ("PIS__TICKER__INTERVAL_MS", "often"),
+            ],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "invalid PIS__TICKER__INTERVAL_MS: ticker.interval_ms: invalid type: string \"often\", expected u64"
+        );
+    }
+
+    #[test]
+    fn nested_keys_reach_inner_tables() {
+        let mut tree = defaults();
+        apply_env(&mut tree, "LOG__FILE__KEEP", "3").unwrap();
+        apply_env(&mut tree, "mqtt__queue__max_bytes", "1024").unwrap();
+        let config: Config = serde_json::from_value(tree.clone()).unwrap();
+        assert_eq!(config.log.file.keep, 3);
+        assert_eq!(config.mqtt.queue.max_bytes, 1024);
+
+        assert!(apply_env(&mut tree, "TICKER____INTERVAL_MS", "1").is_err());
+        assert!(apply_env(&mut tree, "TICKER__INTERVAL_MS__UNIT", "1").is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let e = resolve_vars(defaults(), &[("PIS__TICKER__INTERVAL", "500")])
+            .err()
+            .unwrap();
+        assert!(
+            e.to_string().starts_with(
+                "invalid PIS__TICKER__INTERVAL: ticker.interval: unknown field `interval`"
+            ),
+            "{e}"
+        );
+    }
+
+    #[test]
+    fn redacted_masks_only_set_secrets() {
+        let mut config = Config::default();
+        config.mqtt.password = Some("hunter2".to_string());
+        config.mqtt.tls = Some(TlsConfig {
+            key_password: Some("keypass".to_string()),
+            ..TlsConfig::default()
+        });
+        config.update.token = Some("update-token".to_string());
+
+        let redacted = config.redacted();
+        assert_eq!(redacted.mqtt.password.as_deref(), Some(REDACTED));
+        assert_eq!(
+            redacted.mqtt.tls.unwrap().key_password.as_deref(),
+            Some(REDACTED)
+        );
+        assert_eq!(redacted.update.token.as_deref(), Some(REDACTED));
+        assert_eq!(redacted.provisioning.token, None);
+
+        let printed = toml::to_string(&config.redacted()).unwrap();
+        for secret in ["hunter2", "keypass", "update-token"] {
+            assert!(!printed.contains(secret), "{printed}");
+        }
+    }
+
+    #[test]
+    fn remote_http_access_is_opt_in() {
+        let config = Config::default();
+        assert!(config.http.listen.ip().is_loopback());
+        config.validate().unwrap();
+
+        let e = resolve_vars(defaults(), &[("PIS__HTTP__LISTEN", "0.0.0.0:8080")])
+            .err()
+            .unwrap();
+        assert!(format!("{e:#}").contains("http.allow_remote"), "{e:#}");
+        resolve_vars(
+            defaults(),
+            &[
+                ("PIS__HTTP__LISTEN", "0.0.0.0:8080"),
+                ("PIS__HTTP__ALLOW_REMOTE", "true"),
+            ],
+        )
+        .unwrap();
+        resolve_vars(defaults(), &[("PIS__HTTP__LISTEN", "[::1]:8080")]).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+use std::process::ExitCode;
+
+use thiserror::Error;
+
+// Process exit codes. These are part of the service interface: systemd units
+// and the image test harness match on them, so never renumber an entry.
+//
+//   0  clean shutdown (signal received or iteration limit reached)
+//   1  unexpected internal error, or a failing `status` or `self-test`
+//   2  invalid command line (reported by clap)
+//   3  configuration error
+//   4  MQTT broker unreachable
+//   5  HTTP server could not bind its address
+//   6  watchdog timeout
+//   7  restart requested, e.g. to apply a remote configuration change; the
+//      service manager is expected to start the service again
+pub const EXIT_OK: u8 = 0;
+pub const EXIT_INTERNAL: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_CONFIG: u8 = 3;
+pub const EXIT_BROKER_UNREACHABLE: u8 = 4;
+pub const EXIT_HTTP_BIND: u8 = 5;
+pub const EXIT_WATCHDOG_TIMEOUT: u8 = 6;
+pub const EXIT_RESTART: u8 = 7;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid configuration: {0:#}")]
+    Config(anyhow::Error),
+    #[error("MQTT broker unreachable: {0:#}")]
+    BrokerUnreachable(anyhow::Error),
+    #[error("cannot bind HTTP server: {0:#}")]
+    HttpBind(anyhow::Error),
+    #[error("watchdog timeout: {0}")]
+    WatchdogTimeout(String),
+    #[error("restart requested: {0}")]
+    Restart(String),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config(_) => EXIT_CONFIG,
+            Error::BrokerUnreachable(_) => EXIT_BROKER_UNREACHABLE,
+            Error::HttpBind(_) => EXIT_HTTP_BIND,
+            Error::WatchdogTimeout(_) => EXIT_WATCHDOG_TIMEOUT,
+            Error::Restart(_) => EXIT_RESTART,
+            Error::Internal(_) => EXIT_INTERNAL,
+        }
+    }
+
+    // Fatal errors stop the whole service instead of restarting the
+    // subsystem that raised them.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Error::Internal(_))
+    }
+}
+
+impl From<Error> for ExitCode {
+    fn from(error: Error) -> Self {
+        ExitCode::from(error.exit_code())
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+
+use chrono::{DateTime, Utc};
+use log::warn;
+
+const PROC_STAT: &str = "/proc/stat";
+
+// The addresses of every interface except loopback, by interface name.
+// These change at runtime, e.g. with DHCP or a modem reconnecting, so they
+// are looked up whenever they are needed.
+pub fn addresses() -> BTreeMap<String, Vec<IpAddr>> {
+    let mut addresses: BTreeMap<String, Vec<IpAddr>> = BTreeMap::new();
+    let mut list: *mut libc::ifaddrs = ptr::null_mut();
+    // SAFETY: getifaddrs fills in `list` on success, which is freed below.
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        warn!(
+            "cannot list network addresses: {}",
+            io::Error::last_os_error()
+        );
+        return addresses;
+    }
+    let mut node = list;
+    while !node.is_null() {
+        // SAFETY: the nodes stay valid until freeifaddrs.
+        let entry = unsafe { &*node };
+        node = entry.ifa_next;
+        if entry.ifa_addr.is_null() || entry.ifa_flags & libc::IFF_LOOPBACK as u32 != 0 {
+            continue;
+        }
+        // SAFETY: ifa_addr points to a socket address of the family it
+        // names.
+        let address = unsafe {
+            match i32::from((*entry.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let address = &*(entry.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let address = &*(entry.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(address.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            }
+        };
+        // SAFETY: ifa_name is a NUL-terminated string.
+        let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+        addresses
+            .entry(name.to_string_lossy().into_owned())
+            .or_default()
+            .push(address);
+    }
+    // SAFETY: `list` came from getifaddrs and is not used afterwards.
+    unsafe { libc::freeifaddrs(list) };
+    addresses
+}
+
+// From the btime line of /proc/stat; None where that is unavailable.
+pub fn boot_time() -> Option<DateTime<Utc>> {
+    let stat = fs::read_to_string(PROC_STAT).ok()?;
+    let secs = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    DateTime::from_timestamp(secs, 0)
+}
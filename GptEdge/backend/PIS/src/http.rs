@@ -0,0 +1,498 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_derive::Serialize;
+use serde_json::json;
+use warp::http::header::{CONTENT_ENCODING, CONTENT_TYPE, VARY};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Filter, Rejection};
+
+use crate::codec::Codec;
+use crate::config::{ConfigWatch, ProvisioningConfig};
+use crate::error::Error;
+use crate::identity::{Identity, IdentityStore, ProvisionError, Provisioning};
+use crate::metrics::Metrics;
+use crate::scheduler::Scheduler;
+use crate::status::{Report, State, Status};
+use crate::supervisor::Shutdown;
+use crate::update::{Job, SubmitError, Updater};
+
+// Provisioning requests are small documents.
+pub const NAME: &str = "http";
+
+const MAX_BODY: u64 = 16 * 1024;
+
+#[derive(Serialize)]
+struct StatusReport<'a> {
+    #[serde(flatten)]
+    report: Report,
+    identity: &'a Identity,
+}
+
+// Serves the local API:
+//
+//   GET /healthz  200 while the process is up
+//   GET /readyz   200 once every subsystem is running, 503 otherwise
+//   GET /status     uptime, tick counter, version, identity and subsystem
+//                   states
+//   GET /metrics    Prometheus text exposition format
+//   GET /identity   device identity, without credentials
+//   POST /provision assigns the device to a fleet and vehicle, see
+//                   `identity::Provisioning` for the body
+//   GET /update     progress of the current or last update
+//   POST /update    starts an update, see `update::Job` for the body;
+//                   requires update.token as a bearer token
+//   GET /jobs       scheduled jobs with their next run and run history
+//   GET /jobs/NAME  one of them
+//
+// Documents are JSON unless the request asks for CBOR or MessagePack with
+// Accept, and compressed if Accept-Encoding allows gzip or zstd. Request
+// bodies are decoded as their Content-Type and Content-Encoding say.
+//
+// The listen address, provisioning settings and update token are read once;
+// changing them requires a restart.
+pub async fn run(
+    config: ConfigWatch,
+    status: Arc<Status>,
+    metrics: Arc<Metrics>,
+    identity: Arc<IdentityStore>,
+    updater: Arc<Updater>,
+    scheduler: Arc<Scheduler>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let (listen, provisioning, update_token) = {
+        let config = config.borrow();
+        (
+            config.http.listen,
+            config.provisioning.clone(),
+            config.update.token.clone(),
+        )
+    };
+
+    let healthz = warp::path!("healthz")
+        .and(negotiated())
+        .map(|codec| respond(codec, &json!({ "status": "ok" }), StatusCode::OK));
+    let readyz = {
+        let status = status.clone();
+        warp::path!("readyz").and(negotiated()).map(move |codec| {
+            let waiting = status.not_ready();
+            let (body, code) = match waiting.is_empty() {
+                true => (json!({ "status": "ready" }), StatusCode::OK),
+                false => (
+                    json!({ "status": "not_ready", "waiting_for": waiting }),
+                    StatusCode::SERVICE_UNAVAILABLE,
+                ),
+            };
+            respond(codec, &body, code)
+        })
+    };
+    let report = {
+        let (status, identity) = (status.clone(), identity.clone());
+        warp::path!("status").and(negotiated()).map(move |codec| {
+            let report = StatusReport {
+                report: status.report(),
+                identity: &identity.current(),
+            };
+            respond(codec, &report, StatusCode::OK)
+        })
+    };
+    let show_identity = {
+        let identity = identity.clone();
+        warp::path!("identity")
+            .and(negotiated())
+            .map(move |codec| respond(codec, &*identity.current(), StatusCode::OK))
+    };
+    let progress = {
+        let updater = updater.clone();
+        warp::path!("update")
+            .and(negotiated())
+            .map(move |codec| respond(codec, &updater.progress(), StatusCode::OK))
+    };
+    let jobs = {
+        let scheduler = scheduler.clone();
+        warp::path!("jobs")
+            .and(negotiated())
+            .map(move |codec| respond(codec, &scheduler.jobs(), StatusCode::OK))
+    };
+    let job = warp::path!("jobs" / String)
+        .and(negotiated())
+        .map(move |name: String, codec| match scheduler.job(&name) {
+            Some(job) => respond(codec, &job, StatusCode::OK),
+            None => respond(
+                codec,
+                &json!({ "error": format!("no job named {name}") }),
+                StatusCode::NOT_FOUND,
+            ),
+        });
+    let submit = submit_route(updater, update_token);
+    let provision = provision_route(identity, provisioning);
+    let exposition = {
+        let metrics = metrics.clone();
+        warp::path!("metrics").map(move || {
+            warp::reply::with_header(
+                metrics.render(),
+                "content-type",
+                "text/plain; version=0.0.4; charset=utf-8",
+            )
+        })
+    };
+    let instrument = warp::log::custom(move |info| {
+        metrics.record_http(route(info.path()), info.status().as_u16(), info.elapsed())
+    });
+    let routes = warp::get()
+        .and(
+            healthz
+                .or(readyz)
+                .or(report)
+                .or(exposition)
+                .or(show_identity)
+                .or(progress)
+                .or(jobs)
+                .or(job),
+        )
+        .or(provision)
+        .or(submit)
+        .with(instrument);
+
+    let (addr, server) = warp::serve(routes)
+        .try_bind_with_graceful_shutdown(listen, async move { shutdown.wait().await })
+        .map_err(|e| Error::HttpBind(anyhow::anyhow!("{listen}: {e}")))?;
+    info!("http listening on {addr}");
+    status.set(NAME, State::Running, None);
+    server.await;
+    Ok(())
+}
+
+// POST /update, answering every request itself.
+fn submit_route(
+    updater: Arc<Updater>,
+    token: Option<String>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("update"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(negotiated())
+        .and(body())
+        .map(
+            move |authorization: Option<String>, codec, job: Result<Job, (StatusCode, String)>| {
+                submit(
+                    &updater,
+                    token.as_deref(),
+                    authorization.as_deref(),
+                    job,
+                    codec,
+                )
+            },
+        )
+}
+
+// POST /provision, answering every request itself.
+fn provision_route(
+    identity: Arc<IdentityStore>,
+    config: ProvisioningConfig,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("provision"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(negotiated())
+        .and(body())
+        .map(
+            move |authorization: Option<String>,
+                  codec,
+                  request: Result<Provisioning, (StatusCode, String)>| {
+                match request {
+                    Ok(request) => {
+                        provision(&identity, &config, authorization.as_deref(), request, codec)
+                    }
+                    Err((code, error)) => respond(codec, &json!({ "error": error }), code),
+                }
+            },
+        )
+}
+
+fn provision(
+    identity: &IdentityStore,
+    config: &ProvisioningConfig,
+    authorization: Option<&str>,
+    request: Provisioning,
+    codec: Codec,
+) -> Response {
+    let refuse = |code, message: &str| {
+        warn!("provisioning request refused: {message}");
+        respond(codec, &json!({ "error": message }), code)
+    };
+    if !config.http_enabled {
+        return refuse(StatusCode::FORBIDDEN, "provisioning over HTTP is disabled");
+    }
+    // Validation requires one, but anyone on the network could otherwise
+    // assign the unit.
+    let Some(token) = &config.token else {
+        return refuse(StatusCode::FORBIDDEN, "provisioning.token is not set");
+    };
+    if !authorized(token, authorization) {
+        return refuse(StatusCode::UNAUTHORIZED, "missing or invalid token");
+    }
+    match identity.provision(request) {
+        Ok(identity) => respond(codec, &*identity, StatusCode::OK),
+        Err(e @ ProvisionError::Invalid(_)) => refuse(StatusCode::BAD_REQUEST, &e.to_string()),
+        Err(e @ ProvisionError::AlreadyProvisioned { .. }) => {
+            refuse(StatusCode::CONFLICT, &e.to_string())
+        }
+        Err(ProvisionError::Internal(e)) => {
+            error!("cannot provision: {e:#}");
+            respond(
+                codec,
+                &json!({ "error": "cannot store provisioning" }),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    }
+}
+
+fn submit(
+    updater: &Updater,
+    token: Option<&str>,
+    authorization: Option<&str>,
+    job: Result<Job, (StatusCode, String)>,
+    codec: Codec,
+) -> Response {
+    let refuse = |code, message: &str| {
+        warn!("update request refused: {message}");
+        respond(codec, &json!({ "error": message }), code)
+    };
+    // Anyone who can reach the port could otherwise install what they like.
+    let Some(token) = token else {
+        return refuse(StatusCode::FORBIDDEN, "update.token is not set");
+    };
+    if !authorized(token, authorization) {
+        return refuse(StatusCode::UNAUTHORIZED, "missing or invalid token");
+    }
+    let job = match job {
+        Ok(job) => job,
+        Err((code, error)) => return respond(codec, &json!({ "error": error }), code),
+    };
+    let (body, code) = match updater.submit(job) {
+        Ok(()) => (json!(updater.progress()), StatusCode::ACCEPTED),
+        Err(e) => {
+            let code = match e {
+                SubmitError::Disabled => StatusCode::SERVICE_UNAVAILABLE,
+                SubmitError::Invalid(_) => StatusCode::BAD_REQUEST,
+                SubmitError::Busy(_) => StatusCode::CONFLICT,
+            };
+            (json!({ "error": e.to_string() }), code)
+        }
+    };
+    respond(codec, &body, code)
+}
+
+// Whether the Authorization header carries `token` as a bearer token,
+// compared in constant time.
+fn authorized(token: &str, authorization: Option<&str>) -> bool {
+    let presented = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    presented.is_some_and(|presented| {
+        presented.len() == token.len()
+            && openssl::memcmp::eq(presented.as_bytes(), token.as_bytes())
+    })
+}
+
+// The codec for the reply, from the Accept and Accept-Encoding headers.
+fn negotiated() -> impl Filter<Extract = (Codec,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept")
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .map(|accept: Option<String>, accept_encoding: Option<String>| {
+            Codec::negotiate(accept.as_deref(), accept_encoding.as_deref())
+        })
+}
+
+// The request body, decoded as its Content-Type and Content-Encoding say, or
+// the status and message to refuse it with.
+fn body<T: DeserializeOwned + Send>(
+) -> impl Filter<Extract = (Result<T, (StatusCode, String)>,), Error = Rejection> + Clone {
+    warp::body::content_length_limit(MAX_BODY)
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::bytes())
+        .map(
+            |content_type: Option<String>, content_encoding: Option<String>, body: Bytes| {
+                let codec =
+                    Codec::from_content_type(content_type.as_deref(), content_encoding.as_deref())
+                        .map_err(|e| (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()))?;
+                codec
+                    .decode(&body)
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("malformed body: {e:#}")))
+            },
+        )
+}
+
+fn respond<T: Serialize + ?Sized>(codec: Codec, value: &T, code: StatusCode) -> Response {
+    let body = match codec.encode(value) {
+        Ok(body) => body,
+        Err(e) => {
+            error!("cannot encode reply: {e:#}");
+            let mut response = Response::new("cannot encode reply".into());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return response;
+        }
+    };
+    let mut response = Response::new(body.into());
+    *response.status_mut() = code;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, codec.content_type().parse().unwrap());
+    if let Some(content_encoding) = codec.content_encoding() {
+        headers.insert(CONTENT_ENCODING, content_encoding.parse().unwrap());
+    }
+    headers.insert(VARY, "accept, accept-encoding".parse().unwrap());
+    response
+}
+
+fn route(path: &str) -> &'static str {
+    match path {
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/status" => "/status",
+        "/metrics" => "/metrics",
+        "/identity" => "/identity",
+        "/provision" => "/provision",
+        "/update" => "/update",
+        "/jobs" => "/jobs",
+        path if path.starts_with("/jobs/") => "/jobs/{name}",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use serde_json::Value;
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::config::UpdateConfig;
+    use crate::state::StateStore;
+    use crate::update::Phase;
+
+    const TOKEN: &str = "s3cret";
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("pis-http-{}", uuid::Uuid::new_v4()))
+    }
+
+    // Sends `body` to `path`, with `authorization` if given.
+    async fn post(
+        filter: &(impl Filter<Extract = (Response,), Error = Rejection> + Clone + 'static),
+        path: &str,
+        authorization: Option<&str>,
+        body: &Value,
+    ) -> (StatusCode, Value) {
+        let mut request = warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("content-type", "application/json")
+            .json(body);
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        let response = request.reply(filter).await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+        (response.status(), body)
+    }
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {token}")
+    }
+
+    #[tokio::test]
+    async fn update_requires_the_token() {
+        let dir = temp_dir();
+        let state = Arc::new(StateStore::open(&dir).unwrap());
+        let config = UpdateConfig {
+            enabled: true,
+            ..UpdateConfig::default()
+        };
+        let updater = Arc::new(Updater::new(&config, state, broadcast::channel(1).0));
+        let job = json!({
+            "id": "job-1",
+            "version": "999.0.0",
+            "url": "https://updates.example.com/pis.bundle",
+            "sha256": "0".repeat(64),
+            "signature": openssl::base64::encode_block(&[0; 64]),
+        });
+
+        let unset = submit_route(updater.clone(), None);
+        let (code, body) = post(&unset, "/update", Some(&bearer(TOKEN)), &job).await;
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "update.token is not set");
+
+        let route = submit_route(updater.clone(), Some(TOKEN.to_string()));
+        for authorization in [
+            None,
+            Some("Bearer wrong!".to_string()),
+            Some(format!("Basic {TOKEN}")),
+            Some(TOKEN.to_string()),
+        ] {
+            let (code, body) = post(&route, "/update", authorization.as_deref(), &job).await;
+            assert_eq!(code, StatusCode::UNAUTHORIZED, "{authorization:?}");
+            assert_eq!(body["error"], "missing or invalid token");
+        }
+        assert_eq!(updater.progress().phase, Phase::Idle);
+
+        let (code, body) = post(&route, "/update", Some(&bearer(TOKEN)), &job).await;
+        assert_eq!(code, StatusCode::ACCEPTED);
+        assert_eq!(body["job_id"], "job-1");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn provisioning_requires_the_token() {
+        let dir = temp_dir();
+        let identity = Arc::new(IdentityStore::open(&dir).unwrap());
+        let request = json!({ "fleet_id": "north", "vehicle_id": "bus-12" });
+
+        let disabled = provision_route(
+            identity.clone(),
+            ProvisioningConfig {
+                http_enabled: false,
+                token: Some(TOKEN.to_string()),
+            },
+        );
+        let (code, _) = post(&disabled, "/provision", Some(&bearer(TOKEN)), &request).await;
+        assert_eq!(code, StatusCode::FORBIDDEN);
+
+        let unset = provision_route(
+            identity.clone(),
+            ProvisioningConfig {
+                http_enabled: true,
+                token: None,
+            },
+        );
+        let (code, body) = post(&unset, "/provision", Some(&bearer(TOKEN)), &request).await;
+        assert_eq!(code, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "provisioning.token is not set");
+
+        let route = provision_route(
+            identity.clone(),
+            ProvisioningConfig {
+                http_enabled: true,
+                token: Some(TOKEN.to_string()),
+            },
+        );
+        for authorization in [None, Some(bearer("s3creT")), Some(bearer(""))] {
+            let (code, _) = post(&route, "/provision", authorization.as_deref(), &request).await;
+            assert_eq!(code, StatusCode::UNAUTHORIZED, "{authorization:?}");
+        }
+        assert_eq!(identity.current().fleet_id, None);
+
+        let (code, body) = post(&route, "/provision", Some(&bearer(TOKEN)), &request).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["fleet_id"], "north");
+        assert_eq!(identity.current().vehicle_id.as_deref(), Some("bus-12"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::state::write_atomic;
+
+const DEVICE_ID_FILE: &str = "device-id";
+// Holds credentials, so it is only readable by the service user.
+const PROVISIONING_FILE: &str = "provisioning.json";
+
+const MACHINE_ID: &str = "/etc/machine-id";
+const SERIAL_NUMBER: &str = "/proc/device-tree/serial-number";
+const NET_DIR: &str = "/sys/class/net";
+
+// Names that topic templates may use, see `Identity::render`.
+pub const PLACEHOLDERS: [&str; 6] = [
+    "client_id",
+    "device_id",
+    "fleet_id",
+    "vehicle_id",
+    "machine_id",
+    "serial_number",
+];
+
+// Substituted for identifiers the device does not have (yet).
+const UNKNOWN: &str = "unknown";
+
+// The current identity. A new value is published whenever the device is
+// provisioned or SIGHUP reloads the provisioning file.
+pub type IdentityWatch = watch::Receiver<Arc<Identity>>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Credentials {
+    // MQTT login, used instead of mqtt.username and mqtt.password.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+// Who this unit is. Credentials are never serialized, so the identity can be
+// shown as is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Identity {
+    pub device_id: String,
+    pub machine_id: Option<String>,
+    pub serial_number: Option<String>,
+    // Interface name to MAC address.
+    pub macs: BTreeMap<String, String>,
+    pub fleet_id: Option<String>,
+    pub vehicle_id: Option<String>,
+    pub provisioned_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub credentials: Credentials,
+}
+
+impl Identity {
+    // Replaces the PLACEHOLDERS in a topic template. Identifiers the device
+    // lacks, such as the fleet id before provisioning, become "unknown".
+    pub fn render(&self, template: &str, client_id: &str) -> String {
+        let value = |id: &Option<String>| id.clone().unwrap_or_else(|| UNKNOWN.to_string());
+        template
+            .replace("{client_id}", client_id)
+            .replace("{device_id}", &self.device_id)
+            .replace("{fleet_id}", &value(&self.fleet_id))
+            .replace("{vehicle_id}", &value(&self.vehicle_id))
+            .replace("{machine_id}", &value(&self.machine_id))
+            .replace("{serial_number}", &value(&self.serial_number))
+    }
+}
+
+// What a provisioning request assigns to the device.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Provisioning {
+    pub fleet_id: String,
+    pub vehicle_id: String,
+    #[serde(default)]
+    pub credentials: Credentials,
+    // Required to replace an earlier assignment.
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum ProvisionError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("already provisioned as fleet {fleet_id}, vehicle {vehicle_id}")]
+    AlreadyProvisioned {
+        fleet_id: String,
+        vehicle_id: String,
+    },
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+// Contents of PROVISIONING_FILE.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    fleet_id: String,
+    vehicle_id: String,
+    provisioned_at: DateTime<Utc>,
+    #[serde(default)]
+    credentials: Credentials,
+}
+
+// Owns the device identity: a UUID generated on first boot, the hardware
+// identifiers found on this unit and the fleet assignment made by
+// provisioning, all kept in the state directory.
+pub struct IdentityStore {
+    dir: PathBuf,
+    tx: watch::Sender<Arc<Identity>>,
+}
+
+impl IdentityStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        let device_id = device_id(dir)?;
+        let identity = load(dir, device_id)?;
+        let (tx, _) = watch::channel(Arc::new(identity));
+        Ok(IdentityStore {
+            dir: dir.to_path_buf(),
+            tx,
+        })
+    }
+
+    pub fn current(&self) -> Arc<Identity> {
+        self.tx.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> IdentityWatch {
+        self.tx.subscribe()
+    }
+
+    // Re-reads the hardware identifiers and the provisioning file, e.g.
+    // after `pis provision` changed it while the service was running.
+    pub fn reload(&self) -> Result<()> {
+        let identity = load(&self.dir, self.current().device_id.clone())?;
+        self.publish(identity);
+        Ok(())
+    }
+
+    // Stores the assignment and publishes the new identity. A device that is
+    // already provisioned is only reassigned when the request says `force`.
+    pub fn provision(&self, request: Provisioning) -> Result<Arc<Identity>, ProvisionError> {
+        check_id("fleet_id", &request.fleet_id)?;
+        check_id("vehicle_id", &request.vehicle_id)?;
+        if request.credentials.password.is_some() && request.credentials.username.is_none() {
+            return Err(ProvisionError::Invalid(
+                "credentials.password requires credentials.username".to_string(),
+            ));
+        }
+        let current = self.current();
+        if let (Some(fleet_id), Some(vehicle_id)) = (&current.fleet_id, &current.vehicle_id) {
+            if !request.force {
+                return Err(ProvisionError::AlreadyProvisioned {
+                    fleet_id: fleet_id.clone(),
+                    vehicle_id: vehicle_id.clone(),
+                });
+            }
+        }
+
+        let record = Record {
+            fleet_id: request.fleet_id,
+            vehicle_id: request.vehicle_id,
+            provisioned_at: Utc::now(),
+            credentials: request.credentials,
+        };
+        let data = serde_json::to_vec_pretty(&record).context("cannot serialize provisioning")?;
+        write_atomic(&self.dir.join(PROVISIONING_FILE), &data, 0o600)?;
+        info!(
+            "provisioned as fleet {}, vehicle {}",
+            record.fleet_id, record.vehicle_id
+        );
+
+        let mut identity = (*current).clone();
+        apply(&mut identity, record);
+        self.publish(identity);
+        Ok(self.current())
+    }
+
+    fn publish(&self, identity: Identity) {
+        self.tx.send_if_modified(|current| {
+            if **current == identity {
+                return false;
+            }
+            *current = Arc::new(identity);
+            true
+        });
+    }
+}
+
+fn load(dir: &Path, device_id: String) -> Result<Identity> {
+    let mut identity = Identity {
+        device_id,
+        machine_id: read_id(Path::new(MACHINE_ID)),
+        serial_number: read_id(Path::new(SERIAL_NUMBER)),
+        macs: macs(Path::new(NET_DIR)),
+        fleet_id: None,
+        vehicle_id: None,
+        provisioned_at: None,
+        credentials: Credentials::default(),
+    };
+    let path = dir.join(PROVISIONING_FILE);
+    match fs::read(&path) {
+        Ok(data) => {
+            let record: Record = serde_json::from_slice(&data)
+                .with_context(|| format!("malformed {}", path.display()))?;
+            apply(&mut identity, record);
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    }
+    Ok(identity)
+}
+
+fn apply(identity: &mut Identity, record: Record) {
+    identity.fleet_id = Some(record.fleet_id);
+    identity.vehicle_id = Some(record.vehicle_id);
+    identity.provisioned_at = Some(record.provisioned_at);
+    identity.credentials = record.credentials;
+}
+
+// Ids end up in MQTT topics, so they must not contain levels or wildcards.
+// They are filled into topic templates one placeholder after the other, so
+// braces could expand into another placeholder, and topics starting with
+// `$` are reserved by brokers.
+fn check_id(key: &str, id: &str) -> Result<(), ProvisionError> {
+    if id.is_empty() {
+        return Err(ProvisionError::Invalid(format!("{key} must not be empty")));
+    }
+    if id.contains(['/', '+', '#', '{', '}', '$']) || id.chars().any(char::is_control) {
+        return Err(ProvisionError::Invalid(format!(
+            "{key} must not contain '/', '+', '#', '{{', '}}', '$' or control characters"
+        )));
+    }
+    Ok(())
+}
+
+fn device_id(dir: &Path) -> Result<String> {
+    let path = dir.join(DEVICE_ID_FILE);
+    if let Some(id) = read_stored(&path)? {
+        return Ok(id);
+    }
+    let id = Uuid::new_v4().to_string();
+    write_atomic(&path, id.as_bytes(), 0o644)?;
+    info!("generated {id} as device id");
+    Ok(id)
+}
+
+fn read_stored(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(id) if !id.trim().is_empty() => Ok(Some(id.trim().to_string())),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("cannot read {}", path.display())),
+    }
+}
+
+// Device tree strings end in a NUL. Missing files are normal: not every
+// board has a serial number and containers lack a machine id.
+fn read_id(path: &Path) -> Option<String> {
+    match fs::read_to_string(path) {
+        Ok(id) => {
+            let id = id.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!id.is_empty()).then(|| id.to_string())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("cannot read {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+// Loopback and virtual interfaces without a hardware address are skipped.
+fn macs(dir: &Path) -> BTreeMap<String, String> {
+    let mut macs = BTreeMap::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return macs;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name == "lo" {
+            continue;
+        }
+        let Ok(address) = fs::read_to_string(entry.path().join("address")) else {
+            continue;
+        };
+        let address = address.trim();
+        if address.is_empty() || address.chars().all(|c| c == '0' || c == ':') {
+            continue;
+        }
+        macs.insert(name, address.to_string());
+    }
+    macs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("pis-identity-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn request(fleet_id: &str, vehicle_id: &str, force: bool) -> Provisioning {
+        Provisioning {
+            fleet_id: fleet_id.to_string(),
+            vehicle_id: vehicle_id.to_string(),
+            credentials: Credentials::default(),
+            force,
+        }
+    }
+
+    #[test]
+    fn first_provisioning_assigns_the_device() {
+        let dir = temp_dir();
+        let store = IdentityStore::open(&dir).unwrap();
+        let identities = store.subscribe();
+        assert_eq!(store.current().fleet_id, None);
+
+        let request = Provisioning {
+            credentials: Credentials {
+                username: Some("bus-12".to_string()),
+                password: Some("secret".to_string()),
+            },
+            ..request("north", "bus-12", false)
+        };
+        let identity = store.provision(request).unwrap();
+        assert_eq!(identity.fleet_id.as_deref(), Some("north"));
+        assert_eq!(identity.vehicle_id.as_deref(), Some("bus-12"));
+        assert_eq!(identity.credentials.password.as_deref(), Some("secret"));
+        assert!(identities.has_changed().unwrap());
+        let mode = fs::metadata(dir.join(PROVISIONING_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reassignment_requires_force() {
+        let dir = temp_dir();
+        let store = IdentityStore::open(&dir).unwrap();
+        store.provision(request("north", "bus-12", false)).unwrap();
+
+        match store.provision(request("south", "bus-7", false)) {
+            Err(ProvisionError::AlreadyProvisioned {
+                fleet_id,
+                vehicle_id,
+            }) => assert_eq!(
+                (fleet_id.as_str(), vehicle_id.as_str()),
+                ("north", "bus-12")
+            ),
+            other => panic!("expected AlreadyProvisioned, got {other:?}"),
+        }
+        assert_eq!(store.current().fleet_id.as_deref(), Some("north"));
+
+        let identity = store.provision(request("south", "bus-7", true)).unwrap();
+        assert_eq!(identity.fleet_id.as_deref(), Some("south"));
+        assert_eq!(identity.vehicle_id.as_deref(), Some("bus-7"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn identity_survives_reopening() {
+        let dir = temp_dir();
+        let store = IdentityStore::open(&dir).unwrap();
+        let before = store.provision(request("north", "bus-12", false)).unwrap();
+        drop(store);
+
+        let store = IdentityStore::open(&dir).unwrap();
+        let after = store.current();
+        assert_eq!(after.device_id, before.device_id);
+        assert_eq!(after.fleet_id.as_deref(), Some("north"));
+        assert_eq!(after.vehicle_id.as_deref(), Some("bus-12"));
+        assert_eq!(after.provisioned_at, before.provisioned_at);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ids_that_break_topics_are_invalid() {
+        let dir = temp_dir();
+        let store = IdentityStore::open(&dir).unwrap();
+        for (fleet_id, vehicle_id) in [("", "bus-12"), ("north/east", "bus-12"), ("north", "#")] {
+            assert!(matches!(
+                store.provision(request(fleet_id, vehicle_id, false)),
+                Err(ProvisionError::Invalid(_))
+            ));
+        }
+        assert!(!dir.join(PROVISIONING_FILE).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ids_that_expand_placeholders_are_invalid() {
+        for id in ["{device_id}", "bus}", "{", "$SYS", "bus-$1"] {
+            match check_id("vehicle_id", id) {
+                Err(ProvisionError::Invalid(message)) => {
+                    assert!(
+                        message.starts_with("vehicle_id must not contain"),
+                        "{message}"
+                    )
+                }
+                other => panic!("{id}: expected Invalid, got {other:?}"),
+            }
+        }
+        check_id("vehicle_id", "bus-12").unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use log::Record;
+use serde_json::{json, Map, Value};
+
+use super::Sink;
+use crate::config::LogFileConfig;
+
+// Appends one JSON object per line to a file, for devices whose root file
+// system is read-only and that keep logs on a data partition instead of in
+// the journal. The file is rotated to `<path>.1`, `<path>.2`, ... once it
+// grows beyond `max_bytes` or gets older than `max_age_secs`.
+pub struct File {
+    config: LogFileConfig,
+    state: Mutex<State>,
+}
+
+struct State {
+    file: fs::File,
+    size: u64,
+    opened: SystemTime,
+}
+
+impl File {
+    pub fn open(config: &LogFileConfig) -> Result<Self> {
+        Ok(File {
+            state: Mutex::new(State::open(&config.path)?),
+            config: config.clone(),
+        })
+    }
+
+    fn rotate(&self, state: &mut State) -> Result<()> {
+        let path = &self.config.path;
+        let _ = fs::remove_file(rotated(path, self.config.keep));
+        for n in (1..self.config.keep).rev() {
+            let _ = fs::rename(rotated(path, n), rotated(path, n + 1));
+        }
+        if self.config.keep > 0 {
+            fs::rename(path, rotated(path, 1))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        *state = State::open(path)?;
+        Ok(())
+    }
+}
+
+impl State {
+    fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        let metadata = file.metadata()?;
+        Ok(State {
+            size: metadata.len(),
+            // Not every file system records a creation time.
+            opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+impl Sink for File {
+    fn write(&self, record: &Record, fields: &[(String, String)]) {
+        let mut line = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        if !fields.is_empty() {
+            let fields: Map<String, Value> = fields
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::from(value.as_str())))
+                .collect();
+            line["fields"] = Value::Object(fields);
+        }
+        let mut line = line.to_string();
+        line.push('\n');
+
+        let mut state = self.state.lock().unwrap();
+        let age = state.opened.elapsed().unwrap_or(Duration::ZERO);
+        if state.size > 0
+            && (state.size + line.len() as u64 > self.config.max_bytes
+                || age > Duration::from_secs(self.config.max_age_secs))
+        {
+            if let Err(e) = self.rotate(&mut state) {
+                eprintln!("cannot rotate {}: {e:#}", self.config.path.display());
+            }
+        }
+        match state.file.write_all(line.as_bytes()) {
+            Ok(()) => state.size += line.len() as u64,
+            Err(_) => eprint!("{line}"),
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.state.lock().unwrap().file.sync_data();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    fn write(file: &File, message: &str) {
+        file.write(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .level(Level::Info)
+                .target("hello_world_yocto::ticker")
+                .build(),
+            &[("counter".to_string(), "7".to_string())],
+        );
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_as_many_as_configured() {
+        let dir = std::env::temp_dir().join(format!("pis-log-{}", uuid::Uuid::new_v4()));
+        let config = LogFileConfig {
+            path: dir.join("pis.log"),
+            max_bytes: 400,
+            max_age_secs: 3600,
+            keep: 2,
+        };
+        let file = File::open(&config).unwrap();
+        write(&file, "first");
+        let line = fs::read_to_string(&config.path).unwrap();
+        let entry: Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(entry["message"], "first");
+        assert_eq!(entry["level"], "INFO");
+        assert_eq!(entry["target"], "hello_world_yocto::ticker");
+        assert_eq!(entry["fields"], json!({ "counter": "7" }));
+        let per_line = line.len() as u64;
+
+        for n in 0..20 {
+            write(&file, &format!("tick {n:05}"));
+        }
+        assert!(fs::metadata(&config.path).unwrap().len() <= config.max_bytes);
+        // Rotated once the next line would not have fit.
+        for n in [1, 2] {
+            let size = fs::metadata(rotated(&config.path, n)).unwrap().len();
+            assert!(size <= config.max_bytes, "{n}: {size}");
+            assert!(size > config.max_bytes - per_line, "{n}: {size}");
+        }
+        assert!(!rotated(&config.path, 3).exists());
+
+        // The newest lines are in the file itself.
+        let current = fs::read_to_string(&config.path).unwrap();
+        let last: Value = serde_json::from_str(current.lines().last().unwrap()).unwrap();
+        assert_eq!(last["message"], "tick 00019");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
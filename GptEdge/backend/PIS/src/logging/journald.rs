@@ -0,0 +1,116 @@
+use std::os::unix::net::UnixDatagram;
+
+use anyhow::{Context, Result};
+use log::Record;
+
+use super::{severity, Sink, IDENTIFIER};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+// Writes to the systemd journal using its native protocol, so every field
+// attached to a record becomes a journal field that `journalctl -o verbose`
+// shows and `journalctl FIELD=value` can match on.
+pub struct Journald {
+    socket: UnixDatagram,
+}
+
+impl Journald {
+    pub fn new() -> Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(SOCKET)
+            .with_context(|| format!("cannot connect to the journal at {SOCKET}"))?;
+        Ok(Journald { socket })
+    }
+}
+
+impl Sink for Journald {
+    fn write(&self, record: &Record, fields: &[(String, String)]) {
+        let mut data = Vec::new();
+        add_field(&mut data, "PRIORITY", &severity(record.level()).to_string());
+        add_field(&mut data, "MESSAGE", &record.args().to_string());
+        add_field(&mut data, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        add_field(&mut data, "TARGET", record.target());
+        if let Some(module) = record.module_path() {
+            add_field(&mut data, "CODE_MODULE", module);
+        }
+        if let Some(file) = record.file() {
+            add_field(&mut data, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            add_field(&mut data, "CODE_LINE", &line.to_string());
+        }
+        for (key, value) in fields {
+            if let Some(name) = field_name(key) {
+                add_field(&mut data, &name, value);
+            }
+        }
+        // Nothing sensible to do when the journal is gone; systemd keeps
+        // stderr as a fallback for the service.
+        if self.socket.send(&data).is_err() {
+            eprintln!("{}", record.args());
+        }
+    }
+}
+
+// Journal field names are upper case letters, digits and underscores, and
+// must not start with an underscore, which marks trusted fields.
+fn field_name(key: &str) -> Option<String> {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' => c.to_ascii_uppercase(),
+            'A'..='Z' | '0'..='9' => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches('_');
+    (!name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()))
+        .then(|| name.chars().take(64).collect())
+}
+
+// Values containing a newline use the length-prefixed binary form.
+fn add_field(data: &mut Vec<u8>, name: &str, value: &str) {
+    data.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        data.push(b'\n');
+        data.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        data.push(b'=');
+    }
+    data.extend_from_slice(value.as_bytes());
+    data.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_names_are_sanitized() {
+        for (key, name) in [
+            ("command", Some("COMMAND")),
+            ("trace-id", Some("TRACE_ID")),
+            ("Topic.Name", Some("TOPIC_NAME")),
+            // Leading underscores would make it a trusted field.
+            ("_PID", Some("PID")),
+            ("9lives", None),
+            ("__", None),
+            ("", None),
+        ] {
+            assert_eq!(field_name(key).as_deref(), name, "{key:?}");
+        }
+        assert_eq!(field_name(&"k".repeat(100)).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn multi_line_values_are_length_prefixed() {
+        let mut data = Vec::new();
+        add_field(&mut data, "MESSAGE", "one line");
+        add_field(&mut data, "MESSAGE", "two\nlines");
+        let mut expected = b"MESSAGE=one line\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(data, expected);
+    }
+}
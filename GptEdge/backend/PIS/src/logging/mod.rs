@@ -0,0 +1,247 @@
+mod file;
+mod journald;
+mod syslog;
+
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use log::kv::{self, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::config::{LogBackend, LogConfig};
+
+// Module paths of this crate start with the crate name; module filters may
+// leave it out, so `mqtt = "debug"` and `hello_world_yocto::mqtt = "debug"`
+// mean the same thing.
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+// Identifies the service in the journal and in syslog.
+const IDENTIFIER: &str = "pis";
+
+// Where a record ends up. Every backend gets the full record, including the
+// key-value fields attached with `log::info!(key = value; "...")`. The
+// message text stays complete on its own; fields are for machine consumers.
+trait Sink: Send + Sync {
+    fn write(&self, record: &Record, fields: &[(String, String)]);
+
+    fn flush(&self) {}
+}
+
+struct Filter {
+    default: LevelFilter,
+    // Longest prefix first, so the most specific filter wins.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn new(config: &LogConfig) -> Result<Self> {
+        let mut modules = config
+            .modules
+            .iter()
+            .map(|(module, level)| {
+                let module = module.strip_prefix(CRATE_PREFIX).unwrap_or(module);
+                Ok((module.to_string(), parse_level(level)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(Filter {
+            default: parse_level(&config.level)?,
+            modules,
+        })
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let target = target.strip_prefix(CRATE_PREFIX).unwrap_or(target);
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+struct Inner {
+    filter: Filter,
+    sink: Box<dyn Sink>,
+}
+
+struct Logger {
+    inner: RwLock<Option<Inner>>,
+}
+
+static LOGGER: Logger = Logger {
+    inner: RwLock::new(None),
+};
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.inner.read().unwrap() {
+            Some(inner) => metadata.level() <= inner.filter.level(metadata.target()),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        let inner = self.inner.read().unwrap();
+        let Some(inner) = &*inner else {
+            return;
+        };
+        if record.level() > inner.filter.level(record.target()) {
+            return;
+        }
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        inner.sink.write(record, &fields.0);
+    }
+
+    fn flush(&self) {
+        if let Some(inner) = &*self.inner.read().unwrap() {
+            inner.sink.flush();
+        }
+    }
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+// Installs a stderr logger at info level, used until the configuration has
+// been loaded. Must be called once, before anything logs.
+pub fn init() {
+    *LOGGER.inner.write().unwrap() = Some(Inner {
+        filter: Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        },
+        sink: Box::new(Stderr),
+    });
+    log::set_logger(&LOGGER).expect("logger already installed");
+    log::set_max_level(LevelFilter::Info);
+}
+
+// Switches to the configured backend and filters. Called at startup and on
+// every reload; on error the current logger stays in place.
+pub fn configure(config: &LogConfig) -> Result<()> {
+    let filter = Filter::new(config)?;
+    let sink: Box<dyn Sink> = match config.backend {
+        LogBackend::Stderr => Box::new(Stderr),
+        LogBackend::Journald => Box::new(journald::Journald::new()?),
+        LogBackend::Syslog => Box::new(syslog::Syslog::new(&config.syslog_socket)?),
+        LogBackend::File => Box::new(file::File::open(&config.file)?),
+    };
+    let max_level = filter.max_level();
+    let previous = LOGGER
+        .inner
+        .write()
+        .unwrap()
+        .replace(Inner { filter, sink });
+    if let Some(previous) = previous {
+        previous.sink.flush();
+    }
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+pub fn flush() {
+    log::logger().flush();
+}
+
+pub fn validate(config: &LogConfig) -> Result<()> {
+    Filter::new(config).map(|_| ())
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).with_context(|| format!("invalid log level {level:?}"))
+}
+
+struct Stderr;
+
+impl Sink for Stderr {
+    fn write(&self, record: &Record, _fields: &[(String, String)]) {
+        let _ = writeln!(
+            std::io::stderr().lock(),
+            "{} {:<5} {}: {}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+}
+
+// Severity as used by both syslog and the journal's PRIORITY field.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn the_most_specific_module_wins() {
+        let config = LogConfig {
+            level: "info".to_string(),
+            modules: BTreeMap::from(
+                [
+                    ("mqtt", "debug"),
+                    ("mqtt::connection", "warn"),
+                    ("hello_world_yocto::http", "error"),
+                    ("warp", "warn"),
+                ]
+                .map(|(module, level)| (module.to_string(), level.to_string())),
+            ),
+            ..Default::default()
+        };
+        let filter = Filter::new(&config).unwrap();
+        for (target, level) in [
+            ("hello_world_yocto::mqtt", LevelFilter::Debug),
+            ("hello_world_yocto::mqtt::outbox", LevelFilter::Debug),
+            ("hello_world_yocto::mqtt::connection", LevelFilter::Warn),
+            // A prefix only matches whole path segments.
+            ("hello_world_yocto::mqttx", LevelFilter::Info),
+            ("hello_world_yocto::http", LevelFilter::Error),
+            ("warp::server", LevelFilter::Warn),
+            ("warpx", LevelFilter::Info),
+            ("hello_world_yocto", LevelFilter::Info),
+        ] {
+            assert_eq!(filter.level(target), level, "{target}");
+        }
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn unknown_levels_are_rejected() {
+        let config = LogConfig {
+            modules: BTreeMap::from([("mqtt".to_string(), "verbose".to_string())]),
+            ..Default::default()
+        };
+        let e = Filter::new(&config).err().unwrap();
+        assert_eq!(e.to_string(), "invalid log level \"verbose\"");
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use log::Record;
+
+use super::{severity, Sink, IDENTIFIER};
+
+// LOG_DAEMON.
+const FACILITY: u8 = 3;
+
+// Structured data needs an enterprise number; 32473 is the one RFC 5612
+// reserves for documentation and private use.
+const SD_ID: &str = "pis@32473";
+
+// Sends RFC 5424 messages to a local syslog daemon over a Unix datagram
+// socket. Record fields go into a structured data element.
+pub struct Syslog {
+    path: PathBuf,
+    socket: UnixDatagram,
+    hostname: String,
+}
+
+impl Syslog {
+    pub fn new(path: &Path) -> Result<Self> {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        Ok(Syslog {
+            path: path.to_path_buf(),
+            socket: connect(path)?,
+            hostname: match hostname.is_empty() {
+                true => "-".to_string(),
+                false => hostname,
+            },
+        })
+    }
+}
+
+fn connect(path: &Path) -> Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .connect(path)
+        .with_context(|| format!("cannot connect to syslog at {}", path.display()))?;
+    Ok(socket)
+}
+
+impl Sink for Syslog {
+    fn write(&self, record: &Record, fields: &[(String, String)]) {
+        let mut message = format!(
+            "<{}>1 {} {} {IDENTIFIER} {} - [{SD_ID} target=\"{}\"",
+            FACILITY * 8 + severity(record.level()),
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            process::id(),
+            escape(record.target()),
+        );
+        for (key, value) in fields {
+            let name: String = key
+                .chars()
+                .filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"'))
+                .take(32)
+                .collect();
+            if !name.is_empty() {
+                let _ = write!(message, " {name}=\"{}\"", escape(value));
+            }
+        }
+        let _ = write!(message, "] {}", record.args());
+
+        // The daemon may have been restarted since we connected.
+        let sent = self.socket.send(message.as_bytes()).is_ok()
+            || connect(&self.path)
+                .and_then(|socket| Ok(socket.send(message.as_bytes())?))
+                .is_ok();
+        if !sent {
+            eprintln!("{}", record.args());
+        }
+    }
+}
+
+// Parameter values escape '"', '\' and ']'.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn param_values_are_escaped() {
+        assert_eq!(escape("plain value"), "plain value");
+        assert_eq!(escape(r#"a"b\c]d"#), r#"a\"b\\c\]d"#);
+    }
+
+    #[test]
+    fn fields_become_structured_data() {
+        let dir = std::env::temp_dir().join(format!("pis-syslog-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::new(&path).unwrap();
+        let fields = [
+            ("topic".to_string(), r#"pis/"unit"]"#.to_string()),
+            (r#"bad=name"]"#.to_string(), "x".to_string()),
+        ];
+        syslog.write(
+            &Record::builder()
+                .args(format_args!("cannot subscribe"))
+                .level(Level::Error)
+                .target("hello_world_yocto::mqtt")
+                .build(),
+            &fields,
+        );
+
+        let mut buf = [0; 1024];
+        let len = daemon.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        // LOG_DAEMON at LOG_ERR.
+        assert!(message.starts_with("<27>1 "), "{message}");
+        assert!(
+            message.ends_with(
+                r#" - [pis@32473 target="hello_world_yocto::mqtt" topic="pis/\"unit\"\]" badname="x"] cannot subscribe"#
+            ),
+            "{message}"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
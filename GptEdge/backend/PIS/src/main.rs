@@ -1,16 +1,288 @@
-use std::process;
-use std::{thread, time};
+mod buildinfo;
+mod cli;
+mod codec;
+mod components;
+mod config;
+mod error;
+mod host;
+mod http;
+mod identity;
+mod logging;
+mod metrics;
+mod mqtt;
+mod scheduler;
+mod shadow;
+mod signals;
+mod state;
+mod status;
+mod subcommand;
+mod supervisor;
+mod systemd;
+mod ticker;
+mod update;
+mod watchdog;
 
-fn main() {
-    let delay = time::Duration::from_secs(3);
-    let mut counter = 0;
-    loop{
-        println!("sleeping for 3  sec ");
-        thread::sleep(delay);
-        counter += 1;
-        if counter > 10 {
-            process::exit(0x0100);
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::sync::{broadcast, mpsc, watch};
+
+use cli::{Args, Command};
+use config::Config;
+use error::{Error, EXIT_OK, EXIT_RESTART, EXIT_USAGE};
+use identity::IdentityStore;
+use metrics::Metrics;
+use scheduler::Scheduler;
+use shadow::Shadow;
+use state::StateStore;
+use status::Status;
+use supervisor::Supervisor;
+use ticker::Ticker;
+use update::Updater;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    logging::init();
+    let args = match Args::parse_checked() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            return ExitCode::from(if e.use_stderr() { EXIT_USAGE } else { EXIT_OK });
+        }
+    };
+    let code = match run(args).await {
+        Ok(()) => ExitCode::from(EXIT_OK),
+        Err(Error::Restart(reason)) => {
+            log::info!("exiting to be restarted: {reason}");
+            ExitCode::from(EXIT_RESTART)
+        }
+        Err(e) => {
+            log::error!("{e:#}");
+            e.into()
+        }
+    };
+    logging::flush();
+    code
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let load = || config::load(&args).map_err(Error::Config);
+    match args.command.clone() {
+        None | Some(Command::Run(_)) => {
+            let config = load()?;
+            serve(args, config).await
         }
+        Some(Command::CheckConfig { print }) => subcommand::check_config(&load()?, print),
+        Some(Command::PrintDefaultConfig { json }) => subcommand::print_default_config(json),
+        Some(Command::Version { json }) => subcommand::version(json),
+        Some(Command::MqttPublish {
+            topic,
+            qos,
+            retain,
+            message,
+        }) => subcommand::mqtt_publish(&load()?, &topic, qos, retain, &message).await,
+        Some(Command::Status { address }) => subcommand::status(&load()?, address).await,
+        Some(Command::SelfTest) => subcommand::self_test(&load()?).await,
+        Some(Command::Identity) => subcommand::identity(&load()?),
+        Some(Command::Provision {
+            fleet_id,
+            vehicle_id,
+            username,
+            password_file,
+            force,
+        }) => subcommand::provision(
+            &load()?,
+            fleet_id,
+            vehicle_id,
+            username,
+            password_file.as_deref(),
+            force,
+        ),
     }
 }
 
+async fn serve(args: Args, config: Config) -> Result<(), Error> {
+    logging::configure(&config.log).map_err(Error::Config)?;
+    let state =
+        Arc::new(StateStore::open(&config.service.state_dir).context("cannot open state store")?);
+    let identity = Arc::new(
+        IdentityStore::open(&config.service.state_dir).context("cannot load device identity")?,
+    );
+    log::info!("device id {}", identity.current().device_id);
+    let (config_tx, config_rx) = watch::channel(Arc::new(config));
+
+    let status = Arc::new(Status::new());
+    let mut supervisor = Supervisor::new(config_rx.clone(), status.clone());
+    // On SIGHUP, and when the device shadow changed the remote settings.
+    let reload: shadow::Reload = {
+        let identity = identity.clone();
+        let args = args.clone();
+        Arc::new(move |remote| {
+            let config = config::load_with(&args, remote)?;
+            logging::configure(&config.log)?;
+            identity.reload()?;
+            config_tx.send_replace(Arc::new(config));
+            Ok(())
+        })
+    };
+    {
+        let reload = reload.clone();
+        supervisor.on_reload(move || reload(None));
+    }
+    let metrics = Arc::new(Metrics::default());
+    let (tick_tx, _) = broadcast::channel(16);
+    let (publication_tx, _) = broadcast::channel(64);
+    let updater = Arc::new(Updater::new(
+        &config_rx.borrow().update,
+        state.clone(),
+        publication_tx.clone(),
+    ));
+    let mut scheduler = Scheduler::new();
+    {
+        let ticker = Arc::new(Ticker::new(
+            config_rx.clone(),
+            tick_tx.clone(),
+            status.clone(),
+            metrics.clone(),
+            state.clone(),
+            supervisor.shutdown_handle(),
+        ));
+        scheduler.register(ticker::JOB, Ticker::timing, move |run| {
+            let ticker = ticker.clone();
+            async move { ticker.tick(&run) }
+        });
+    }
+    let scheduler = Arc::new(scheduler);
+    let components = components::registry()
+        .build(
+            &config_rx.borrow().components,
+            &components::Context {
+                publications: publication_tx.clone(),
+            },
+        )
+        .map_err(Error::Config)?;
+    let mut subscriptions = Vec::new();
+    let shadow = match config_rx.borrow().shadow.enabled {
+        true => {
+            let (desired_tx, desired_rx) = mpsc::channel(8);
+            subscriptions.push(mqtt::Subscription {
+                topic: config_rx.borrow().shadow.desired_topic.clone(),
+                messages: desired_tx,
+            });
+            Some(Arc::new(Shadow::new(
+                args,
+                config_rx.clone(),
+                reload.clone(),
+                publication_tx.clone(),
+                desired_rx,
+            )))
+        }
+        false => None,
+    };
+    if config_rx.borrow().mqtt.enabled {
+        let mut commands = mqtt::command::builtin(
+            supervisor.shutdown_handle(),
+            config_rx.borrow().service.reboot_command.clone(),
+            status.clone(),
+            identity.clone(),
+            reload.clone(),
+        );
+        if config_rx.borrow().update.enabled {
+            commands.register(
+                "update",
+                update::UpdateCommand {
+                    updater: updater.clone(),
+                },
+            );
+        }
+        let commands = Arc::new(commands);
+        let config_rx = config_rx.clone();
+        let metrics = metrics.clone();
+        let tick_tx = tick_tx.clone();
+        let status = status.clone();
+        let identity = identity.clone();
+        supervisor.add("mqtt", move |shutdown| {
+            mqtt::run(
+                config_rx.clone(),
+                metrics.clone(),
+                commands.clone(),
+                mqtt::Feed {
+                    ticks: tick_tx.subscribe(),
+                    publications: publication_tx.subscribe(),
+                    subscriptions: subscriptions.clone(),
+                },
+                status.clone(),
+                identity.subscribe(),
+                shutdown,
+            )
+        });
+    }
+    if config_rx.borrow().http.enabled {
+        let config_rx = config_rx.clone();
+        let status = status.clone();
+        let metrics = metrics.clone();
+        let identity = identity.clone();
+        let updater = updater.clone();
+        let scheduler = scheduler.clone();
+        supervisor.add_reporting(http::NAME, move |shutdown| {
+            http::run(
+                config_rx.clone(),
+                status.clone(),
+                metrics.clone(),
+                identity.clone(),
+                updater.clone(),
+                scheduler.clone(),
+                shutdown,
+            )
+        });
+    }
+    if let Some(notifier) = systemd::Notifier::from_env().map_err(Error::Config)? {
+        let config_rx = config_rx.clone();
+        let status = status.clone();
+        let notifier = Arc::new(notifier);
+        supervisor.add("systemd", move |shutdown| {
+            systemd::run(
+                config_rx.clone(),
+                notifier.clone(),
+                status.clone(),
+                shutdown,
+            )
+        });
+    }
+    if config_rx.borrow().watchdog.enabled {
+        let config_rx = config_rx.clone();
+        let status = status.clone();
+        supervisor.add(watchdog::NAME, move |shutdown| {
+            watchdog::run(config_rx.clone(), status.clone(), shutdown)
+        });
+    }
+    if config_rx.borrow().update.enabled {
+        let config_rx = config_rx.clone();
+        let status = status.clone();
+        supervisor.add(update::NAME, move |shutdown| {
+            update::run(config_rx.clone(), updater.clone(), status.clone(), shutdown)
+        });
+    }
+    {
+        let config_rx = config_rx.clone();
+        let state = state.clone();
+        supervisor.add("state", move |shutdown| {
+            state::run(config_rx.clone(), state.clone(), shutdown)
+        });
+    }
+    supervisor.add(scheduler::NAME, move |shutdown| {
+        scheduler::run(config_rx.clone(), scheduler.clone(), shutdown)
+    });
+    if let Some(shadow) = shadow {
+        supervisor.add_subsystem(shadow);
+    }
+    for component in components {
+        supervisor.add_subsystem(component);
+    }
+    let result = supervisor.run().await;
+    if let Err(e) = state.flush() {
+        log::error!("cannot save state: {e:#}");
+    }
+    result
+}
@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds in seconds. Ticks are expected to be a few milliseconds late
+// at most, HTTP requests to be answered well within a second.
+const TICK_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+const HTTP_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+// Process-wide counters and gauges, shared by the subsystems that update them.
+#[derive(Debug)]
+pub struct Metrics {
+    pub ticks: AtomicU64,
+    // How late each tick fired compared to its schedule.
+    pub tick_latency: Histogram,
+    // Deviation of the last interval between two ticks from the configured
+    // period, in microseconds.
+    pub tick_jitter_us: AtomicU64,
+    pub mqtt_publishes: AtomicU64,
+    pub mqtt_publish_failures: AtomicU64,
+    pub mqtt_reconnects: AtomicU64,
+    pub mqtt_connected: AtomicU64,
+    pub mqtt_connect_failures: AtomicU64,
+    pub mqtt_failovers: AtomicU64,
+    mqtt_transitions: Mutex<BTreeMap<&'static str, u64>>,
+    pub mqtt_queue_depth: AtomicU64,
+    pub mqtt_queue_bytes: AtomicU64,
+    pub mqtt_queue_dropped: AtomicU64,
+    pub mqtt_expired: AtomicU64,
+    http_requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    http_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            ticks: AtomicU64::new(0),
+            tick_latency: Histogram::new(TICK_BUCKETS),
+            tick_jitter_us: AtomicU64::new(0),
+            mqtt_publishes: AtomicU64::new(0),
+            mqtt_publish_failures: AtomicU64::new(0),
+            mqtt_reconnects: AtomicU64::new(0),
+            mqtt_connected: AtomicU64::new(0),
+            mqtt_connect_failures: AtomicU64::new(0),
+            mqtt_failovers: AtomicU64::new(0),
+            mqtt_transitions: Mutex::new(BTreeMap::new()),
+            mqtt_queue_depth: AtomicU64::new(0),
+            mqtt_queue_bytes: AtomicU64::new(0),
+            mqtt_queue_dropped: AtomicU64::new(0),
+            mqtt_expired: AtomicU64::new(0),
+            http_requests: Mutex::new(BTreeMap::new()),
+            http_latency: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Metrics {
+    // `route` must come from a fixed set so that scanners probing random
+    // paths cannot create new series.
+    pub fn record_http(&self, route: &'static str, code: u16, elapsed: Duration) {
+        *self
+            .http_requests
+            .lock()
+            .unwrap()
+            .entry((route, code))
+            .or_default() += 1;
+        self.http_latency
+            .lock()
+            .unwrap()
+            .entry(route)
+            .or_insert_with(|| Histogram::new(HTTP_BUCKETS))
+            .observe(elapsed);
+    }
+
+    pub fn record_mqtt_transition(&self, state: &'static str) {
+        *self
+            .mqtt_transitions
+            .lock()
+            .unwrap()
+            .entry(state)
+            .or_default() += 1;
+    }
+
+    // Renders everything in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            header(out, name, help, "counter");
+            let _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        };
+        let gauge = |out: &mut String, name: &str, help: &str, value: f64| {
+            header(out, name, help, "gauge");
+            let _ = writeln!(out, "{name} {value}");
+        };
+
+        counter(
+            &mut out,
+            "pis_ticks_total",
+            "Loop ticks since start.",
+            &self.ticks,
+        );
+        header(
+            &mut out,
+            "pis_tick_latency_seconds",
+            "Delay between a tick's schedule and its execution.",
+            "histogram",
+        );
+        self.tick_latency
+            .render(&mut out, "pis_tick_latency_seconds", "");
+        gauge(
+            &mut out,
+            "pis_tick_jitter_seconds",
+            "Deviation of the last tick interval from the configured period.",
+            self.tick_jitter_us.load(Ordering::Relaxed) as f64 / 1e6,
+        );
+
+        counter(
+            &mut out,
+            "pis_mqtt_publishes_total",
+            "MQTT messages accepted by the broker.",
+            &self.mqtt_publishes,
+        );
+        counter(
+            &mut out,
+            "pis_mqtt_publish_failures_total",
+            "MQTT publishes that failed.",
+            &self.mqtt_publish_failures,
+        );
+        counter(
+            &mut out,
+            "pis_mqtt_reconnects_total",
+            "Successful reconnections to the MQTT broker.",
+            &self.mqtt_reconnects,
+        );
+        gauge(
+            &mut out,
+            "pis_mqtt_connected",
+            "Whether the MQTT broker connection is up.",
+            self.mqtt_connected.load(Ordering::Relaxed) as f64,
+        );
+        counter(
+            &mut out,
+            "pis_mqtt_connect_failures_total",
+            "Connection attempts in which no MQTT broker could be reached.",
+            &self.mqtt_connect_failures,
+        );
+        counter(
+            &mut out,
+            "pis_mqtt_failovers_total",
+            "Connections made to a failover MQTT broker.",
+            &self.mqtt_failovers,
+        );
+        header(
+            &mut out,
+            "pis_mqtt_state_transitions_total",
+            "MQTT connection state changes by new state.",
+            "counter",
+        );
+        for (state, count) in self.mqtt_transitions.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "pis_mqtt_state_transitions_total{{state=\"{}\"}} {count}",
+                escape(state)
+            );
+        }
+        gauge(
+            &mut out,
+            "pis_mqtt_queue_depth",
+            "Messages waiting in the MQTT outbox.",
+            self.mqtt_queue_depth.load(Ordering::Relaxed) as f64,
+        );
+        gauge(
+            &mut out,
+            "pis_mqtt_queue_bytes",
+            "Disk space used by the MQTT outbox.",
+            self.mqtt_queue_bytes.load(Ordering::Relaxed) as f64,
+        );
+        counter(
+            &mut out,
+            "pis_mqtt_queue_dropped_total",
+            "Queued MQTT messages dropped because of age or size limits.",
+            &self.mqtt_queue_dropped,
+        );
+        counter(
+            &mut out,
+            "pis_mqtt_expired_total",
+            "MQTT messages dropped because their message expiry passed before they were sent.",
+            &self.mqtt_expired,
+        );
+
+        header(
+            &mut out,
+            "pis_http_requests_total",
+            "HTTP requests by route and status code.",
+            "counter",
+        );
+        for ((route, code), count) in self.http_requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "pis_http_requests_total{{route=\"{}\",code=\"{code}\"}} {count}",
+                escape(route)
+            );
+        }
+        header(
+            &mut out,
+            "pis_http_request_duration_seconds",
+            "Time taken to answer HTTP requests.",
+            "histogram",
+        );
+        for (route, histogram) in self.http_latency.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "pis_http_request_duration_seconds",
+                &format!("route=\"{}\",", escape(route)),
+            );
+        }
+
+        if let Some(process) = ProcessStats::read() {
+            gauge(
+                &mut out,
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes.",
+                process.rss_bytes as f64,
+            );
+            header(
+                &mut out,
+                "process_cpu_seconds_total",
+                "User and system CPU time spent in seconds.",
+                "counter",
+            );
+            let _ = writeln!(out, "process_cpu_seconds_total {}", process.cpu_seconds);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+// Label values may not contain bare backslashes, double quotes or newlines.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Cumulative histogram with fixed buckets, updated without locking.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // One count per bound plus the +Inf bucket, not yet cumulative.
+    counts: Vec<AtomicU64>,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // `labels` is either empty or a list of `key="value",` pairs.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut total = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{le}\"}} {total}");
+        }
+        let labels = labels.trim_end_matches(',');
+        let labels = match labels.is_empty() {
+            true => String::new(),
+            false => format!("{{{labels}}}"),
+        };
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {total}");
+    }
+}
+
+struct ProcessStats {
+    rss_bytes: u64,
+    cpu_seconds: f64,
+}
+
+impl ProcessStats {
+    // Reads /proc/self/stat; None where that is unavailable.
+    fn read() -> Option<Self> {
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+        // The command name may contain spaces, the fields after it do not.
+        let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+        // Numbered from the state field, which is field 3 in proc(5).
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+        let rss_pages: u64 = fields.get(21)?.parse().ok()?;
+        // SAFETY: sysconf only reads system configuration.
+        let (ticks, page_size) = unsafe {
+            (
+                libc::sysconf(libc::_SC_CLK_TCK),
+                libc::sysconf(libc::_SC_PAGESIZE),
+            )
+        };
+        if ticks <= 0 || page_size <= 0 {
+            return None;
+        }
+        Some(ProcessStats {
+            rss_bytes: rss_pages * page_size as u64,
+            cpu_seconds: (utime + stime) as f64 / ticks as f64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The sample lines of `name` in `out`, as label set and value.
+    fn samples<'a>(out: &'a str, name: &str) -> Vec<(&'a str, &'a str)> {
+        out.lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.rsplit_once(' '))
+            .filter(|(series, _)| {
+                series
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('{'))
+            })
+            .map(|(series, value)| (&series[name.len()..], value))
+            .collect()
+    }
+
+    #[test]
+    fn every_metric_has_help_and_type() {
+        let metrics = Metrics::default();
+        metrics.ticks.store(3, Ordering::Relaxed);
+        let out = metrics.render();
+
+        let help = out.find("# HELP pis_ticks_total Loop ticks since start.\n");
+        let kind = out.find("# TYPE pis_ticks_total counter\n");
+        let sample = out.find("pis_ticks_total 3\n");
+        assert!(help < kind && kind < sample && help.is_some(), "{out}");
+        assert!(out.contains("# TYPE pis_mqtt_connected gauge\n"));
+        assert!(out.contains("# TYPE pis_tick_latency_seconds histogram\n"));
+
+        // Each HELP line is followed by the TYPE line of the same metric.
+        let lines: Vec<&str> = out.lines().collect();
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(rest) = line.strip_prefix("# HELP ") {
+                let name = rest.split(' ').next().unwrap();
+                assert!(
+                    lines[i + 1].starts_with(&format!("# TYPE {name} ")),
+                    "{line}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.record_http("/a\"b\\c\nd", 200, Duration::from_millis(2));
+        let out = metrics.render();
+        assert!(
+            out.contains("pis_http_requests_total{route=\"/a\\\"b\\\\c\\nd\",code=\"200\"} 1\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("pis_http_request_duration_seconds_count{route=\"/a\\\"b\\\\c\\nd\"} 1\n"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        for ms in [0, 3, 3, 40, 700, 2000] {
+            metrics.tick_latency.observe(Duration::from_millis(ms));
+        }
+        let out = metrics.render();
+        let name = "pis_tick_latency_seconds";
+        assert_eq!(
+            samples(&out, &format!("{name}_bucket")),
+            [
+                ("{le=\"0.001\"}", "1"),
+                ("{le=\"0.005\"}", "3"),
+                ("{le=\"0.01\"}", "3"),
+                ("{le=\"0.05\"}", "4"),
+                ("{le=\"0.1\"}", "4"),
+                ("{le=\"0.5\"}", "4"),
+                ("{le=\"1\"}", "5"),
+                ("{le=\"+Inf\"}", "6"),
+            ]
+        );
+        assert_eq!(samples(&out, &format!("{name}_count")), [("", "6")]);
+        assert_eq!(samples(&out, &format!("{name}_sum")), [("", "2.746")]);
+
+        // Labelled histograms put `le` after the other labels.
+        metrics.record_http("/health", 200, Duration::from_millis(20));
+        let out = metrics.render();
+        let name = "pis_http_request_duration_seconds";
+        let buckets = samples(&out, &format!("{name}_bucket"));
+        assert_eq!(buckets.len(), HTTP_BUCKETS.len() + 1);
+        assert_eq!(buckets[2], ("{route=\"/health\",le=\"0.01\"}", "0"));
+        assert_eq!(buckets[3], ("{route=\"/health\",le=\"0.05\"}", "1"));
+        assert_eq!(
+            buckets.last().unwrap(),
+            &("{route=\"/health\",le=\"+Inf\"}", "1")
+        );
+        assert_eq!(
+            samples(&out, &format!("{name}_count")),
+            [("{route=\"/health\"}", "1")]
+        );
+    }
+}
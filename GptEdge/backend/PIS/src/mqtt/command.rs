@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use log::{info, warn};
+use paho_mqtt as mqtt;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::codec::Codec;
+use crate::identity::IdentityStore;
+use crate::shadow::Reload;
+use crate::status::Status;
+use crate::supervisor::Shutdown;
+
+// Something the back office can ask a unit to do. Handlers receive the
+// `params` of the request and return the `result` of the response.
+#[async_trait]
+pub trait CommandHandler: Send + Sync {
+    async fn handle(&self, params: Value) -> Result<Value>;
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    command: String,
+    #[serde(default)]
+    params: Value,
+}
+
+// Sent on the response topic. With MQTT v3 the request `id` is the only way
+// to match a response to its request.
+#[derive(Serialize)]
+struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    command: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Commands that may run at the same time. Requests beyond that are answered
+// with an error rather than queued, so that a flood of requests cannot pile
+// up handlers.
+const MAX_RUNNING: usize = 8;
+
+pub struct Commands {
+    handlers: HashMap<String, Arc<dyn CommandHandler>>,
+    running: Semaphore,
+}
+
+impl Default for Commands {
+    fn default() -> Self {
+        Commands {
+            handlers: HashMap::new(),
+            running: Semaphore::new(MAX_RUNNING),
+        }
+    }
+}
+
+impl Commands {
+    pub fn register(&mut self, name: &str, handler: impl CommandHandler + 'static) {
+        self.handlers.insert(name.to_string(), Arc::new(handler));
+    }
+
+    // Runs the command carried by `message`, encoded with `codec`, and
+    // publishes the reply in the same encoding, with `properties` on MQTT
+    // v5: to the response topic with the request's correlation data when the
+    // requester supplied one, otherwise to `response_topic`. While
+    // MAX_RUNNING commands are running the reply is "busy" instead.
+    pub async fn dispatch(
+        &self,
+        client: &mqtt::AsyncClient,
+        message: mqtt::Message,
+        response_topic: &str,
+        qos: i32,
+        properties: mqtt::Properties,
+        codec: Codec,
+    ) -> Result<()> {
+        let reply = self
+            .reply(&message, response_topic, qos, properties, codec)
+            .await?;
+        client
+            .publish(reply)
+            .await
+            .context("cannot publish command response")
+    }
+
+    async fn reply(
+        &self,
+        message: &mqtt::Message,
+        response_topic: &str,
+        qos: i32,
+        mut properties: mqtt::Properties,
+        codec: Codec,
+    ) -> Result<mqtt::Message> {
+        let response = match codec.decode::<Request>(message.payload()) {
+            Ok(request) => match self.running.try_acquire() {
+                Ok(_permit) => self.run(request).await,
+                Err(_) => {
+                    warn!(command = request.command.as_str(); "command {} refused, {MAX_RUNNING} commands running", request.command);
+                    Response {
+                        id: request.id,
+                        command: request.command,
+                        ok: false,
+                        result: None,
+                        error: Some(format!("busy, {MAX_RUNNING} commands running")),
+                    }
+                }
+            },
+            Err(e) => Response {
+                id: None,
+                command: String::new(),
+                ok: false,
+                result: None,
+                error: Some(format!("malformed request: {e:#}")),
+            },
+        };
+
+        let request = message.properties();
+        let topic = match request.get_string(mqtt::PropertyCode::ResponseTopic) {
+            Some(topic) => {
+                if let Some(data) = request.get_binary(mqtt::PropertyCode::CorrelationData) {
+                    properties.push_binary(mqtt::PropertyCode::CorrelationData, data)?;
+                }
+                topic
+            }
+            None => response_topic.to_string(),
+        };
+        Ok(mqtt::MessageBuilder::new()
+            .topic(topic)
+            .payload(codec.encode(&response)?)
+            .qos(qos)
+            .properties(properties)
+            .finalize())
+    }
+
+    async fn run(&self, request: Request) -> Response {
+        let outcome = match self.handlers.get(&request.command) {
+            Some(handler) => handler.handle(request.params).await,
+            None => Err(anyhow!("unknown command {:?}", request.command)),
+        };
+        match &outcome {
+            Ok(_) => {
+                info!(command = request.command.as_str(); "command {} succeeded", request.command)
+            }
+            Err(e) => {
+                warn!(command = request.command.as_str(); "command {} failed: {e:#}", request.command)
+            }
+        }
+        Response {
+            id: request.id,
+            command: request.command,
+            ok: outcome.is_ok(),
+            error: outcome.as_ref().err().map(|e| format!("{e:#}")),
+            result: outcome.ok(),
+        }
+    }
+}
+
+struct Ping {
+    started: Instant,
+}
+
+#[async_trait]
+impl CommandHandler for Ping {
+    async fn handle(&self, _params: Value) -> Result<Value> {
+        Ok(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_secs": self.started.elapsed().as_secs(),
+        }))
+    }
+}
+
+// What GET /status reports: uptime, ticks, version, identity and subsystem
+// states.
+struct StatusCommand {
+    status: Arc<Status>,
+    identity: Arc<IdentityStore>,
+}
+
+#[async_trait]
+impl CommandHandler for StatusCommand {
+    async fn handle(&self, _params: Value) -> Result<Value> {
+        let mut report = serde_json::to_value(self.status.report())?;
+        report["identity"] = serde_json::to_value(&*self.identity.current())?;
+        Ok(report)
+    }
+}
+
+// Reloads the configuration as SIGHUP does. When the result is invalid the
+// service keeps its current configuration and the error is the reply.
+struct ReloadCommand {
+    reload: Reload,
+}
+
+#[async_trait]
+impl CommandHandler for ReloadCommand {
+    async fn handle(&self, _params: Value) -> Result<Value> {
+        (self.reload)(None)?;
+        Ok(Value::Null)
+    }
+}
+
+// Stops the service cleanly with EXIT_RESTART; the service manager is
+// expected to start it again.
+struct Restart {
+    shutdown: Shutdown,
+}
+
+#[async_trait]
+impl CommandHandler for Restart {
+    async fn handle(&self, _params: Value) -> Result<Value> {
+        self.shutdown.restart("restart command");
+        Ok(Value::Null)
+    }
+}
+
+struct Reboot {
+    command: Vec<String>,
+}
+
+#[async_trait]
+impl CommandHandler for Reboot {
+    async fn handle(&self, _params: Value) -> Result<Value> {
+        let Some((program, args)) = self.command.split_first() else {
+            bail!("no reboot command configured");
+        };
+        let status = Command::new(program)
+            .args(args)
+            .status()
+            .await
+            .with_context(|| format!("cannot run {program}"))?;
+        if !status.success() {
+            bail!("{program} failed with {status}");
+        }
+        Ok(Value::Null)
+    }
+}
+
+pub fn builtin(
+    shutdown: Shutdown,
+    reboot_command: Vec<String>,
+    status: Arc<Status>,
+    identity: Arc<IdentityStore>,
+    reload: Reload,
+) -> Commands {
+    let mut commands = Commands::default();
+    commands.register(
+        "ping",
+        Ping {
+            started: Instant::now(),
+        },
+    );
+    commands.register("status", StatusCommand { status, identity });
+    commands.register("reload", ReloadCommand { reload });
+    commands.register("restart", Restart { shutdown });
+    commands.register(
+        "reboot",
+        Reboot {
+            command: reboot_command,
+        },
+    );
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait]
+    impl CommandHandler for Echo {
+        async fn handle(&self, params: Value) -> Result<Value> {
+            Ok(params)
+        }
+    }
+
+    fn commands() -> Commands {
+        let mut commands = Commands::default();
+        commands.register("echo", Echo);
+        commands
+    }
+
+    fn request(payload: &str, properties: mqtt::Properties) -> mqtt::Message {
+        mqtt::MessageBuilder::new()
+            .topic("pis/unit/commands")
+            .payload(payload)
+            .properties(properties)
+            .finalize()
+    }
+
+    // The topic and decoded body of the reply to `payload`.
+    async fn reply(commands: &Commands, payload: &str) -> (String, Value) {
+        let reply = commands
+            .reply(
+                &request(payload, mqtt::Properties::new()),
+                "pis/unit/responses",
+                1,
+                mqtt::Properties::new(),
+                Codec::JSON,
+            )
+            .await
+            .unwrap();
+        (
+            reply.topic().to_string(),
+            serde_json::from_slice(reply.payload()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn replies_echo_the_request_id() {
+        let (topic, body) = reply(
+            &commands(),
+            r#"{"id": "req-1", "command": "echo", "params": {"x": 1}}"#,
+        )
+        .await;
+        assert_eq!(topic, "pis/unit/responses");
+        assert_eq!(
+            body,
+            json!({ "id": "req-1", "command": "echo", "ok": true, "result": { "x": 1 } })
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_are_an_error() {
+        let (_, body) = reply(&commands(), r#"{"id": 7, "command": "format"}"#).await;
+        assert_eq!(
+            body,
+            json!({ "id": 7, "command": "format", "ok": false, "error": "unknown command \"format\"" })
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_answered() {
+        let (topic, body) = reply(&commands(), r#"{"id": 7, "params": {}}"#).await;
+        assert_eq!(topic, "pis/unit/responses");
+        assert_eq!(body["ok"], false);
+        assert_eq!(body["command"], "");
+        assert!(body.get("id").is_none());
+        let error = body["error"].as_str().unwrap();
+        assert!(error.starts_with("malformed request: "), "{error}");
+
+        let (_, body) = reply(&commands(), "{").await;
+        assert_eq!(body["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn v5_replies_go_to_the_response_topic_with_the_correlation_data() {
+        let mut properties = mqtt::Properties::new();
+        properties
+            .push_string(mqtt::PropertyCode::ResponseTopic, "backoffice/replies/42")
+            .unwrap();
+        properties
+            .push_binary(mqtt::PropertyCode::CorrelationData, b"42".to_vec())
+            .unwrap();
+        let reply = commands()
+            .reply(
+                &request(r#"{"command": "echo"}"#, properties),
+                "pis/unit/responses",
+                1,
+                mqtt::Properties::new(),
+                Codec::JSON,
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.topic(), "backoffice/replies/42");
+        assert_eq!(
+            reply
+                .properties()
+                .get_binary(mqtt::PropertyCode::CorrelationData),
+            Some(b"42".to_vec())
+        );
+        let body: Value = serde_json::from_slice(reply.payload()).unwrap();
+        assert_eq!(body["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_max_running_are_refused() {
+        let commands = commands();
+        let running = commands
+            .running
+            .try_acquire_many(MAX_RUNNING as u32)
+            .unwrap();
+        let (_, body) = reply(&commands, r#"{"id": 1, "command": "echo"}"#).await;
+        assert_eq!(
+            body,
+            json!({ "id": 1, "command": "echo", "ok": false, "error": "busy, 8 commands running" })
+        );
+
+        drop(running);
+        let (_, body) = reply(&commands, r#"{"id": 2, "command": "echo"}"#).await;
+        assert_eq!(body["ok"], true);
+    }
+
+    #[tokio::test]
+    async fn a_failed_reload_is_the_reply() {
+        let mut commands = Commands::default();
+        commands.register(
+            "reload",
+            ReloadCommand {
+                reload: Arc::new(|_| bail!("invalid configuration: ticker.interval_ms")),
+            },
+        );
+        let (_, body) = reply(&commands, r#"{"command": "reload"}"#).await;
+        assert_eq!(
+            body,
+            json!({ "command": "reload", "ok": false, "error": "invalid configuration: ticker.interval_ms" })
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use paho_mqtt as mqtt;
+use serde_derive::Serialize;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::{self, Instant};
+
+use super::outbox::QueuedMessage;
+use super::v5;
+use crate::config::{MqttConfig, Protocol, ReconnectConfig};
+use crate::identity::Credentials;
+use crate::metrics::Metrics;
+use crate::status::{State, Status};
+
+// A connection lost sooner than this counts as a failed attempt, so that a
+// broker dropping every client right after accepting it is not hammered.
+const STABLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+impl ConnectionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "disconnected",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+        }
+    }
+}
+
+// A change of the broker connection. Every one is logged and counted, and
+// published on mqtt.events_topic once the connection allows it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub state: ConnectionState,
+    pub previous: ConnectionState,
+    // The server connected to, or the one that was lost.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    // Failed connection attempts before this one succeeded.
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub session: Option<Session>,
+    pub timestamp: DateTime<Utc>,
+}
+
+// What the broker agreed to when the connection was set up.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Session {
+    pub protocol: &'static str,
+    #[serde(skip)]
+    pub topic_alias_maximum: u16,
+}
+
+// Keeps a client connected. Each attempt tries the configured servers in
+// order, primary first; rounds in which none of them answers are followed
+// by an exponentially growing delay with jitter. A unit that failed over
+// stays on the failover server until that connection is lost. With
+// protocol "auto", a client that had to fall back to 3.1.1 keeps using it.
+pub struct Connection {
+    pub client: mqtt::AsyncClient,
+    pub settings: MqttConfig,
+    pub credentials: Credentials,
+    pub will: Option<QueuedMessage>,
+    pub metrics: Arc<Metrics>,
+    pub status: Arc<Status>,
+    pub transitions: mpsc::UnboundedSender<Transition>,
+    // Whether an earlier client of this service was connected already.
+    pub reconnect: bool,
+    // Counts lost connections, see `v5::Publisher`.
+    pub epoch: Arc<AtomicU64>,
+}
+
+impl Connection {
+    pub async fn maintain(mut self) -> Result<()> {
+        let lost = Arc::new(Notify::new());
+        {
+            let (lost, epoch) = (lost.clone(), self.epoch.clone());
+            self.client.set_connection_lost_callback(move |_| {
+                epoch.fetch_add(1, Ordering::SeqCst);
+                lost.notify_one();
+            });
+        }
+        // Why an MQTT v5 broker ended the connection, if it said so.
+        let disconnected = Arc::new(Mutex::new(None));
+        {
+            let disconnected = disconnected.clone();
+            self.client.set_disconnected_callback(move |_, _, code| {
+                *disconnected.lock().unwrap() = v5::reason(code as i32);
+            });
+        }
+        let mut protocol = self.settings.protocol;
+        let mut backoff = Backoff::new(self.settings.reconnect.clone());
+        let uris = self.settings.uris().join(", ");
+        let mut state = ConnectionState::Disconnected;
+        let mut attempts = 0;
+        loop {
+            self.transition(
+                &mut state,
+                ConnectionState::Connecting,
+                None,
+                attempts,
+                None,
+                None,
+            );
+            let options = super::connect_options(
+                &self.settings,
+                &self.credentials,
+                self.will.as_ref(),
+                protocol,
+            )?;
+            let (server, session) = match super::connect(&self.client, options).await {
+                Ok(response) => {
+                    let connected = response.connect_response();
+                    let v5 = connected
+                        .as_ref()
+                        .is_some_and(|r| r.mqtt_version >= mqtt::MQTT_VERSION_5);
+                    let topic_alias_maximum = response
+                        .properties()
+                        .get_int(mqtt::PropertyCode::TopicAliasMaximum)
+                        .unwrap_or(0);
+                    (
+                        connected.map_or_else(|| self.settings.uri.clone(), |r| r.server_uri),
+                        Session {
+                            protocol: if v5 { "5" } else { "3.1.1" },
+                            topic_alias_maximum: u16::try_from(topic_alias_maximum).unwrap_or(0),
+                        },
+                    )
+                }
+                Err(e) => {
+                    attempts += 1;
+                    self.metrics
+                        .mqtt_connect_failures
+                        .fetch_add(1, Ordering::Relaxed);
+                    let wait = backoff.failed();
+                    let e = v5::describe(&e);
+                    warn!("cannot connect to MQTT broker {uris}, retrying in {wait:?}: {e}");
+                    self.status.set(
+                        "mqtt",
+                        State::Degraded,
+                        Some(format!("cannot connect to {uris}, retrying in {wait:?}")),
+                    );
+                    time::sleep(wait).await;
+                    continue;
+                }
+            };
+
+            info!(
+                "connected to MQTT broker {server} with MQTT {} as {}",
+                session.protocol,
+                self.client.client_id()
+            );
+            if protocol == Protocol::Auto && session.protocol != "5" {
+                info!("MQTT broker {server} does not support v5, staying with 3.1.1");
+                protocol = Protocol::V3;
+            }
+            if self.reconnect {
+                self.metrics.mqtt_reconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            self.reconnect = true;
+            if server != self.settings.uri {
+                warn!(
+                    "primary MQTT broker {} unreachable, failed over to {server}",
+                    self.settings.uri
+                );
+                self.metrics.mqtt_failovers.fetch_add(1, Ordering::Relaxed);
+            }
+            self.metrics.mqtt_connected.store(1, Ordering::Relaxed);
+            self.status.set(
+                "mqtt",
+                State::Running,
+                Some(format!("connected to {server}")),
+            );
+            self.transition(
+                &mut state,
+                ConnectionState::Connected,
+                Some(server.clone()),
+                attempts,
+                None,
+                Some(session),
+            );
+            attempts = 0;
+            backoff.connected();
+            // A notification may be left over from an earlier connection.
+            while self.client.is_connected() {
+                lost.notified().await;
+            }
+
+            self.metrics.mqtt_connected.store(0, Ordering::Relaxed);
+            let reason = match disconnected.lock().unwrap().take() {
+                Some(reason) => format!("disconnected by the broker: {reason}"),
+                None => "connection lost".to_string(),
+            };
+            warn!("lost connection to MQTT broker {server}: {reason}");
+            self.status.set(
+                "mqtt",
+                State::Degraded,
+                Some(format!("lost connection to {server}: {reason}")),
+            );
+            self.transition(
+                &mut state,
+                ConnectionState::Disconnected,
+                Some(server),
+                0,
+                Some(reason),
+                None,
+            );
+            if let Some(wait) = backoff.lost() {
+                attempts += 1;
+                time::sleep(wait).await;
+            }
+        }
+    }
+
+    fn transition(
+        &self,
+        current: &mut ConnectionState,
+        state: ConnectionState,
+        server: Option<String>,
+        attempts: u32,
+        reason: Option<String>,
+        session: Option<Session>,
+    ) {
+        if *current == state {
+            return;
+        }
+        let previous = std::mem::replace(current, state);
+        self.metrics.record_mqtt_transition(state.as_str());
+        info!(
+            state = state.as_str();
+            "MQTT connection {} -> {}",
+            previous.as_str(),
+            state.as_str()
+        );
+        // Degraded rather than starting even before the first connection:
+        // messages are queued meanwhile, and readiness must not wait for the
+        // network.
+        if state == ConnectionState::Connecting {
+            self.status.set(
+                "mqtt",
+                State::Degraded,
+                Some(format!("connecting to {}", self.settings.uris().join(", "))),
+            );
+        }
+        // The receiver is gone once the client is being replaced.
+        let _ = self.transitions.send(Transition {
+            state,
+            previous,
+            server,
+            attempts,
+            reason,
+            session,
+            timestamp: Utc::now(),
+        });
+    }
+}
+
+// Delays between connection attempts, see `ReconnectConfig`.
+struct Backoff {
+    settings: ReconnectConfig,
+    delay: Duration,
+    connected: Option<Instant>,
+}
+
+impl Backoff {
+    fn new(settings: ReconnectConfig) -> Self {
+        Backoff {
+            delay: settings.min_delay(),
+            settings,
+            connected: None,
+        }
+    }
+
+    // How long to wait after a failed attempt.
+    fn failed(&mut self) -> Duration {
+        let wait = jittered(self.delay, self.settings.jitter);
+        self.delay = (self.delay * 2).min(self.settings.max_delay());
+        wait
+    }
+
+    fn connected(&mut self) {
+        self.connected = Some(Instant::now());
+    }
+
+    // How long to wait after the connection was lost, if at all. Only a
+    // connection that lasted starts over with the shortest delay.
+    fn lost(&mut self) -> Option<Duration> {
+        match self.connected.take() {
+            Some(since) if since.elapsed() >= STABLE => {
+                self.delay = self.settings.min_delay();
+                None
+            }
+            _ => Some(self.failed()),
+        }
+    }
+}
+
+// Between `delay * (1 - jitter)` and `delay`, so that units cut off at the
+// same time do not all come back at the same time.
+fn jittered(delay: Duration, jitter: f64) -> Duration {
+    let mut bytes = [0; 4];
+    let random = match openssl::rand::rand_bytes(&mut bytes) {
+        Ok(()) => f64::from(u32::from_ne_bytes(bytes)) / f64::from(u32::MAX),
+        Err(_) => 0.5,
+    };
+    delay.mul_f64(1.0 - jitter * random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_jitter(jitter: f64) -> Backoff {
+        Backoff::new(ReconnectConfig {
+            min_delay_ms: 1000,
+            max_delay_ms: 5000,
+            jitter,
+        })
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let delay = Duration::from_secs(10);
+        assert_eq!(jittered(delay, 0.0), delay);
+        for jitter in [0.25, 0.5, 1.0] {
+            for _ in 0..1000 {
+                let wait = jittered(delay, jitter);
+                assert!(wait <= delay, "{wait:?}");
+                assert!(wait >= delay.mul_f64(1.0 - jitter), "{wait:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let mut backoff = with_jitter(0.0);
+        let waits: Vec<_> = (0..5).map(|_| backoff.failed().as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 5, 5]);
+
+        let mut backoff = with_jitter(0.5);
+        for cap in [1000, 2000, 4000, 5000, 5000] {
+            let wait = backoff.failed();
+            assert!(wait <= Duration::from_millis(cap), "{wait:?}");
+            assert!(wait >= Duration::from_millis(cap / 2), "{wait:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stable_connection_starts_over() {
+        let mut backoff = with_jitter(0.0);
+        backoff.failed();
+        backoff.failed();
+
+        // Dropped right after it was accepted: still backing off.
+        backoff.connected();
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(backoff.lost(), Some(Duration::from_secs(4)));
+
+        backoff.connected();
+        time::advance(STABLE).await;
+        assert_eq!(backoff.lost(), None);
+        assert_eq!(backoff.failed(), Duration::from_secs(1));
+    }
+}
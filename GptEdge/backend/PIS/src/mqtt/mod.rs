@@ -0,0 +1,996 @@
+pub mod command;
+pub mod connection;
+pub mod outbox;
+pub mod tls;
+pub mod v5;
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use log::{error, info, warn};
+use paho_mqtt as mqtt;
+use serde_derive::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::time;
+use uuid::Uuid;
+
+use crate::buildinfo::{self, BuildInfo};
+use crate::codec::Codec;
+use crate::config::{Config, ConfigWatch, MqttConfig, PresenceConfig, Protocol};
+use crate::error::Error;
+use crate::host;
+use crate::identity::{Credentials, Identity, IdentityStore, IdentityWatch};
+use crate::metrics::Metrics;
+use crate::status::Status;
+use crate::supervisor::{AbortOnDrop, Shutdown};
+use crate::ticker::Tick;
+use command::Commands;
+use connection::{Connection, ConnectionState};
+use outbox::{Outbox, QueuedMessage};
+use v5::Publisher;
+
+const OUTBOX_DIR: &str = "mqtt-outbox";
+
+// Retained on the birth topic after every connect, so that the fleet backend
+// sees what each unit runs and where to reach it without asking.
+#[derive(Serialize)]
+struct Birth<'a> {
+    status: &'static str,
+    client_id: &'a str,
+    timestamp: DateTime<Utc>,
+    identity: &'a Identity,
+    addresses: BTreeMap<String, Vec<IpAddr>>,
+    boot_time: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    info: BuildInfo,
+}
+
+// The will, and the message before a clean disconnect. A will is set up
+// long before it is published, so it has no timestamp.
+#[derive(Serialize)]
+struct Offline<'a> {
+    status: &'static str,
+    client_id: &'a str,
+    device_id: &'a str,
+    graceful: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+// A message from another subsystem, such as update progress. The topic is a
+// template like the configured topics.
+#[derive(Debug, Clone)]
+pub struct Publication {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+// What flows over the connection besides commands: a heartbeat per tick,
+// the messages of other subsystems and the topics they subscribed to.
+pub struct Feed {
+    pub ticks: broadcast::Receiver<Tick>,
+    pub publications: broadcast::Receiver<Publication>,
+    pub subscriptions: Vec<Subscription>,
+}
+
+// Messages on `topic`, a template like the configured topics, go to
+// `messages` instead of the command handlers. The topic is subscribed on
+// every connect, so retained messages arrive again after a reconnect.
+#[derive(Clone)]
+pub struct Subscription {
+    pub topic: String,
+    pub messages: mpsc::Sender<Vec<u8>>,
+}
+
+#[derive(Serialize)]
+struct Heartbeat<'a> {
+    client_id: &'a str,
+    counter: u64,
+    timestamp: DateTime<Utc>,
+}
+
+// Publishes every tick as a JSON heartbeat, along with the messages other
+// subsystems hand over, answers commands sent to the device's command
+// topic and passes on what arrives on the other subscriptions. Connection
+// settings are read once; changing them requires a restart of the service.
+// A new device identity, e.g. after provisioning, reconnects with the new
+// topics and credentials. While the broker is unreachable messages go to
+// the outbox and are replayed in order later.
+pub async fn run(
+    config: ConfigWatch,
+    metrics: Arc<Metrics>,
+    commands: Arc<Commands>,
+    mut feed: Feed,
+    status: Arc<Status>,
+    mut identity: IdentityWatch,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let (settings, state_dir) = {
+        let config = config.borrow();
+        (config.mqtt.clone(), config.service.state_dir.clone())
+    };
+    let open_outbox = || match settings.queue.enabled {
+        true => Outbox::open(
+            &state_dir.join(OUTBOX_DIR),
+            settings.queue.clone(),
+            metrics.clone(),
+        )
+        .map(Some),
+        false => Ok(None),
+    };
+    let mut outbox = open_outbox()?;
+    let mut reconnect = false;
+
+    loop {
+        let device = identity.borrow_and_update().clone();
+        let client_id = client_id(&settings, &device);
+        let mut client = create_client(&settings, &client_id)?;
+        let mut incoming = client.get_stream(32);
+        let will = presence(&settings, &settings.will, &device, &client_id, false)?;
+        let (transition_tx, mut transitions) = mpsc::unbounded_channel();
+        let epoch = Arc::new(AtomicU64::new(0));
+        // Only the sender's publisher sets up topic aliases; this one decodes
+        // what arrives and makes the properties of command replies.
+        let publisher = Publisher::new(&settings, &device, &client_id, epoch.clone());
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let mut sender = AbortOnDrop(tokio::spawn(
+            Sender {
+                client: client.clone(),
+                publisher: Publisher::new(&settings, &device, &client_id, epoch.clone()),
+                outbox: outbox.take(),
+                metrics: metrics.clone(),
+                qos: settings.qos,
+            }
+            .run(outgoing_rx),
+        ));
+        // Ticks keep being queued while the first connection is still pending.
+        // The guard stops the connection attempts on every way out of this
+        // iteration, so that a restarted subsystem does not compete with a
+        // leftover client for the same client id.
+        let mut connection = AbortOnDrop(tokio::spawn(
+            Connection {
+                client: client.clone(),
+                settings: settings.clone(),
+                credentials: device.credentials.clone(),
+                will,
+                metrics: metrics.clone(),
+                status: status.clone(),
+                transitions: transition_tx,
+                reconnect,
+                epoch,
+            }
+            .maintain(),
+        ));
+        let heartbeat_topic = device.render(&settings.heartbeat_topic, &client_id);
+        let command_topic = device.render(&settings.command_topic, &client_id);
+        let response_topic = device.render(&settings.response_topic, &client_id);
+        let events_topic = settings
+            .events_topic
+            .as_ref()
+            .map(|topic| device.render(topic, &client_id));
+        let shared_command_topic = settings.shared_command_topic.as_ref().map(|topic| {
+            format!(
+                "$share/{}/{}",
+                device.render(&settings.share_group, &client_id),
+                device.render(topic, &client_id)
+            )
+        });
+        let subscribed: Vec<_> = feed
+            .subscriptions
+            .iter()
+            .map(|subscription| {
+                (
+                    device.render(&subscription.topic, &client_id),
+                    subscription.messages.clone(),
+                )
+            })
+            .collect();
+
+        let changed = loop {
+            let (message, what) = tokio::select! {
+                tick = feed.ticks.recv() => match tick {
+                    Ok(tick) => (
+                        QueuedMessage {
+                            topic: heartbeat_topic.clone(),
+                            qos: settings.qos,
+                            retain: false,
+                            timestamp: tick.timestamp,
+                            payload: serde_json::to_vec(&Heartbeat {
+                                client_id: &client_id,
+                                counter: tick.counter,
+                                timestamp: tick.timestamp,
+                            })?,
+                        },
+                        format!("heartbeat {}", tick.counter),
+                    ),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("mqtt skipped {skipped} heartbeats");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break false,
+                },
+                publication = feed.publications.recv() => match publication {
+                    Ok(publication) => {
+                        let topic = device.render(&publication.topic, &client_id);
+                        (
+                            QueuedMessage {
+                                topic: topic.clone(),
+                                qos: settings.qos,
+                                retain: publication.retain,
+                                timestamp: Utc::now(),
+                                payload: publication.payload,
+                            },
+                            format!("message to {topic}"),
+                        )
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("mqtt skipped {skipped} messages");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break false,
+                },
+                Some(transition) = transitions.recv() => {
+                    if transition.state == ConnectionState::Connected {
+                        let birth = birth(&settings, &device, &client_id)?;
+                        let topics = std::iter::once(&command_topic)
+                            .chain(&shared_command_topic)
+                            .chain(subscribed.iter().map(|(topic, _)| topic))
+                            .cloned()
+                            .collect();
+                        let _ = outgoing.send(Outgoing::Connected {
+                            topic_alias_maximum: transition
+                                .session
+                                .map_or(0, |session| session.topic_alias_maximum),
+                            birth,
+                            topics,
+                        });
+                    }
+                    let Some(topic) = &events_topic else {
+                        continue;
+                    };
+                    (
+                        QueuedMessage {
+                            topic: topic.clone(),
+                            qos: settings.qos,
+                            retain: false,
+                            timestamp: transition.timestamp,
+                            payload: serde_json::to_vec(&transition)?,
+                        },
+                        format!("{} event", transition.state.as_str()),
+                    )
+                }
+                // None marks a lost connection, which the client handles itself.
+                Some(Some(message)) = incoming.next() => {
+                    if let Some((topic, messages)) =
+                        subscribed.iter().find(|(topic, _)| topic == message.topic())
+                    {
+                        let payload = publisher
+                            .received_codec(&message)
+                            .and_then(|codec| codec.decode_json(message.payload()));
+                        match payload {
+                            Ok(payload) => {
+                                if messages.try_send(payload).is_err() {
+                                    warn!("dropped message on {topic}, nobody is keeping up");
+                                }
+                            }
+                            Err(e) => warn!("dropped message on {topic}: {e:#}"),
+                        }
+                        continue;
+                    }
+                    let codec = publisher.received_codec(&message).unwrap_or_else(|e| {
+                        warn!("command on {}: {e:#}, assuming JSON", message.topic());
+                        Codec::JSON
+                    });
+                    let properties = publisher.reply_properties(&message, codec)?;
+                    let (client, commands) = (client.clone(), commands.clone());
+                    let (response_topic, qos) = (response_topic.clone(), settings.qos);
+                    tokio::spawn(async move {
+                        if let Err(e) = commands.dispatch(&client, message, &response_topic, qos, properties, codec).await {
+                            error!("{e:#}");
+                        }
+                    });
+                    continue;
+                }
+                // The connection task only ends when it failed; the
+                // supervisor restarts the subsystem with a new one.
+                result = &mut connection.0 => return Err(match result {
+                    Ok(Err(e)) => e,
+                    Ok(Ok(())) => anyhow!("mqtt connection task ended"),
+                    Err(e) => anyhow!("mqtt connection task failed: {e}"),
+                }),
+                // Only ends early when the outbox cannot be written.
+                result = &mut sender.0 => return Err(match result {
+                    Ok(Err(e)) => e,
+                    Ok(Ok(_)) => anyhow!("mqtt sender ended"),
+                    Err(e) => anyhow!("mqtt sender failed: {e}"),
+                }),
+                Ok(()) = identity.changed() => break true,
+                _ = shutdown.wait() => break false,
+            };
+            let _ = outgoing.send(Outgoing::Message { message, what });
+        };
+
+        // Whatever the sender still has is published, or queued if the broker
+        // went away, unless the broker takes longer than a connect would.
+        drop(outgoing);
+        let drained = match time::timeout(settings.connect_timeout(), &mut sender.0).await {
+            Ok(Ok(result)) => Some(result?),
+            Ok(Err(e)) => return Err(anyhow!("mqtt sender failed: {e}")),
+            Err(_) => {
+                warn!("mqtt broker not accepting messages, dropped those not yet queued");
+                // Gone for good before the outbox is opened again.
+                sender.0.abort();
+                let _ = (&mut sender.0).await;
+                None
+            }
+        };
+        drop(connection);
+        if let Some(mut sender) = drained {
+            if client.is_connected() {
+                if let Some(offline) =
+                    presence(&settings, &settings.offline, &device, &client_id, true)?
+                {
+                    sender.offline(&offline, settings.connect_timeout()).await;
+                }
+            }
+            outbox = sender.outbox;
+        }
+        if client.is_connected() {
+            if let Err(e) = client.disconnect(None).await {
+                warn!("mqtt disconnect failed: {e}");
+            }
+        }
+        if !changed {
+            return Ok(());
+        }
+        // Messages already queued keep the topics they were created with.
+        info!("device identity changed, reconnecting to MQTT broker");
+        reconnect = true;
+        if outbox.is_none() {
+            outbox = open_outbox()?;
+        }
+    }
+}
+
+// What the loop in `run` hands to the sender.
+enum Outgoing {
+    // After every connect: the birth message first, then the subscriptions
+    // and whatever the outbox holds.
+    Connected {
+        topic_alias_maximum: u16,
+        birth: QueuedMessage,
+        topics: Vec<String>,
+    },
+    Message {
+        message: QueuedMessage,
+        what: String,
+    },
+}
+
+// Publishes on a task of its own, so that a broker that is slow to
+// acknowledge holds up neither ticks and commands nor shutdown and identity
+// changes. Messages are sent in the order they were handed over.
+struct Sender {
+    client: mqtt::AsyncClient,
+    publisher: Publisher,
+    outbox: Option<Outbox>,
+    metrics: Arc<Metrics>,
+    qos: i32,
+}
+
+impl Sender {
+    // Returns once `outgoing` is closed and everything in it was handled.
+    async fn run(mut self, mut outgoing: mpsc::UnboundedReceiver<Outgoing>) -> Result<Self> {
+        while let Some(next) = outgoing.recv().await {
+            match next {
+                Outgoing::Connected {
+                    topic_alias_maximum,
+                    birth,
+                    topics,
+                } => {
+                    self.publisher.connected(topic_alias_maximum);
+                    if let Err(e) = self.publish(&birth).await {
+                        warn!(
+                            "cannot publish birth message to {}: {}",
+                            birth.topic,
+                            v5::describe(&e)
+                        );
+                    }
+                    subscribe(&self.client, &topics, self.qos).await;
+                    if let Some(outbox) = &mut self.outbox {
+                        flush(&self.client, &mut self.publisher, outbox, &self.metrics).await?;
+                    }
+                }
+                Outgoing::Message { message, what } => self.send(&message, &what).await?,
+            }
+        }
+        Ok(self)
+    }
+
+    async fn send(&mut self, message: &QueuedMessage, what: &str) -> Result<()> {
+        match &mut self.outbox {
+            // Anything already waiting has to go first to keep the order.
+            Some(outbox) if !outbox.is_empty() || !self.client.is_connected() => {
+                outbox.push(message)?;
+                flush(&self.client, &mut self.publisher, outbox, &self.metrics).await?;
+            }
+            Some(_) => {
+                if let Err(e) = self.publish(message).await {
+                    warn!("{what} queued: {}", v5::describe(&e));
+                    if let Some(outbox) = &mut self.outbox {
+                        outbox.push(message)?;
+                    }
+                }
+            }
+            None => {
+                if let Err(e) = self.publish(message).await {
+                    warn!("{what} not published: {}", v5::describe(&e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The message before a clean disconnect, given up on after `timeout`.
+    async fn offline(&mut self, message: &QueuedMessage, timeout: Duration) {
+        let error = match time::timeout(timeout, self.publish(message)).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => v5::describe(&e),
+            Err(_) => format!("no reply within {timeout:?}"),
+        };
+        warn!(
+            "cannot publish offline message to {}: {error}",
+            message.topic
+        );
+    }
+
+    async fn publish(&mut self, message: &QueuedMessage) -> mqtt::Result<()> {
+        publish(&self.client, &mut self.publisher, message, &self.metrics).await
+    }
+}
+
+// Connects once, without retrying, for the command-line tools. The client
+// id gets a random suffix so that a running service keeps its session.
+async fn connect_once(
+    config: &Config,
+) -> Result<(mqtt::AsyncClient, String, Arc<Identity>), Error> {
+    let settings = &config.mqtt;
+    let device = IdentityStore::open(&config.service.state_dir)?.current();
+    let client_id = client_id(settings, &device);
+    let client = create_client(
+        settings,
+        &format!(
+            "{client_id}-cli-{}",
+            &Uuid::new_v4().simple().to_string()[..8]
+        ),
+    )?;
+    let attempts = connect_options(settings, &device.credentials, None, settings.protocol)?;
+    connect(&client, attempts).await.map_err(|e| {
+        Error::BrokerUnreachable(anyhow!(
+            "cannot connect to {}: {}",
+            settings.uri,
+            v5::describe(&e)
+        ))
+    })?;
+    Ok((client, client_id, device))
+}
+
+// Publishes a single message and waits for the broker to accept it. Returns
+// the topic after substitution.
+pub async fn publish_once(
+    config: &Config,
+    topic_template: &str,
+    payload: Vec<u8>,
+    qos: i32,
+    retain: bool,
+) -> Result<String, Error> {
+    let (client, client_id, device) = connect_once(config).await?;
+    let topic = device.render(topic_template, &client_id);
+    let message = match retain {
+        true => mqtt::Message::new_retained(&topic, payload, qos),
+        false => mqtt::Message::new(&topic, payload, qos),
+    };
+    let published = client.publish(message).await;
+    let _ = client.disconnect(None).await;
+    published.map_err(|e| anyhow!("cannot publish to {topic}: {}", v5::describe(&e)))?;
+    Ok(topic)
+}
+
+// Checks that the broker accepts a connection with the configured settings.
+pub async fn probe(config: &Config) -> Result<(), Error> {
+    let (client, _, _) = connect_once(config).await?;
+    let _ = client.disconnect(None).await;
+    Ok(())
+}
+
+// A message that expired while it waited is dropped, which counts as sent.
+async fn publish(
+    client: &mqtt::AsyncClient,
+    publisher: &mut Publisher,
+    message: &QueuedMessage,
+    metrics: &Metrics,
+) -> mqtt::Result<()> {
+    let Some(mqtt_message) = publisher.message(client.mqtt_version(), message)? else {
+        info!("dropped expired message to {}", message.topic);
+        metrics.mqtt_expired.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    };
+    let result = client.publish(mqtt_message).await;
+    match &result {
+        Ok(()) => metrics.mqtt_publishes.fetch_add(1, Ordering::Relaxed),
+        Err(_) => {
+            publisher.failed(&message.topic);
+            metrics
+                .mqtt_publish_failures
+                .fetch_add(1, Ordering::Relaxed)
+        }
+    };
+    result
+}
+
+// All topics go into one request. Paho only reports the reason code of a
+// failed subscription when there are several.
+async fn subscribe(client: &mqtt::AsyncClient, topics: &[String], qos: i32) {
+    match client.subscribe_many_same_qos(topics, qos).await {
+        Ok(response) => {
+            let codes = response.subscribe_many_response().unwrap_or_default();
+            for (topic, code) in topics.iter().zip(codes) {
+                if code >= 0x80 {
+                    let reason = v5::reason(code).unwrap_or_else(|| format!("code {code}"));
+                    error!(topic = topic.as_str(); "cannot subscribe to {topic}: {reason}");
+                }
+            }
+        }
+        Err(e) => {
+            for topic in topics {
+                error!(topic = topic.as_str(); "cannot subscribe to {topic}: {}", v5::describe(&e));
+            }
+        }
+    }
+}
+
+// Sends queued messages oldest first until the outbox is empty or the broker
+// stops accepting them.
+async fn flush(
+    client: &mqtt::AsyncClient,
+    publisher: &mut Publisher,
+    outbox: &mut Outbox,
+    metrics: &Metrics,
+) -> Result<()> {
+    let mut sent = 0;
+    while client.is_connected() {
+        let Some(message) = outbox.front()? else {
+            break;
+        };
+        if let Err(e) = publish(client, publisher, &message, metrics).await {
+            warn!(
+                "replay stopped, {} messages still queued: {}",
+                outbox.len(),
+                v5::describe(&e)
+            );
+            break;
+        }
+        outbox.pop()?;
+        sent += 1;
+    }
+    if sent > 0 {
+        info!("replayed {sent} queued messages");
+    }
+    Ok(())
+}
+
+// A client that can connect with MQTT v5 unless only 3.1.1 is configured.
+fn create_client(settings: &MqttConfig, client_id: &str) -> Result<mqtt::AsyncClient> {
+    let version = match settings.protocol {
+        Protocol::V3 => mqtt::MQTT_VERSION_DEFAULT,
+        Protocol::V5 | Protocol::Auto => mqtt::MQTT_VERSION_5,
+    };
+    mqtt::CreateOptionsBuilder::new()
+        .server_uri(&settings.uri)
+        .client_id(client_id)
+        .mqtt_version(version)
+        .persistence(mqtt::PersistenceType::None)
+        .create_client()
+        .context("cannot create MQTT client")
+}
+
+// Tries each of the options from `connect_options` in turn.
+async fn connect(
+    client: &mqtt::AsyncClient,
+    attempts: Vec<mqtt::ConnectOptions>,
+) -> mqtt::Result<mqtt::ServerResponse> {
+    let mut attempts = attempts.into_iter().peekable();
+    while let Some(options) = attempts.next() {
+        match client.connect(options).await {
+            Err(e) if attempts.peek().is_some() => {
+                info!(
+                    "MQTT v5 connection refused, trying 3.1.1: {}",
+                    v5::describe(&e)
+                );
+            }
+            result => return result,
+        }
+    }
+    Err(mqtt::Error::General("no connect options"))
+}
+
+// One set of options per protocol version to try: with "auto" a broker that
+// refuses MQTT v5 is asked again with 3.1.1. Credentials from provisioning
+// take precedence over configured ones.
+fn connect_options(
+    settings: &MqttConfig,
+    credentials: &Credentials,
+    will: Option<&QueuedMessage>,
+    protocol: Protocol,
+) -> Result<Vec<mqtt::ConnectOptions>> {
+    let versions: &[u32] = match protocol {
+        Protocol::V3 => &[mqtt::MQTT_VERSION_DEFAULT],
+        Protocol::V5 => &[mqtt::MQTT_VERSION_5],
+        Protocol::Auto => &[mqtt::MQTT_VERSION_5, mqtt::MQTT_VERSION_DEFAULT],
+    };
+    let mut attempts = Vec::new();
+    for &version in versions {
+        let mut options = match version {
+            mqtt::MQTT_VERSION_5 => {
+                let mut options = mqtt::ConnectOptionsBuilder::new_v5();
+                options.clean_start(settings.clean_session);
+                if !settings.clean_session {
+                    let mut properties = mqtt::Properties::new();
+                    properties.push_u32(
+                        mqtt::PropertyCode::SessionExpiryInterval,
+                        settings.v5.session_expiry_secs.unwrap_or(u32::MAX),
+                    )?;
+                    options.properties(properties);
+                }
+                options
+            }
+            _ => {
+                let mut options = mqtt::ConnectOptionsBuilder::new();
+                options.clean_session(settings.clean_session);
+                options
+            }
+        };
+        options
+            .keep_alive_interval(settings.keep_alive())
+            .connect_timeout(settings.connect_timeout())
+            .server_uris(&settings.uris());
+        if let Some(will) = will {
+            options.will_message(to_mqtt(will));
+        }
+        let (username, password) = login(settings, credentials);
+        if let Some(username) = username {
+            options.user_name(username);
+        }
+        if let Some(password) = password {
+            options.password(password);
+        }
+        match &settings.tls {
+            Some(tls_config) => {
+                options.ssl_options(tls::ssl_options(tls_config)?);
+            }
+            None if settings.uses_tls() => {
+                options.ssl_options(mqtt::SslOptions::new());
+            }
+            None => {}
+        }
+        attempts.push(options.finalize());
+    }
+    Ok(attempts)
+}
+
+// The user name and password to connect with. A user name from provisioning
+// comes with its own password, or none.
+fn login<'a>(
+    settings: &'a MqttConfig,
+    credentials: &'a Credentials,
+) -> (Option<&'a String>, Option<&'a String>) {
+    match &credentials.username {
+        Some(username) => (Some(username), credentials.password.as_ref()),
+        None => (settings.username.as_ref(), settings.password.as_ref()),
+    }
+}
+
+fn to_mqtt(message: &QueuedMessage) -> mqtt::Message {
+    match message.retain {
+        true => mqtt::Message::new_retained(&message.topic, &*message.payload, message.qos),
+        false => mqtt::Message::new(&message.topic, &*message.payload, message.qos),
+    }
+}
+
+// Retained on the birth topic after every connect.
+fn birth(settings: &MqttConfig, device: &Identity, client_id: &str) -> Result<QueuedMessage> {
+    let timestamp = Utc::now();
+    Ok(QueuedMessage {
+        topic: device.render(&settings.birth_topic, client_id),
+        qos: settings.qos,
+        retain: true,
+        timestamp,
+        payload: match &settings.birth_payload {
+            Some(template) => render(template, device, client_id, timestamp),
+            None => serde_json::to_vec(&Birth {
+                status: "online",
+                client_id,
+                timestamp,
+                identity: device,
+                addresses: host::addresses(),
+                boot_time: host::boot_time(),
+                info: buildinfo::info(),
+            })?,
+        },
+    })
+}
+
+// The will or the offline message, unless it is disabled.
+fn presence(
+    settings: &MqttConfig,
+    presence: &PresenceConfig,
+    device: &Identity,
+    client_id: &str,
+    graceful: bool,
+) -> Result<Option<QueuedMessage>> {
+    if !presence.enabled {
+        return Ok(None);
+    }
+    let timestamp = Utc::now();
+    let topic = presence.topic.as_ref().unwrap_or(&settings.birth_topic);
+    Ok(Some(QueuedMessage {
+        topic: device.render(topic, client_id),
+        qos: settings.qos,
+        retain: presence.retain,
+        timestamp,
+        payload: match &presence.payload {
+            Some(template) => render(template, device, client_id, timestamp),
+            None => serde_json::to_vec(&Offline {
+                status: "offline",
+                client_id,
+                device_id: &device.device_id,
+                graceful,
+                timestamp: graceful.then_some(timestamp),
+            })?,
+        },
+    }))
+}
+
+// Fills in a payload template. Unknown placeholders are left alone, since
+// payloads such as JSON documents have braces of their own.
+fn render(template: &str, device: &Identity, client_id: &str, timestamp: DateTime<Utc>) -> Vec<u8> {
+    let boot_time = host::boot_time().map(|time| time.to_rfc3339());
+    device
+        .render(template, client_id)
+        .replace("{timestamp}", &timestamp.to_rfc3339())
+        .replace("{version}", env!("CARGO_PKG_VERSION"))
+        .replace("{boot_time}", boot_time.as_deref().unwrap_or_default())
+        .into_bytes()
+}
+
+// The configured client id, or else the device id.
+fn client_id(settings: &MqttConfig, device: &Identity) -> String {
+    settings
+        .client_id
+        .clone()
+        .unwrap_or_else(|| device.device_id.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn device() -> Identity {
+        Identity {
+            device_id: "unit-7".to_string(),
+            machine_id: None,
+            serial_number: None,
+            macs: BTreeMap::new(),
+            fleet_id: None,
+            vehicle_id: None,
+            provisioned_at: None,
+            credentials: Credentials::default(),
+        }
+    }
+
+    #[test]
+    fn auto_tries_v5_before_3_1_1() {
+        let settings = MqttConfig {
+            clean_session: false,
+            ..Default::default()
+        };
+        let credentials = Credentials::default();
+        let versions = |protocol| {
+            connect_options(&settings, &credentials, None, protocol)
+                .unwrap()
+                .iter()
+                .map(|options| options.mqtt_version())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(versions(Protocol::V3), [mqtt::MQTT_VERSION_DEFAULT]);
+        assert_eq!(versions(Protocol::V5), [mqtt::MQTT_VERSION_5]);
+        assert_eq!(
+            versions(Protocol::Auto),
+            [mqtt::MQTT_VERSION_5, mqtt::MQTT_VERSION_DEFAULT]
+        );
+
+        let attempts = connect_options(&settings, &credentials, None, Protocol::Auto).unwrap();
+        assert!(!attempts[0].clean_start());
+        assert!(!attempts[1].clean_session());
+    }
+
+    #[test]
+    fn provisioned_credentials_take_precedence() {
+        let settings = MqttConfig {
+            username: Some("fleet".to_string()),
+            password: Some("fleet-secret".to_string()),
+            ..Default::default()
+        };
+        let mut credentials = Credentials::default();
+        assert_eq!(
+            login(&settings, &credentials),
+            (
+                Some(&"fleet".to_string()),
+                Some(&"fleet-secret".to_string())
+            )
+        );
+
+        credentials.username = Some("unit-7".to_string());
+        credentials.password = Some("unit-secret".to_string());
+        assert_eq!(
+            login(&settings, &credentials),
+            (
+                Some(&"unit-7".to_string()),
+                Some(&"unit-secret".to_string())
+            )
+        );
+
+        // Never the configured password with the provisioned user name.
+        credentials.password = None;
+        assert_eq!(
+            login(&settings, &credentials),
+            (Some(&"unit-7".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn the_will_goes_into_every_attempt() {
+        let settings = MqttConfig::default();
+        let device = device();
+        let will = presence(&settings, &settings.will, &device, "unit-7", false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(will.topic, "pis/unit-7/birth");
+        assert!(will.retain);
+        let attempts =
+            connect_options(&settings, &device.credentials, Some(&will), Protocol::Auto).unwrap();
+        for options in &attempts {
+            let options = format!("{options:?}");
+            assert!(options.contains("pis/unit-7/birth"), "{options}");
+        }
+
+        let attempts =
+            connect_options(&settings, &device.credentials, None, Protocol::Auto).unwrap();
+        assert!(!format!("{:?}", attempts[0]).contains("pis/unit-7/birth"));
+    }
+
+    #[test]
+    fn birth_reports_identity_and_build() {
+        let settings = MqttConfig::default();
+        let birth = birth(&settings, &device(), "unit-7").unwrap();
+        assert_eq!(birth.topic, "pis/unit-7/birth");
+        assert!(birth.retain);
+        let payload: serde_json::Value = serde_json::from_slice(&birth.payload).unwrap();
+        assert_eq!(payload["status"], "online");
+        assert_eq!(payload["client_id"], "unit-7");
+        assert_eq!(payload["identity"]["device_id"], "unit-7");
+        assert_eq!(payload["timestamp"], serde_json::json!(birth.timestamp));
+        assert!(payload["addresses"].is_object());
+        assert!(payload["build"]["version"].is_string(), "{payload}");
+    }
+
+    #[test]
+    fn payload_templates_fill_in_placeholders() {
+        let settings = MqttConfig {
+            birth_payload: Some(
+                "{device_id} of {fleet_id} up at {timestamp}, {version} {\"raw\": {x}}".to_string(),
+            ),
+            ..Default::default()
+        };
+        let birth = birth(&settings, &device(), "unit-7").unwrap();
+        assert_eq!(
+            String::from_utf8(birth.payload).unwrap(),
+            format!(
+                "unit-7 of unknown up at {}, {} {{\"raw\": {{x}}}}",
+                birth.timestamp.to_rfc3339(),
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn only_the_offline_message_is_graceful() {
+        let settings = MqttConfig::default();
+        let device = device();
+        let will = presence(&settings, &settings.will, &device, "unit-7", false)
+            .unwrap()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&will.payload).unwrap();
+        assert_eq!(
+            payload,
+            serde_json::json!({
+                "status": "offline",
+                "client_id": "unit-7",
+                "device_id": "unit-7",
+                "graceful": false,
+            })
+        );
+
+        let offline = presence(&settings, &settings.offline, &device, "unit-7", true)
+            .unwrap()
+            .unwrap();
+        let payload: serde_json::Value = serde_json::from_slice(&offline.payload).unwrap();
+        assert_eq!(payload["graceful"], true);
+        assert_eq!(payload["timestamp"], serde_json::json!(offline.timestamp));
+    }
+
+    #[test]
+    fn presence_can_be_configured_or_disabled() {
+        let mut settings = MqttConfig {
+            offline: PresenceConfig {
+                topic: Some("fleet/{fleet_id}/{device_id}/presence".to_string()),
+                payload: Some("{client_id} gone".to_string()),
+                retain: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let offline = presence(&settings, &settings.offline, &device(), "unit-7", true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(offline.topic, "fleet/unknown/unit-7/presence");
+        assert_eq!(offline.payload, b"unit-7 gone");
+        assert!(!offline.retain);
+
+        settings.will.enabled = false;
+        assert!(
+            presence(&settings, &settings.will, &device(), "unit-7", false)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    // Needs a broker without authentication on localhost:1883, e.g.
+    // `mosquitto -p 1883`, then `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    async fn publishes_through_a_local_broker() {
+        let dir = std::env::temp_dir().join(format!("pis-mqtt-{}", uuid::Uuid::new_v4()));
+        let mut config = Config::default();
+        config.service.state_dir = dir.clone();
+        config.mqtt.protocol = Protocol::Auto;
+        probe(&config).await.unwrap();
+
+        let (mut listener, _, _) = connect_once(&config).await.unwrap();
+        let mut messages = listener.get_stream(8);
+        let topic = format!("pis/test/{}", uuid::Uuid::new_v4());
+        listener.subscribe(&topic, 1).await.unwrap();
+        let published = publish_once(&config, &topic, b"hello".to_vec(), 1, false)
+            .await
+            .unwrap();
+        assert_eq!(published, topic);
+        let message = time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .flatten()
+            .unwrap();
+        assert_eq!(message.payload(), b"hello");
+        let _ = listener.disconnect(None).await;
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
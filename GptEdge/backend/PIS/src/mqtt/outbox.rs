@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::QueueConfig;
+use crate::metrics::Metrics;
+
+// A message waiting for the broker. Each one is stored in its own file named
+// after a monotonically increasing sequence number: a JSON header line
+// followed by the raw payload. Files are written to a temporary name, synced
+// and renamed, so a power cut leaves either the whole message or nothing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub topic: String,
+    pub qos: i32,
+    pub retain: bool,
+    pub timestamp: DateTime<Utc>,
+    #[serde(skip)]
+    pub payload: Vec<u8>,
+}
+
+pub struct Outbox {
+    dir: PathBuf,
+    limits: QueueConfig,
+    metrics: Arc<Metrics>,
+    // Sequence number and file size of every queued message, oldest first.
+    entries: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next_seq: u64,
+}
+
+impl Outbox {
+    pub fn open(dir: &Path, limits: QueueConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).with_context(|| format!("cannot read {}", dir.display()))? {
+            let entry = entry?;
+            let path = entry.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("msg") => {}
+                // Left over from a write that never completed.
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            entries.push((seq, entry.metadata()?.len()));
+        }
+        entries.sort_unstable();
+
+        let outbox = Outbox {
+            dir: dir.to_path_buf(),
+            limits,
+            metrics,
+            next_seq: entries.last().map_or(0, |(seq, _)| seq + 1),
+            bytes: entries.iter().map(|(_, size)| size).sum(),
+            entries: entries.into(),
+        };
+        outbox.update_metrics();
+        if !outbox.is_empty() {
+            info!("mqtt outbox holds {} messages", outbox.len());
+        }
+        Ok(outbox)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn push(&mut self, message: &QueuedMessage) -> Result<()> {
+        let seq = self.next_seq;
+        let path = self.path(seq);
+        let tmp = path.with_extension("tmp");
+        let mut data = serde_json::to_vec(message)?;
+        data.push(b'\n');
+        data.extend_from_slice(&message.payload);
+
+        let mut file =
+            File::create(&tmp).with_context(|| format!("cannot create {}", tmp.display()))?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path).with_context(|| format!("cannot write {}", path.display()))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.next_seq += 1;
+        self.entries.push_back((seq, data.len() as u64));
+        self.bytes += data.len() as u64;
+        self.enforce_limits();
+        self.update_metrics();
+        Ok(())
+    }
+
+    // Returns the oldest message still worth sending. Expired and unreadable
+    // messages are dropped on the way.
+    pub fn front(&mut self) -> Result<Option<QueuedMessage>> {
+        let max_age = Duration::seconds(self.limits.max_age_secs as i64);
+        while let Some(&(seq, _)) = self.entries.front() {
+            match self.read(seq) {
+                Ok(message) if Utc::now() - message.timestamp <= max_age => {
+                    return Ok(Some(message))
+                }
+                Ok(message) => {
+                    warn!(
+                        "dropping queued message from {}, too old",
+                        message.timestamp
+                    );
+                }
+                Err(e) => warn!("dropping unreadable queued message {seq}: {e:#}"),
+            }
+            self.drop_front();
+        }
+        Ok(None)
+    }
+
+    // Removes the oldest message once the broker has acknowledged it.
+    pub fn pop(&mut self) -> Result<()> {
+        if let Some(&(seq, size)) = self.entries.front() {
+            match fs::remove_file(self.path(seq)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e).context("cannot remove sent message");
+                }
+                _ => {}
+            }
+            self.entries.pop_front();
+            self.bytes -= size;
+            self.update_metrics();
+        }
+        Ok(())
+    }
+
+    fn read(&self, seq: u64) -> Result<QueuedMessage> {
+        let data = fs::read(self.path(seq))?;
+        let split = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .context("missing header")?;
+        let mut message: QueuedMessage = serde_json::from_slice(&data[..split])?;
+        message.payload = data[split + 1..].to_vec();
+        Ok(message)
+    }
+
+    fn enforce_limits(&mut self) {
+        while self.len() > self.limits.max_messages || self.bytes > self.limits.max_bytes {
+            self.drop_front();
+            warn!("mqtt outbox full, dropped oldest message");
+        }
+    }
+
+    fn drop_front(&mut self) {
+        if let Some((seq, size)) = self.entries.pop_front() {
+            let _ = fs::remove_file(self.path(seq));
+            self.bytes -= size;
+            self.metrics
+                .mqtt_queue_dropped
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        self.update_metrics();
+    }
+
+    fn update_metrics(&self) {
+        self.metrics
+            .mqtt_queue_depth
+            .store(self.len() as u64, Ordering::Relaxed);
+        self.metrics
+            .mqtt_queue_bytes
+            .store(self.bytes, Ordering::Relaxed);
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{seq:020}.msg"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("pis-outbox-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn limits(max_messages: usize, max_bytes: u64, max_age_secs: u64) -> QueueConfig {
+        QueueConfig {
+            enabled: true,
+            max_messages,
+            max_bytes,
+            max_age_secs,
+        }
+    }
+
+    fn message(payload: &str, timestamp: DateTime<Utc>) -> QueuedMessage {
+        QueuedMessage {
+            topic: "pis/test".to_string(),
+            qos: 1,
+            retain: false,
+            timestamp,
+            payload: payload.as_bytes().to_vec(),
+        }
+    }
+
+    // Takes every message still worth sending, oldest first.
+    fn drain(outbox: &mut Outbox) -> Vec<String> {
+        let mut payloads = Vec::new();
+        while let Some(message) = outbox.front().unwrap() {
+            payloads.push(String::from_utf8(message.payload).unwrap());
+            outbox.pop().unwrap();
+        }
+        payloads
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let dir = temp_dir();
+        let metrics = Arc::new(Metrics::default());
+        let mut outbox = Outbox::open(&dir, limits(10, 1 << 20, 3600), metrics.clone()).unwrap();
+        outbox
+            .push(&message("old", Utc::now() - Duration::hours(2)))
+            .unwrap();
+        outbox.push(&message("new", Utc::now())).unwrap();
+        assert_eq!(drain(&mut outbox), ["new"]);
+        assert_eq!(metrics.mqtt_queue_dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_messages_make_room() {
+        let dir = temp_dir();
+        let metrics = Arc::new(Metrics::default());
+        let mut outbox = Outbox::open(&dir, limits(2, 1 << 20, 3600), metrics.clone()).unwrap();
+        for payload in ["1", "2", "3"] {
+            outbox.push(&message(payload, Utc::now())).unwrap();
+        }
+        assert_eq!(drain(&mut outbox), ["2", "3"]);
+
+        // Room for two messages of this size, but not for three.
+        outbox.push(&message("4", Utc::now())).unwrap();
+        let size = outbox.bytes;
+        outbox.limits = limits(10, size * 2 + 1, 3600);
+        for payload in ["5", "6"] {
+            outbox.push(&message(payload, Utc::now())).unwrap();
+        }
+        assert_eq!(outbox.bytes, size * 2);
+        assert_eq!(drain(&mut outbox), ["5", "6"]);
+        assert_eq!(metrics.mqtt_queue_dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.mqtt_queue_bytes.load(Ordering::Relaxed), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn messages_are_replayed_in_order_after_reopening() {
+        let dir = temp_dir();
+        let metrics = Arc::new(Metrics::default());
+        let mut outbox = Outbox::open(&dir, limits(100, 1 << 20, 3600), metrics.clone()).unwrap();
+        // read_dir lists the files in no particular order.
+        let payloads: Vec<String> = (0..12).map(|i| i.to_string()).collect();
+        for payload in &payloads {
+            outbox.push(&message(payload, Utc::now())).unwrap();
+        }
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir, limits(100, 1 << 20, 3600), metrics).unwrap();
+        assert_eq!(outbox.len(), 12);
+        outbox.push(&message("12", Utc::now())).unwrap();
+        let mut expected = payloads;
+        expected.push("12".to_string());
+        assert_eq!(drain(&mut outbox), expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_cut_short_by_a_crash_are_dropped() {
+        let dir = temp_dir();
+        let metrics = Arc::new(Metrics::default());
+        let mut outbox = Outbox::open(&dir, limits(10, 1 << 20, 3600), metrics.clone()).unwrap();
+        for payload in ["1", "2", "3"] {
+            outbox.push(&message(payload, Utc::now())).unwrap();
+        }
+        // The last record lost the end of its header, and a later write
+        // never got renamed into place.
+        let last = outbox.path(2);
+        let data = fs::read(&last).unwrap();
+        fs::write(&last, &data[..data.len() / 2]).unwrap();
+        let tmp = outbox.path(3).with_extension("tmp");
+        fs::write(&tmp, b"{\"topic\":").unwrap();
+        drop(outbox);
+
+        let mut outbox = Outbox::open(&dir, limits(10, 1 << 20, 3600), metrics.clone()).unwrap();
+        assert!(!tmp.exists());
+        assert_eq!(drain(&mut outbox), ["1", "2"]);
+        assert_eq!(metrics.mqtt_queue_dropped.load(Ordering::Relaxed), 1);
+
+        // Sequence numbers carry on after the dropped record.
+        outbox.push(&message("4", Utc::now())).unwrap();
+        assert!(outbox.path(3).exists());
+        assert_eq!(drain(&mut outbox), ["4"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
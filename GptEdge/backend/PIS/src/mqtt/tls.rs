@@ -0,0 +1,253 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use openssl::pkey::{PKey, Private};
+use openssl::x509::X509;
+use paho_mqtt as mqtt;
+
+use crate::config::TlsConfig;
+
+// Parses every configured file so a missing or broken certificate is reported
+// at startup rather than as an opaque handshake failure on each reconnect.
+pub fn check(tls: &TlsConfig) -> Result<()> {
+    ssl_version(&tls.version)?;
+
+    if let Some(ca_file) = &tls.ca_file {
+        let cas = X509::stack_from_pem(&read(ca_file)?)
+            .with_context(|| format!("{} is not a PEM certificate bundle", ca_file.display()))?;
+        if cas.is_empty() {
+            bail!("{} contains no certificates", ca_file.display());
+        }
+    }
+
+    match (&tls.cert_file, &tls.key_file) {
+        (Some(cert_file), Some(key_file)) => {
+            let cert = X509::from_pem(&read(cert_file)?)
+                .with_context(|| format!("{} is not a PEM certificate", cert_file.display()))?;
+            let key = private_key(key_file, tls.key_password.as_deref())?;
+            if !cert.public_key()?.public_eq(&key) {
+                bail!(
+                    "{} does not belong to the certificate in {}",
+                    key_file.display(),
+                    cert_file.display()
+                );
+            }
+        }
+        (None, None) if tls.key_password.is_some() => {
+            bail!("key_password is set without a key_file")
+        }
+        (None, None) => {}
+        _ => bail!("cert_file and key_file must be set together"),
+    }
+    Ok(())
+}
+
+pub fn ssl_options(tls: &TlsConfig) -> Result<mqtt::SslOptions> {
+    let mut builder = mqtt::SslOptionsBuilder::new();
+    if let Some(ca_file) = &tls.ca_file {
+        builder.trust_store(ca_file)?;
+    }
+    if let Some(cert_file) = &tls.cert_file {
+        builder.key_store(cert_file)?;
+    }
+    if let Some(key_file) = &tls.key_file {
+        builder.private_key(key_file)?;
+    }
+    if let Some(password) = &tls.key_password {
+        builder.private_key_password(password);
+    }
+    builder
+        .ssl_version(ssl_version(&tls.version)?)
+        .enable_server_cert_auth(tls.verify_server_cert)
+        .verify(tls.verify_hostname);
+    Ok(builder.finalize())
+}
+
+// The vendored OpenSSL negotiates the newest version both sides support and
+// refuses anything older than TLS 1.2 at its default security level; paho
+// passes nothing finer to it. TLS 1.0 and 1.1 are rejected rather than
+// silently upgraded, and "1.3" cannot keep a broker from settling on 1.2.
+fn ssl_version(version: &str) -> Result<mqtt::SslVersion> {
+    Ok(match version {
+        "default" | "1.3" => mqtt::SslVersion::Default,
+        "1.2" => mqtt::SslVersion::Tls_1_2,
+        "1.0" | "1.1" => bail!("TLS {version} is insecure and no longer supported, use 1.2 or 1.3"),
+        _ => bail!("unsupported TLS version {version:?}, expected default, 1.2 or 1.3"),
+    })
+}
+
+fn private_key(path: &Path, password: Option<&str>) -> Result<PKey<Private>> {
+    let pem = read(path)?;
+    let key = match password {
+        Some(password) => PKey::private_key_from_pem_passphrase(&pem, password.as_bytes()),
+        None => PKey::private_key_from_pem(&pem),
+    };
+    key.with_context(|| {
+        format!(
+            "{} is not a PEM private key or the password is wrong",
+            path.display()
+        )
+    })
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::symm::Cipher;
+    use openssl::x509::X509NameBuilder;
+
+    use super::*;
+
+    // A self-signed certificate and its key, written as PEM files.
+    struct Files {
+        dir: PathBuf,
+    }
+
+    impl Files {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("pis-tls-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let key = PKey::generate_ed25519().unwrap();
+            let mut name = X509NameBuilder::new().unwrap();
+            name.append_entry_by_text("CN", "unit-7").unwrap();
+            let name = name.build();
+            let mut cert = X509::builder().unwrap();
+            cert.set_version(2).unwrap();
+            cert.set_subject_name(&name).unwrap();
+            cert.set_issuer_name(&name).unwrap();
+            cert.set_pubkey(&key).unwrap();
+            cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+                .unwrap();
+            cert.sign(&key, MessageDigest::null()).unwrap();
+            let cert = cert.build().to_pem().unwrap();
+            fs::write(dir.join("ca.pem"), &cert).unwrap();
+            fs::write(dir.join("cert.pem"), &cert).unwrap();
+            fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+            let locked = key
+                .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")
+                .unwrap();
+            fs::write(dir.join("locked.pem"), locked).unwrap();
+            let other = PKey::generate_ed25519().unwrap();
+            fs::write(
+                dir.join("other.pem"),
+                other.private_key_to_pem_pkcs8().unwrap(),
+            )
+            .unwrap();
+            Files { dir }
+        }
+
+        fn path(&self, name: &str) -> Option<PathBuf> {
+            Some(self.dir.join(name))
+        }
+
+        fn config(&self) -> TlsConfig {
+            TlsConfig {
+                ca_file: self.path("ca.pem"),
+                cert_file: self.path("cert.pem"),
+                key_file: self.path("key.pem"),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn error(tls: &TlsConfig) -> String {
+        format!("{:#}", check(tls).unwrap_err())
+    }
+
+    #[test]
+    fn matching_files_pass() {
+        let files = Files::new();
+        check(&files.config()).unwrap();
+        ssl_options(&files.config()).unwrap();
+
+        let locked = TlsConfig {
+            key_file: files.path("locked.pem"),
+            key_password: Some("secret".to_string()),
+            ..files.config()
+        };
+        check(&locked).unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_reported() {
+        let files = Files::new();
+        for (tls, missing) in [
+            (
+                TlsConfig {
+                    ca_file: files.path("missing-ca.pem"),
+                    ..files.config()
+                },
+                "missing-ca.pem",
+            ),
+            (
+                TlsConfig {
+                    cert_file: files.path("missing-cert.pem"),
+                    ..files.config()
+                },
+                "missing-cert.pem",
+            ),
+            (
+                TlsConfig {
+                    key_file: files.path("missing-key.pem"),
+                    ..files.config()
+                },
+                "missing-key.pem",
+            ),
+        ] {
+            let e = error(&tls);
+            assert!(e.starts_with("cannot read "), "{e}");
+            assert!(e.contains(missing), "{e}");
+        }
+
+        let e = error(&TlsConfig {
+            key_file: None,
+            ..files.config()
+        });
+        assert_eq!(e, "cert_file and key_file must be set together");
+    }
+
+    #[test]
+    fn a_key_of_another_certificate_is_rejected() {
+        let files = Files::new();
+        let e = error(&TlsConfig {
+            key_file: files.path("other.pem"),
+            ..files.config()
+        });
+        assert!(e.contains("does not belong to the certificate"), "{e}");
+
+        let e = error(&TlsConfig {
+            key_file: files.path("locked.pem"),
+            key_password: Some("wrong".to_string()),
+            ..files.config()
+        });
+        assert!(e.contains("the password is wrong"), "{e}");
+    }
+
+    #[test]
+    fn only_tls_1_2_and_later_are_accepted() {
+        for version in ["default", "1.2", "1.3"] {
+            assert!(ssl_version(version).is_ok(), "{version}");
+        }
+        for version in ["1.0", "1.1"] {
+            let e = ssl_version(version).unwrap_err().to_string();
+            assert!(e.contains("no longer supported"), "{e}");
+        }
+        assert!(ssl_version("2.0").is_err());
+    }
+}
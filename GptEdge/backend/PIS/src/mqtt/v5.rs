@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use chrono::Utc;
+use log::warn;
+use paho_mqtt as mqtt;
+use uuid::Uuid;
+
+use super::outbox::QueuedMessage;
+use crate::codec::Codec;
+use crate::config::MqttConfig;
+use crate::identity::Identity;
+
+const TRACE_ID: &str = "trace_id";
+const CONTENT_TYPE: &str = "content-type";
+const CONTENT_ENCODING: &str = "content-encoding";
+
+// Turns queued messages into MQTT messages: encoded as configured in
+// mqtt.payload, with the properties configured in mqtt.v5. The client
+// leaves the properties out on a 3.1.1 connection.
+pub struct Publisher {
+    codec: Codec,
+    codecs: Vec<(String, Codec)>,
+    user_properties: Vec<(String, String)>,
+    trace_ids: bool,
+    expiry: Vec<(String, u32)>,
+    max_aliases: u16,
+    // Counts lost connections. Aliases are only valid on the connection
+    // they were set up on, so none are used until `connected` is called for
+    // the current one.
+    epoch: Arc<AtomicU64>,
+    connection: Option<u64>,
+    limit: u16,
+    next_alias: u16,
+    aliases: HashMap<String, u16>,
+}
+
+impl Publisher {
+    pub fn new(
+        settings: &MqttConfig,
+        device: &Identity,
+        client_id: &str,
+        epoch: Arc<AtomicU64>,
+    ) -> Self {
+        Publisher {
+            codec: settings.payload.codec(),
+            codecs: settings
+                .payload
+                .topics
+                .iter()
+                .map(|topic| (device.render(&topic.topic, client_id), topic.codec()))
+                .collect(),
+            user_properties: settings
+                .v5
+                .user_properties
+                .iter()
+                .map(|(name, value)| (name.clone(), device.render(value, client_id)))
+                .collect(),
+            trace_ids: settings.v5.trace_ids,
+            expiry: settings
+                .v5
+                .message_expiry
+                .iter()
+                .map(|(filter, secs)| (device.render(filter, client_id), *secs))
+                .collect(),
+            max_aliases: settings.v5.topic_aliases,
+            epoch,
+            connection: None,
+            limit: 0,
+            next_alias: 1,
+            aliases: HashMap::new(),
+        }
+    }
+
+    // Starts over with the topic aliases, up to as many as the broker
+    // accepts on the new connection.
+    pub fn connected(&mut self, topic_alias_maximum: u16) {
+        self.connection = Some(self.epoch.load(Ordering::SeqCst));
+        self.limit = self.max_aliases.min(topic_alias_maximum);
+        self.next_alias = 1;
+        self.aliases.clear();
+    }
+
+    // None once the message has expired. Payloads that are not JSON, such
+    // as those from templates, are sent as they are. `mqtt_version` is that
+    // of the client's connection.
+    pub fn message(
+        &mut self,
+        mqtt_version: u32,
+        message: &QueuedMessage,
+    ) -> mqtt::Result<Option<mqtt::Message>> {
+        let mut codec = self.codec(&message.topic);
+        let payload = match codec.encode_json(&message.payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("sending message to {} unencoded: {e:#}", message.topic);
+                codec = Codec::JSON;
+                message.payload.clone()
+            }
+        };
+        let mut properties = self.properties(None, codec)?;
+        if let Some(secs) = self.expiry(&message.topic) {
+            let age = (Utc::now() - message.timestamp).num_seconds().max(0);
+            let Some(left) = u32::try_from(i64::from(secs) - age)
+                .ok()
+                .filter(|&left| left > 0)
+            else {
+                return Ok(None);
+            };
+            properties.push_u32(mqtt::PropertyCode::MessageExpiryInterval, left)?;
+        }
+        let mut topic = message.topic.as_str();
+        if self.aliases_usable(mqtt_version) {
+            match self.aliases.get(topic) {
+                Some(&alias) => {
+                    properties.push_u16(mqtt::PropertyCode::TopicAlias, alias)?;
+                    topic = "";
+                }
+                None if self.next_alias <= self.limit => {
+                    properties.push_u16(mqtt::PropertyCode::TopicAlias, self.next_alias)?;
+                    self.aliases.insert(topic.to_string(), self.next_alias);
+                    self.next_alias += 1;
+                }
+                None => {}
+            }
+        }
+        Ok(Some(
+            mqtt::MessageBuilder::new()
+                .topic(topic)
+                .payload(payload)
+                .qos(message.qos)
+                .retained(message.retain)
+                .properties(properties)
+                .finalize(),
+        ))
+    }
+
+    // The broker may not have seen the alias assignment of a message that
+    // failed, so the topic is sent in full next time.
+    pub fn failed(&mut self, topic: &str) {
+        self.aliases.remove(topic);
+    }
+
+    // How a received message is encoded: as its content type says, or else
+    // as configured for its topic.
+    pub fn received_codec(&self, message: &mqtt::Message) -> Result<Codec> {
+        let user_property = |wanted: &str| {
+            message
+                .properties()
+                .user_iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| value)
+        };
+        match (user_property(CONTENT_TYPE), user_property(CONTENT_ENCODING)) {
+            (None, None) => Ok(self.codec(message.topic())),
+            (content_type, content_encoding) => {
+                Codec::from_content_type(content_type.as_deref(), content_encoding.as_deref())
+            }
+        }
+    }
+
+    // The properties of a command response, which keeps the trace id of the
+    // request.
+    pub fn reply_properties(
+        &self,
+        request: &mqtt::Message,
+        codec: Codec,
+    ) -> mqtt::Result<mqtt::Properties> {
+        let trace_id = request
+            .properties()
+            .user_iter()
+            .find(|(name, _)| name == TRACE_ID)
+            .map(|(_, value)| value);
+        self.properties(trace_id, codec)
+    }
+
+    fn properties(&self, trace_id: Option<String>, codec: Codec) -> mqtt::Result<mqtt::Properties> {
+        let mut properties = mqtt::Properties::new();
+        for (name, value) in &self.user_properties {
+            properties.push_string_pair(mqtt::PropertyCode::UserProperty, name, value)?;
+        }
+        let trace_id = match trace_id {
+            Some(trace_id) => Some(trace_id),
+            None => self.trace_ids.then(|| Uuid::new_v4().to_string()),
+        };
+        if let Some(trace_id) = trace_id {
+            properties.push_string_pair(mqtt::PropertyCode::UserProperty, TRACE_ID, &trace_id)?;
+        }
+        // Plain JSON goes without, to keep messages small.
+        if codec != Codec::JSON {
+            properties.push_string_pair(
+                mqtt::PropertyCode::UserProperty,
+                CONTENT_TYPE,
+                codec.content_type(),
+            )?;
+            if let Some(content_encoding) = codec.content_encoding() {
+                properties.push_string_pair(
+                    mqtt::PropertyCode::UserProperty,
+                    CONTENT_ENCODING,
+                    content_encoding,
+                )?;
+            }
+        }
+        Ok(properties)
+    }
+
+    fn codec(&self, topic: &str) -> Codec {
+        self.codecs
+            .iter()
+            .find(|(filter, _)| mqtt::topic_matcher::topic_matches(filter, topic))
+            .map_or(self.codec, |(_, codec)| *codec)
+    }
+
+    fn expiry(&self, topic: &str) -> Option<u32> {
+        self.expiry
+            .iter()
+            .find(|(filter, _)| mqtt::topic_matcher::topic_matches(filter, topic))
+            .map(|(_, secs)| *secs)
+    }
+
+    fn aliases_usable(&self, mqtt_version: u32) -> bool {
+        mqtt_version >= mqtt::MQTT_VERSION_5
+            && self.connection == Some(self.epoch.load(Ordering::SeqCst))
+    }
+}
+
+// Paho reports the return code of a refused connection and the reason code
+// of a failed MQTT v5 request as the error code.
+pub fn describe(e: &mqtt::Error) -> String {
+    let described = match e {
+        mqtt::Error::Paho(code) => reason(*code),
+        mqtt::Error::PahoDescr(code, what) => {
+            reason(*code).map(|reason| format!("{what}: {reason}"))
+        }
+        mqtt::Error::ReasonCode(code) => reason(*code as i32),
+        _ => None,
+    };
+    described.unwrap_or_else(|| e.to_string())
+}
+
+pub fn reason(code: i32) -> Option<String> {
+    let text = match code {
+        1 => "unacceptable protocol version",
+        2 => "client identifier rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorized",
+        0x80..=0xff => {
+            let reason = mqtt::ReasonCode::from(code as u32);
+            return Some(format!("{reason} (reason code {code:#04x})"));
+        }
+        _ => return None,
+    };
+    Some(format!("{text} (return code {code})"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::Duration;
+
+    use super::*;
+    use crate::identity::Credentials;
+
+    fn publisher(settings: &MqttConfig) -> (Publisher, Arc<AtomicU64>) {
+        let device = Identity {
+            device_id: "unit-7".to_string(),
+            machine_id: None,
+            serial_number: None,
+            macs: BTreeMap::new(),
+            fleet_id: None,
+            vehicle_id: None,
+            provisioned_at: None,
+            credentials: Credentials::default(),
+        };
+        let epoch = Arc::new(AtomicU64::new(0));
+        let publisher = Publisher::new(settings, &device, "unit-7", epoch.clone());
+        (publisher, epoch)
+    }
+
+    fn queued(topic: &str, age: Duration) -> QueuedMessage {
+        QueuedMessage {
+            topic: topic.to_string(),
+            qos: 1,
+            retain: false,
+            timestamp: Utc::now() - age,
+            payload: b"{}".to_vec(),
+        }
+    }
+
+    // The topic and topic alias a message goes out with.
+    fn sent(publisher: &mut Publisher, version: u32, topic: &str) -> (String, Option<i32>) {
+        let message = publisher
+            .message(version, &queued(topic, Duration::zero()))
+            .unwrap()
+            .unwrap();
+        let alias = message.properties().get_int(mqtt::PropertyCode::TopicAlias);
+        (message.topic().to_string(), alias)
+    }
+
+    #[test]
+    fn topics_are_aliased_after_their_first_message() {
+        let mut settings = MqttConfig::default();
+        settings.v5.topic_aliases = 2;
+        let (mut publisher, _) = publisher(&settings);
+        let v5 = mqtt::MQTT_VERSION_5;
+        publisher.connected(10);
+
+        assert_eq!(sent(&mut publisher, v5, "a"), ("a".to_string(), Some(1)));
+        assert_eq!(sent(&mut publisher, v5, "a"), (String::new(), Some(1)));
+        assert_eq!(sent(&mut publisher, v5, "b"), ("b".to_string(), Some(2)));
+        // Beyond topic_aliases topics go in full.
+        assert_eq!(sent(&mut publisher, v5, "c"), ("c".to_string(), None));
+        assert_eq!(sent(&mut publisher, v5, "b"), (String::new(), Some(2)));
+
+        // The broker may not have seen the assignment.
+        publisher.failed("a");
+        assert_eq!(sent(&mut publisher, v5, "a"), ("a".to_string(), None));
+    }
+
+    #[test]
+    fn aliases_need_a_v5_connection_that_is_still_up() {
+        let mut settings = MqttConfig::default();
+        settings.v5.topic_aliases = 10;
+        let (mut publisher, epoch) = publisher(&settings);
+        let v5 = mqtt::MQTT_VERSION_5;
+        assert_eq!(sent(&mut publisher, v5, "a"), ("a".to_string(), None));
+
+        // No more than the broker accepts.
+        publisher.connected(1);
+        assert_eq!(
+            sent(&mut publisher, mqtt::MQTT_VERSION_3_1_1, "a"),
+            ("a".to_string(), None)
+        );
+        assert_eq!(sent(&mut publisher, v5, "a"), ("a".to_string(), Some(1)));
+        assert_eq!(sent(&mut publisher, v5, "b"), ("b".to_string(), None));
+
+        // Lost: the next connection starts over.
+        epoch.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(sent(&mut publisher, v5, "a"), ("a".to_string(), None));
+        publisher.connected(1);
+        assert_eq!(sent(&mut publisher, v5, "a"), ("a".to_string(), Some(1)));
+    }
+
+    #[test]
+    fn expiry_counts_from_when_the_message_was_queued() {
+        let mut settings = MqttConfig::default();
+        settings
+            .v5
+            .message_expiry
+            .insert("pis/{device_id}/passengers/#".to_string(), 30);
+        let (mut publisher, _) = publisher(&settings);
+        let expiry = |publisher: &mut Publisher, topic: &str, age: i64| {
+            publisher
+                .message(mqtt::MQTT_VERSION_5, &queued(topic, Duration::seconds(age)))
+                .unwrap()
+                .map(|message| {
+                    message
+                        .properties()
+                        .get_int(mqtt::PropertyCode::MessageExpiryInterval)
+                })
+        };
+
+        let left = expiry(&mut publisher, "pis/unit-7/passengers/count", 10)
+            .unwrap()
+            .unwrap();
+        assert!((19..=20).contains(&left), "{left}");
+        assert_eq!(
+            expiry(&mut publisher, "pis/unit-7/passengers/count", 40),
+            None
+        );
+        // Other topics do not expire.
+        assert_eq!(
+            expiry(&mut publisher, "pis/unit-7/heartbeat", 40),
+            Some(None)
+        );
+    }
+}
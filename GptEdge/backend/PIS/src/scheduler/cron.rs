@@ -0,0 +1,333 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use chrono::{
+    DateTime, Datelike, Duration, Local, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike,
+    Utc,
+};
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Nothing matches for this long, e.g. `0 0 30 2 *`, means nothing ever will.
+const SEARCH_YEARS: i32 = 5;
+
+// A cron expression evaluated in local time. Five fields (minute, hour, day
+// of month, month, day of week) or six with a leading seconds field, each a
+// `*`, a value, a range `a-b` or a list of those, optionally with a `/step`.
+// Months and weekdays may be given by their English abbreviations and
+// Sunday is 0 or 7. As in Vixie cron, a day matches when either the day of
+// month or the day of week matches if both are restricted. `@yearly`,
+// `@monthly`, `@weekly`, `@daily` and `@hourly` are accepted as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    text: String,
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let expanded = match text.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            macro_ if macro_.starts_with('@') => bail!("unknown cron macro {macro_}"),
+            fields => fields,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let (seconds, rest) = match fields.len() {
+            5 => ("0", &fields[..]),
+            6 => (fields[0], &fields[1..]),
+            n => bail!("cron expression {text:?} has {n} fields, expected 5 or 6"),
+        };
+        let weekdays = field(rest[4], 0, 7, &WEEKDAYS).context("invalid day of week")?;
+        Ok(Cron {
+            text: text.trim().to_string(),
+            seconds: field(seconds, 0, 59, &[]).context("invalid seconds")?,
+            minutes: field(rest[0], 0, 59, &[]).context("invalid minutes")?,
+            hours: field(rest[1], 0, 23, &[]).context("invalid hours")?,
+            days: field(rest[2], 1, 31, &[]).context("invalid day of month")?,
+            months: field(rest[3], 1, 12, &MONTHS).context("invalid month")?,
+            // 7 is another name for Sunday.
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: rest[2].starts_with('*'),
+            any_weekday: rest[4].starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Cron {
+    // The first matching time strictly after `after`. Local times skipped
+    // by a daylight saving change never match; local times repeated by one
+    // match only once.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_timezone(&Local).naive_local();
+        let mut t = start.with_nanosecond(0)? + Duration::seconds(1);
+        let limit = start.year() + SEARCH_YEARS;
+        while t.year() <= limit {
+            if !bit(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = midnight(NaiveDate::from_ymd_opt(year, month, 1)?);
+            } else if !self.day_matches(&t) {
+                t = midnight(t.date().succ_opt()?);
+            } else if !bit(self.hours, t.hour()) {
+                t = t.with_minute(0)?.with_second(0)? + Duration::hours(1);
+            } else if !bit(self.minutes, t.minute()) {
+                t = t.with_second(0)? + Duration::minutes(1);
+            } else if !bit(self.seconds, t.second()) {
+                t += Duration::seconds(1);
+            } else {
+                let found = match Local.from_local_datetime(&t) {
+                    LocalResult::Single(time) => Some(time),
+                    // Not necessarily in order, and right at a change one
+                    // of them may really be another local time.
+                    LocalResult::Ambiguous(first, second) => [first, second]
+                        .into_iter()
+                        .filter(|time| {
+                            *time > after && time.with_timezone(&Local).naive_local() == t
+                        })
+                        .min(),
+                    LocalResult::None => None,
+                };
+                match found {
+                    Some(time) if time > after => return Some(time.with_timezone(&Utc)),
+                    _ => t += Duration::seconds(1),
+                }
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let day = bit(self.days, t.day());
+        let weekday = bit(self.weekdays, t.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+fn bit(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+fn midnight(date: NaiveDate) -> NaiveDateTime {
+    date.and_hms_opt(0, 0, 0).unwrap()
+}
+
+// Parses one field into a bit mask of the values it matches.
+fn field(text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let value = |text: &str| -> Result<u32> {
+        let lower = text.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            // Named months start at 1, named weekdays at 0.
+            Some(index) => index as u32 + min,
+            None => text
+                .parse()
+                .with_context(|| format!("{text:?} is not a number"))?,
+        };
+        if !(min..=max).contains(&value) {
+            bail!("{value} is not between {min} and {max}");
+        }
+        Ok(value)
+    };
+    let mut mask = 0;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .with_context(|| format!("invalid step {step:?}"))?;
+                if step == 0 {
+                    bail!("step must be greater than 0");
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                // `5/15` runs from 5 to the end of the range.
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if first > last {
+            bail!("range {range} is reversed");
+        }
+        for value in (first..=last).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The zone with the daylight saving changes the tests expect. Every test
+    // sets the same one, so running them in parallel is fine.
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        std::env::set_var("TZ", "Europe/Berlin");
+        Local
+            .with_ymd_and_hms(y, mo, d, h, mi, s)
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    fn next(expression: &str, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        expression.parse::<Cron>().unwrap().next_after(after)
+    }
+
+    fn values(mask: u64) -> Vec<u32> {
+        (0..64).filter(|&value| bit(mask, value)).collect()
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(values(field("*/15", 0, 59, &[]).unwrap()), [0, 15, 30, 45]);
+        assert_eq!(values(field("5/20", 0, 59, &[]).unwrap()), [5, 25, 45]);
+        assert_eq!(values(field("1-10/3", 1, 31, &[]).unwrap()), [1, 4, 7, 10]);
+        assert_eq!(values(field("1,3-4", 0, 7, &[]).unwrap()), [1, 3, 4]);
+        assert_eq!(values(field("mar-May", 1, 12, &MONTHS).unwrap()), [3, 4, 5]);
+        assert_eq!(values(field("sun,SAT", 0, 7, &WEEKDAYS).unwrap()), [0, 6]);
+
+        assert!(field("*/0", 0, 59, &[]).is_err());
+        assert!(field("10-5", 0, 59, &[]).is_err());
+        assert!(field("60", 0, 59, &[]).is_err());
+        assert!(field("0", 1, 31, &[]).is_err());
+        assert!(field("foo", 1, 12, &MONTHS).is_err());
+        assert!(field("", 0, 59, &[]).is_err());
+    }
+
+    #[test]
+    fn parses_expressions() {
+        let cron: Cron = "0 0 * * 7".parse().unwrap();
+        assert_eq!(values(cron.weekdays), [0]);
+        let weekly: Cron = "@weekly".parse().unwrap();
+        assert_eq!((cron.weekdays, cron.days), (weekly.weekdays, weekly.days));
+        assert_eq!(values(cron.seconds), [0]);
+
+        let cron: Cron = "30 */10 * * * *".parse().unwrap();
+        assert_eq!(values(cron.seconds), [30]);
+        assert_eq!(values(cron.minutes), [0, 10, 20, 30, 40, 50]);
+        assert_eq!(cron.to_string(), "30 */10 * * * *");
+
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("* * * * * * *".parse::<Cron>().is_err());
+        assert!("@fortnightly".parse::<Cron>().is_err());
+        assert!("* * * 13 *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn finds_the_next_match_strictly_after() {
+        let noon = local(2026, 6, 1, 12, 0, 0);
+        assert_eq!(next("* * * * *", noon), Some(local(2026, 6, 1, 12, 1, 0)));
+        assert_eq!(
+            next("*/20 * * * * *", local(2026, 6, 1, 12, 0, 5)),
+            Some(local(2026, 6, 1, 12, 0, 20))
+        );
+        assert_eq!(next("0 12 * * *", noon), Some(local(2026, 6, 2, 12, 0, 0)));
+        assert_eq!(next("@yearly", noon), Some(local(2027, 1, 1, 0, 0, 0)));
+        assert_eq!(next("0 0 31 * *", noon), Some(local(2026, 7, 31, 0, 0, 0)));
+    }
+
+    #[test]
+    fn handles_leap_days_and_impossible_dates() {
+        let after = local(2026, 3, 1, 0, 0, 0);
+        assert_eq!(next("0 0 29 2 *", after), Some(local(2028, 2, 29, 0, 0, 0)));
+        assert_eq!(next("0 0 30 2 *", after), None);
+    }
+
+    #[test]
+    fn restricted_day_of_month_and_week_match_either() {
+        // Saturday: Friday the 9th comes first, then Tuesday the 13th.
+        let after = local(2026, 10, 3, 0, 0, 0);
+        let first = next("0 0 13 * fri", after).unwrap();
+        assert_eq!(first, local(2026, 10, 9, 0, 0, 0));
+        assert_eq!(
+            next("0 0 13 * fri", first),
+            Some(local(2026, 10, 13, 0, 0, 0))
+        );
+
+        assert_eq!(
+            next("0 0 13 * *", after),
+            Some(local(2026, 10, 13, 0, 0, 0))
+        );
+        assert_eq!(next("0 0 * * 5", after), Some(local(2026, 10, 9, 0, 0, 0)));
+    }
+
+    #[test]
+    fn stepped_day_of_month_counts_as_unrestricted() {
+        // Mondays on odd days: the 2nd is even, the 9th is not.
+        let after = local(2026, 11, 1, 0, 0, 0);
+        assert_eq!(
+            next("0 0 */2 * mon", after),
+            Some(local(2026, 11, 9, 0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn skips_local_times_that_do_not_exist() {
+        // 2026-03-29 02:00 CET jumps to 03:00 CEST.
+        let after = local(2026, 3, 28, 12, 0, 0);
+        assert_eq!(
+            next("30 2 * * *", after),
+            Some(local(2026, 3, 30, 2, 30, 0))
+        );
+        assert_eq!(
+            next("0 * * * *", local(2026, 3, 29, 1, 30, 0)),
+            Some(utc(2026, 3, 29, 1, 0, 0))
+        );
+    }
+
+    #[test]
+    fn matches_repeated_local_times_once() {
+        // 2026-10-25 03:00 CEST goes back to 02:00 CET.
+        let first = next("30 2 * * *", local(2026, 10, 24, 12, 0, 0)).unwrap();
+        assert_eq!(first, utc(2026, 10, 25, 0, 30, 0));
+        assert_eq!(
+            next("30 2 * * *", first),
+            Some(local(2026, 10, 26, 2, 30, 0))
+        );
+
+        let hour = next("0 * * * *", utc(2026, 10, 24, 23, 30, 0)).unwrap();
+        assert_eq!(hour, utc(2026, 10, 25, 0, 0, 0));
+        assert_eq!(next("0 * * * *", hour), Some(utc(2026, 10, 25, 2, 0, 0)));
+    }
+}
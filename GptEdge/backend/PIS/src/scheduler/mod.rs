@@ -0,0 +1,705 @@
+mod cron;
+
+pub use cron::Cron;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context as _, Error, Result};
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
+use serde_derive::Serialize;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::time;
+
+use crate::config::{CatchUp, Config, ConfigWatch, JobConfig, Overlap};
+use crate::supervisor::Shutdown;
+
+pub const NAME: &str = "scheduler";
+
+// Wall-clock time is checked at least this often: tokio's timers stand
+// still while the device is suspended, and runs missed meanwhile would only
+// be noticed when the next one is due.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+// A run starting later than this after its time counts as missed.
+const LATE: Duration = Duration::from_secs(2);
+// The clock going back by more than this reschedules every job, which
+// would otherwise wait for its old time to come round again.
+const CLOCK_TOLERANCE: Duration = Duration::from_secs(2);
+// Missed runs made up for with `catch_up = "all"`.
+const MAX_CATCH_UP: usize = 10;
+// Runs waiting with `overlap = "queue"`.
+const MAX_QUEUED: usize = 10;
+// Missed runs of very frequent jobs stop being counted after a long suspend.
+const MAX_SCAN: u64 = 100_000;
+
+// When a job runs, see `JobConfig` for the syntax.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Rate(Duration),
+    Delay(Duration),
+    Cron(Cron),
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let text = text.trim();
+        if text.is_empty() {
+            bail!("schedule must not be empty");
+        }
+        let schedule = if let Some(period) = text.strip_prefix("every ") {
+            Schedule::Rate(parse_duration(period)?)
+        } else if let Some(delay) = text.strip_prefix("after ") {
+            Schedule::Delay(parse_duration(delay)?)
+        } else {
+            Schedule::Cron(text.parse()?)
+        };
+        Ok(schedule)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Rate(period) => write!(f, "every {}", format_duration(*period)),
+            Schedule::Delay(delay) => write!(f, "after {}", format_duration(*delay)),
+            Schedule::Cron(cron) => write!(f, "{cron}"),
+        }
+    }
+}
+
+impl Schedule {
+    // The first run of a job scheduled at `now`.
+    fn first(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Rate(period) | Schedule::Delay(period) => add(now, *period),
+            Schedule::Cron(cron) => cron.next_after(now),
+        }
+    }
+
+    // The run after the one due at `due`. Fixed-delay jobs are scheduled
+    // when a run ends instead.
+    fn after(&self, due: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::Rate(period) => add(due, *period),
+            Schedule::Delay(_) => None,
+            Schedule::Cron(cron) => cron.next_after(due),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    pub schedule: Schedule,
+    pub timeout: Option<Duration>,
+    pub overlap: Overlap,
+    pub catch_up: CatchUp,
+}
+
+impl Timing {
+    fn from_config(config: &JobConfig) -> Result<Self> {
+        Ok(Timing {
+            schedule: config.schedule.parse()?,
+            timeout: config.timeout(),
+            overlap: config.overlap,
+            catch_up: config.catch_up,
+        })
+    }
+}
+
+// Handed to the job for every run.
+#[derive(Debug, Clone)]
+pub struct Run {
+    pub job: String,
+    // When the run was due; the run itself starts a little later.
+    pub scheduled: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+    TimedOut,
+    Panicked,
+    Cancelled,
+    // Not started because of the overlap policy.
+    Skipped,
+    // Not started because of the catch-up policy.
+    Missed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    pub scheduled: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobReport {
+    pub schedule: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    pub overlap: Overlap,
+    pub catch_up: CatchUp,
+    pub next_run: Option<DateTime<Utc>>,
+    pub running_since: Option<DateTime<Utc>>,
+    pub queued: usize,
+    // Runs started since the service started, and how many of them failed,
+    // timed out or panicked.
+    pub runs: u64,
+    pub failures: u64,
+    // Oldest first.
+    pub history: VecDeque<Record>,
+}
+
+type Handler = Arc<dyn Fn(Run) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+struct Builtin {
+    timing: fn(&Config) -> Timing,
+    handler: Handler,
+}
+
+struct Definition {
+    timing: Timing,
+    command: Option<Vec<String>>,
+    handler: Handler,
+}
+
+// Runs the jobs registered in code and the command jobs from
+// `scheduler.jobs`, and keeps the run history reported over HTTP.
+pub struct Scheduler {
+    builtin: BTreeMap<&'static str, Builtin>,
+    jobs: Mutex<BTreeMap<String, JobReport>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            builtin: BTreeMap::new(),
+            jobs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Registers a job. `timing` is asked again whenever the configuration
+    // is reloaded.
+    pub fn register<F, Fut>(&mut self, name: &'static str, timing: fn(&Config) -> Timing, job: F)
+    where
+        F: Fn(Run) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |run| Box::pin(job(run)));
+        self.builtin.insert(name, Builtin { timing, handler });
+    }
+
+    pub fn jobs(&self) -> BTreeMap<String, JobReport> {
+        self.jobs.lock().unwrap().clone()
+    }
+
+    pub fn job(&self, name: &str) -> Option<JobReport> {
+        self.jobs.lock().unwrap().get(name).cloned()
+    }
+
+    fn definitions(&self, config: &Config) -> BTreeMap<String, Definition> {
+        let mut definitions: BTreeMap<String, Definition> = self
+            .builtin
+            .iter()
+            .map(|(name, builtin)| {
+                let definition = Definition {
+                    timing: (builtin.timing)(config),
+                    command: None,
+                    handler: builtin.handler.clone(),
+                };
+                (name.to_string(), definition)
+            })
+            .collect();
+        for (name, job) in &config.scheduler.jobs {
+            if definitions.contains_key(name) {
+                error!("ignoring scheduler.jobs.{name}, the name is taken by a built-in job");
+                continue;
+            }
+            match Timing::from_config(job) {
+                Ok(timing) => {
+                    let definition = Definition {
+                        timing,
+                        command: Some(job.command.clone()),
+                        handler: command(job.command.clone()),
+                    };
+                    definitions.insert(name.clone(), definition);
+                }
+                Err(e) => error!("ignoring scheduler.jobs.{name}: {e:#}"),
+            }
+        }
+        definitions
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Job {
+    name: String,
+    timing: Timing,
+    command: Option<Vec<String>>,
+    handler: Handler,
+    next: Option<DateTime<Utc>>,
+    running: Option<Running>,
+    queue: VecDeque<DateTime<Utc>>,
+}
+
+struct Running {
+    id: u64,
+    started: DateTime<Utc>,
+    abort: AbortHandle,
+}
+
+// Sent by a run when it ended.
+struct Done {
+    job: String,
+    id: u64,
+    scheduled: DateTime<Utc>,
+    started: DateTime<Utc>,
+    outcome: Outcome,
+    detail: Option<String>,
+}
+
+struct Context {
+    scheduler: Arc<Scheduler>,
+    done: mpsc::UnboundedSender<Done>,
+    next_id: u64,
+    history: usize,
+}
+
+impl Context {
+    fn record(&self, job: &str, record: Record) {
+        let mut jobs = self.scheduler.jobs.lock().unwrap();
+        let Some(report) = jobs.get_mut(job) else {
+            return;
+        };
+        if record.started.is_some() {
+            report.runs += 1;
+        }
+        if matches!(
+            record.outcome,
+            Outcome::Failed | Outcome::TimedOut | Outcome::Panicked
+        ) {
+            report.failures += 1;
+        }
+        report.history.push_back(record);
+        while report.history.len() > self.history {
+            report.history.pop_front();
+        }
+    }
+
+    fn publish(&self, jobs: &BTreeMap<String, Job>) {
+        let mut reports = self.scheduler.jobs.lock().unwrap();
+        reports.retain(|name, _| jobs.contains_key(name));
+        for (name, job) in jobs {
+            let report = reports.entry(name.clone()).or_insert_with(|| JobReport {
+                schedule: String::new(),
+                command: None,
+                timeout_ms: None,
+                overlap: job.timing.overlap,
+                catch_up: job.timing.catch_up,
+                next_run: None,
+                running_since: None,
+                queued: 0,
+                runs: 0,
+                failures: 0,
+                history: VecDeque::new(),
+            });
+            report.schedule = job.timing.schedule.to_string();
+            report.command = job.command.clone();
+            report.timeout_ms = job.timing.timeout.map(|timeout| timeout.as_millis() as u64);
+            report.overlap = job.timing.overlap;
+            report.catch_up = job.timing.catch_up;
+            report.next_run = job.next;
+            report.running_since = job.running.as_ref().map(|running| running.started);
+            report.queued = job.queue.len();
+            while report.history.len() > self.history {
+                report.history.pop_front();
+            }
+        }
+    }
+}
+
+// Runs every job on its schedule until shutdown, which cancels the runs
+// still in progress. Schedules are kept in wall-clock time, so runs missed
+// while the device slept or the clock jumped forward are handled by the
+// job's catch-up policy.
+pub async fn run(
+    mut config: ConfigWatch,
+    scheduler: Arc<Scheduler>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut context = Context {
+        scheduler,
+        done: done_tx,
+        next_id: 0,
+        history: 0,
+    };
+    let mut jobs = BTreeMap::new();
+    let current = config.borrow_and_update().clone();
+    apply(&mut jobs, &current, &mut context);
+
+    let mut last_now = Utc::now();
+    while !shutdown.is_triggered() {
+        let now = Utc::now();
+        if let Ok(back) = (last_now - now).to_std() {
+            if back > CLOCK_TOLERANCE {
+                warn!("clock moved back by {back:?}, rescheduling jobs");
+                for job in jobs.values_mut().filter(|job| job.next.is_some()) {
+                    job.next = job.timing.schedule.first(now);
+                }
+            }
+        }
+        last_now = now;
+        for job in jobs.values_mut() {
+            poll(job, now, &mut context);
+        }
+        context.publish(&jobs);
+
+        let sleep = match jobs.values().filter_map(|job| job.next).min() {
+            Some(next) => (next - Utc::now())
+                .to_std()
+                .unwrap_or_default()
+                .min(MAX_SLEEP),
+            None => MAX_SLEEP,
+        };
+        tokio::select! {
+            _ = time::sleep(sleep) => {}
+            Some(done) = done_rx.recv() => finish(&mut jobs, done, &mut context),
+            Ok(()) = config.changed() => {
+                let current = config.borrow_and_update().clone();
+                apply(&mut jobs, &current, &mut context);
+            }
+            _ = shutdown.wait() => {}
+        }
+    }
+    for running in jobs.values().filter_map(|job| job.running.as_ref()) {
+        running.abort.abort();
+    }
+    Ok(())
+}
+
+// Brings the running jobs in line with the configuration.
+fn apply(jobs: &mut BTreeMap<String, Job>, config: &Config, context: &mut Context) {
+    context.history = config.scheduler.history;
+    let definitions = context.scheduler.definitions(config);
+    jobs.retain(|name, job| {
+        let keep = definitions.contains_key(name);
+        if !keep {
+            info!("job {name} removed");
+            if let Some(running) = &job.running {
+                running.abort.abort();
+            }
+        }
+        keep
+    });
+    let now = Utc::now();
+    for (name, definition) in definitions {
+        match jobs.get_mut(&name) {
+            Some(job) if job.timing == definition.timing && job.command == definition.command => {}
+            Some(job) => {
+                info!("job {name} rescheduled, {}", definition.timing.schedule);
+                let waits_for_run = job.running.is_some()
+                    && matches!(definition.timing.schedule, Schedule::Delay(_));
+                job.next = match waits_for_run {
+                    true => None,
+                    false => definition.timing.schedule.first(now),
+                };
+                job.timing = definition.timing;
+                job.command = definition.command;
+                job.handler = definition.handler;
+                job.queue.clear();
+            }
+            None => {
+                info!("job {name} scheduled, {}", definition.timing.schedule);
+                let job = Job {
+                    name: name.clone(),
+                    next: definition.timing.schedule.first(now),
+                    timing: definition.timing,
+                    command: definition.command,
+                    handler: definition.handler,
+                    running: None,
+                    queue: VecDeque::new(),
+                };
+                jobs.insert(name, job);
+            }
+        }
+    }
+    context.publish(jobs);
+}
+
+// Starts the job if it is due, applying its catch-up policy when more than
+// one run is due.
+fn poll(job: &mut Job, now: DateTime<Utc>, context: &mut Context) {
+    let Some(next) = job.next.filter(|next| *next <= now) else {
+        return;
+    };
+    // A fixed delay counts from the end of the last run, so however late
+    // it is, it is only one run.
+    if let Schedule::Delay(_) = job.timing.schedule {
+        job.next = None;
+        trigger(job, next, context);
+        return;
+    }
+
+    let mut due = VecDeque::new();
+    let mut count: u64 = 0;
+    let mut cursor = Some(next);
+    while let Some(time) = cursor.filter(|time| *time <= now) {
+        count += 1;
+        if due.len() == MAX_CATCH_UP {
+            due.pop_front();
+        }
+        due.push_back(time);
+        cursor = match count < MAX_SCAN {
+            true => job.timing.schedule.after(time),
+            false => job.timing.schedule.first(now),
+        };
+    }
+    job.next = cursor;
+
+    let latest = *due.back().unwrap();
+    let late = (now - latest).to_std().unwrap_or_default() > LATE;
+    let runs: Vec<DateTime<Utc>> = match job.timing.catch_up {
+        CatchUp::Skip if late => Vec::new(),
+        CatchUp::Skip | CatchUp::Once => vec![latest],
+        CatchUp::All => due.into(),
+    };
+    let missed = count - runs.len() as u64;
+    if missed > 0 {
+        warn!("job {}: {missed} runs missed", job.name);
+        let record = Record {
+            scheduled: next,
+            started: None,
+            duration_ms: None,
+            outcome: Outcome::Missed,
+            detail: Some(format!("{missed} runs missed")),
+        };
+        context.record(&job.name, record);
+    }
+    match runs[..] {
+        [] => {}
+        [scheduled] => trigger(job, scheduled, context),
+        _ => {
+            job.queue.extend(runs);
+            if job.running.is_none() {
+                let scheduled = job.queue.pop_front().unwrap();
+                start(job, scheduled, context);
+            }
+        }
+    }
+}
+
+// Starts a due run, or applies the overlap policy while one is going on.
+fn trigger(job: &mut Job, scheduled: DateTime<Utc>, context: &mut Context) {
+    let Some(running) = &job.running else {
+        return start(job, scheduled, context);
+    };
+    let skip = |job: &Job, detail: &str| {
+        info!("job {}: run skipped, {detail}", job.name);
+        let record = Record {
+            scheduled,
+            started: None,
+            duration_ms: None,
+            outcome: Outcome::Skipped,
+            detail: Some(detail.to_string()),
+        };
+        context.record(&job.name, record);
+    };
+    match job.timing.overlap {
+        Overlap::Skip => skip(job, "previous run still in progress"),
+        Overlap::Queue if job.queue.len() < MAX_QUEUED => job.queue.push_back(scheduled),
+        Overlap::Queue => skip(job, "too many runs queued"),
+        Overlap::Cancel => {
+            info!("job {}: cancelling the previous run", job.name);
+            running.abort.abort();
+            job.running = None;
+            start(job, scheduled, context);
+        }
+    }
+}
+
+fn start(job: &mut Job, scheduled: DateTime<Utc>, context: &mut Context) {
+    context.next_id += 1;
+    let id = context.next_id;
+    let started = Utc::now();
+    debug!("job {} started", job.name);
+    let task = tokio::spawn((job.handler)(Run {
+        job: job.name.clone(),
+        scheduled,
+    }));
+    let abort = task.abort_handle();
+    job.running = Some(Running {
+        id,
+        started,
+        abort: task.abort_handle(),
+    });
+
+    let timeout = job.timing.timeout;
+    let name = job.name.clone();
+    let done = context.done.clone();
+    tokio::spawn(async move {
+        let result = match timeout {
+            Some(timeout) => time::timeout(timeout, task).await.ok(),
+            None => Some(task.await),
+        };
+        let (outcome, detail) = match result {
+            None => {
+                abort.abort();
+                let timeout = format_duration(timeout.unwrap_or_default());
+                (Outcome::TimedOut, format!("timed out after {timeout}"))
+            }
+            Some(Ok(Ok(()))) => (Outcome::Succeeded, String::new()),
+            Some(Ok(Err(e))) => (Outcome::Failed, format!("failed: {e:#}")),
+            Some(Err(e)) if e.is_panic() => (Outcome::Panicked, "panicked".to_string()),
+            Some(Err(_)) => (Outcome::Cancelled, "cancelled".to_string()),
+        };
+        let _ = done.send(Done {
+            job: name,
+            id,
+            scheduled,
+            started,
+            outcome,
+            detail: Some(detail).filter(|detail| !detail.is_empty()),
+        });
+    });
+}
+
+fn finish(jobs: &mut BTreeMap<String, Job>, done: Done, context: &mut Context) {
+    let finished = Utc::now();
+    let duration_ms = (finished - done.started).num_milliseconds().max(0) as u64;
+    match (done.outcome, &done.detail) {
+        (Outcome::Succeeded, _) => debug!("job {} finished in {duration_ms} ms", done.job),
+        (Outcome::Cancelled, _) => info!("job {}: run cancelled", done.job),
+        (_, detail) => warn!("job {}: {}", done.job, detail.as_deref().unwrap_or("")),
+    }
+    let record = Record {
+        scheduled: done.scheduled,
+        started: Some(done.started),
+        duration_ms: Some(duration_ms),
+        outcome: done.outcome,
+        detail: done.detail,
+    };
+    context.record(&done.job, record);
+
+    // A cancelled run ends after its replacement started.
+    let Some(job) = jobs.get_mut(&done.job) else {
+        return;
+    };
+    if job.running.as_ref().map(|running| running.id) != Some(done.id) {
+        return;
+    }
+    job.running = None;
+    if let Schedule::Delay(_) = job.timing.schedule {
+        job.next = job.timing.schedule.first(finished);
+    }
+    if let Some(scheduled) = job.queue.pop_front() {
+        start(job, scheduled, context);
+    }
+}
+
+// Runs a command job. Its output is logged at debug level; a failure is
+// reported with the last line it wrote to stderr. The command is killed
+// when the run times out or is cancelled.
+fn command(argv: Vec<String>) -> Handler {
+    Arc::new(move |run: Run| {
+        let argv = argv.clone();
+        Box::pin(async move {
+            let (program, args) = argv.split_first().context("empty command")?;
+            let output = Command::new(program)
+                .args(args)
+                .env("PIS_JOB", &run.job)
+                .env("PIS_JOB_SCHEDULED", run.scheduled.to_rfc3339())
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output()
+                .await
+                .with_context(|| format!("cannot run {program}"))?;
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                debug!("{}: {line}", run.job);
+            }
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                match stderr.lines().rfind(|line| !line.trim().is_empty()) {
+                    Some(line) => bail!("{program} exited with {}: {line}", output.status),
+                    None => bail!("{program} exited with {}", output.status),
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+fn add(time: DateTime<Utc>, duration: Duration) -> Option<DateTime<Utc>> {
+    time.checked_add_signed(chrono::Duration::from_std(duration).ok()?)
+}
+
+// `500ms`, `30s`, `5m`, `2h` or `1d`.
+fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid duration {text:?}"))?;
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 3600 * 1000,
+        "d" => 24 * 3600 * 1000,
+        _ => bail!("invalid duration {text:?}, expected a unit of ms, s, m, h or d"),
+    };
+    if number == 0 {
+        bail!("duration must be greater than 0");
+    }
+    let millis = number
+        .checked_mul(millis)
+        .with_context(|| format!("duration {text:?} is too long"))?;
+    Ok(Duration::from_millis(millis))
+}
+
+// The largest unit `parse_duration` accepts that the duration is a whole
+// multiple of.
+fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    [
+        ("d", 24 * 3600 * 1000),
+        ("h", 3600 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+    ]
+    .into_iter()
+    .find(|(_, unit)| millis > 0 && millis % unit == 0)
+    .map_or_else(
+        || format!("{millis}ms"),
+        |(name, unit)| format!("{}{name}", millis / unit),
+    )
+}
@@ -0,0 +1,646 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use openssl::sha::sha256;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::cli::Args;
+use crate::config::{self, Config, ConfigWatch, Conflict};
+use crate::mqtt::Publication;
+use crate::state::{hex, write_atomic};
+use crate::supervisor::{Shutdown, Subsystem};
+
+pub const NAME: &str = "shadow";
+
+const FILE: &str = "shadow.json";
+
+// Sections that take effect when the configuration is reloaded. The others
+// are read at startup.
+const HOT_SECTIONS: [&str; 3] = ["ticker", "scheduler", "log"];
+
+// Maps whose entries are validated and reported as a whole rather than
+// field by field, since a new entry is only valid with all its fields.
+const ENTRIES: [&[&str]; 2] = [&["components"], &["scheduler", "jobs"]];
+
+// Reloads the configuration, with the given remote settings in place of the
+// stored ones.
+pub type Reload = Arc<dyn Fn(Option<&Value>) -> Result<()> + Send + Sync>;
+
+// Retained on shadow.desired_topic by the back office. `config` is the
+// complete remote configuration in the layout of the config file: settings
+// it no longer mentions, or sets to null, return to their local values.
+// Versions must increase; a document with a version that was already
+// applied is ignored, so the retained copy is not applied again on every
+// reconnect.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Desired {
+    version: u64,
+    config: Map<String, Value>,
+}
+
+// A remote setting, with the local value it replaced so that a later
+// local edit can be recognised.
+#[derive(Clone, Serialize, Deserialize)]
+struct Field {
+    path: Vec<String>,
+    value: Value,
+    local: Value,
+}
+
+impl Field {
+    fn key(&self) -> String {
+        self.path.join(".")
+    }
+}
+
+// A setting that was changed locally after it had been set remotely.
+#[derive(Clone, Serialize, Deserialize)]
+struct Collision {
+    local: Value,
+    remote: Value,
+    kept: Conflict,
+}
+
+// Contents of FILE, which also makes up most of the reported document.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Stored {
+    // The desired version in effect.
+    version: u64,
+    // Increases whenever the local configuration changes.
+    local_version: u64,
+    local_sha256: String,
+    fields: Vec<Field>,
+    // Remote settings that were not applied, by dotted path.
+    errors: BTreeMap<String, String>,
+    conflicts: BTreeMap<String, Collision>,
+    // Sections changed remotely that wait for a restart.
+    pending_restart: Vec<String>,
+    // Why the last desired document was rejected as a whole.
+    rejected: Option<String>,
+    updated_at: Option<DateTime<Utc>>,
+}
+
+impl Stored {
+    fn read(dir: &Path) -> Result<Option<Stored>> {
+        let path = dir.join(FILE);
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .with_context(|| format!("malformed {}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("cannot read {}", path.display())),
+        }
+    }
+
+    fn write(&self, dir: &Path) -> Result<()> {
+        write_atomic(&dir.join(FILE), &serde_json::to_vec_pretty(self)?, 0o644)
+    }
+
+    fn overlay(&self) -> Value {
+        overlay_of(&self.fields)
+    }
+}
+
+// Published retained on shadow.reported_topic after every change.
+#[derive(Serialize)]
+struct Reported<'a> {
+    version: u64,
+    local_version: u64,
+    // The remote settings in effect, by dotted path.
+    applied: BTreeMap<String, &'a Value>,
+    errors: &'a BTreeMap<String, String>,
+    conflicts: &'a BTreeMap<String, Collision>,
+    pending_restart: &'a [String],
+    restarting: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejected: Option<&'a str>,
+    timestamp: DateTime<Utc>,
+}
+
+// The remote settings stored in `dir`, as a tree to merge into the
+// configuration. A stored file that cannot be read is ignored.
+pub fn overlay(dir: &Path) -> Option<Value> {
+    match Stored::read(dir) {
+        Ok(stored) => stored.map(|stored| stored.overlay()),
+        Err(e) => {
+            warn!("ignoring remote configuration: {e:#}");
+            None
+        }
+    }
+}
+
+// The device shadow: receives desired documents, applies the settings that
+// pass validation one by one, stores them for `config::load` and reports
+// the outcome. Settings of hot sections take effect through a reload; the
+// service restarts for the others unless shadow.restart is off.
+pub struct Shadow {
+    args: Args,
+    config: ConfigWatch,
+    reload: Reload,
+    publications: broadcast::Sender<Publication>,
+    desired: tokio::sync::Mutex<mpsc::Receiver<Vec<u8>>>,
+    dir: PathBuf,
+    stored: Mutex<Stored>,
+}
+
+impl Shadow {
+    pub fn new(
+        args: Args,
+        config: ConfigWatch,
+        reload: Reload,
+        publications: broadcast::Sender<Publication>,
+        desired: mpsc::Receiver<Vec<u8>>,
+    ) -> Self {
+        let dir = config.borrow().service.state_dir.clone();
+        let stored = match Stored::read(&dir) {
+            Ok(stored) => stored.unwrap_or_default(),
+            Err(e) => {
+                warn!("starting without remote configuration: {e:#}");
+                Stored::default()
+            }
+        };
+        Shadow {
+            args,
+            config,
+            reload,
+            publications,
+            desired: tokio::sync::Mutex::new(desired),
+            dir,
+            stored: Mutex::new(stored),
+        }
+    }
+
+    // Applies a desired document. Returns whether the service has to
+    // restart.
+    fn apply(&self, payload: &[u8]) -> Result<bool> {
+        let mut stored = self.stored.lock().unwrap();
+        let desired: Desired = match serde_json::from_slice(payload) {
+            Ok(desired) => desired,
+            Err(e) => {
+                warn!("rejected desired configuration: {e}");
+                stored.rejected = Some(format!("malformed desired document: {e}"));
+                return self.finish(&mut stored, None);
+            }
+        };
+        if desired.version <= stored.version {
+            debug!(
+                "ignoring desired configuration version {}, version {} is in effect",
+                desired.version, stored.version
+            );
+            return Ok(false);
+        }
+        // Candidates are tried against this copy rather than the file.
+        let local = config::local_tree(&self.args).and_then(|tree| {
+            let local = config::resolve(&self.args, &tree, None)?;
+            Ok((tree, serde_json::to_value(local)?))
+        });
+        let (local_tree, local) = match local {
+            Ok(local) => local,
+            Err(e) => {
+                warn!("rejected desired configuration: {e:#}");
+                stored.rejected = Some(format!("local configuration is invalid: {e:#}"));
+                return self.finish(&mut stored, None);
+            }
+        };
+        info!("applying desired configuration version {}", desired.version);
+
+        let sections = self.config.borrow().shadow.sections.clone();
+        let mut errors = BTreeMap::new();
+        let mut candidates = Vec::new();
+        let mut fields = Vec::new();
+        flatten(&mut Vec::new(), Value::Object(desired.config), &mut fields);
+        for (path, value) in fields {
+            if value.is_null() {
+                continue;
+            }
+            if !sections.contains(&path[0]) {
+                errors.insert(path.join("."), "not configurable remotely".to_string());
+                continue;
+            }
+            candidates.push(Field {
+                local: at(&local, &path).cloned().unwrap_or(Value::Null),
+                path,
+                value,
+            });
+        }
+        let accepted = self.validate(&local_tree, candidates, &mut errors);
+
+        // Environment variables and command-line flags take precedence.
+        let tree = overlay_of(&accepted);
+        let effective =
+            serde_json::to_value(config::resolve(&self.args, &local_tree, Some(&tree))?)?;
+        let accepted: Vec<Field> = accepted
+            .into_iter()
+            .filter(|field| {
+                let applies = contains(at(&effective, &field.path), &field.value);
+                if !applies {
+                    errors.insert(
+                        field.key(),
+                        "overridden by an environment variable or command-line flag".to_string(),
+                    );
+                }
+                applies
+            })
+            .collect();
+        for (key, e) in &errors {
+            warn!("remote setting {key} not applied: {e}");
+        }
+
+        stored.version = desired.version;
+        stored.fields = accepted;
+        stored.errors = errors;
+        stored.conflicts.clear();
+        stored.rejected = None;
+        let before = self.config.borrow().clone();
+        self.finish(&mut stored, Some(&before))
+    }
+
+    // All fields at once when they make a valid configuration together,
+    // otherwise as many as possible, one at a time. Settings that depend on
+    // each other get another chance once the others are in.
+    fn validate(
+        &self,
+        local: &Value,
+        fields: Vec<Field>,
+        errors: &mut BTreeMap<String, String>,
+    ) -> Vec<Field> {
+        let valid =
+            |fields: &[Field]| config::resolve(&self.args, local, Some(&overlay_of(fields)));
+        if valid(&fields).is_ok() {
+            return fields;
+        }
+        let mut accepted = Vec::new();
+        let mut pending = fields;
+        loop {
+            let mut rejected = Vec::new();
+            let count = accepted.len();
+            for field in pending {
+                let mut candidate = accepted.clone();
+                candidate.push(field);
+                match valid(&candidate) {
+                    Ok(_) => accepted = candidate,
+                    Err(e) => rejected.push((candidate.pop().unwrap(), e)),
+                }
+            }
+            if rejected.is_empty() || accepted.len() == count {
+                for (field, e) in rejected {
+                    errors.insert(field.key(), format!("{e:#}"));
+                }
+                return accepted;
+            }
+            pending = rejected.into_iter().map(|(field, _)| field).collect();
+        }
+    }
+
+    // Looks for local edits of remotely set values, e.g. after SIGHUP
+    // reloaded a changed config file. Returns whether the service has to
+    // restart.
+    fn check_local(&self) -> Result<bool> {
+        let local = config::local_tree(&self.args)
+            .and_then(|tree| config::resolve(&self.args, &tree, None));
+        let local = match local {
+            Ok(local) => serde_json::to_value(local)?,
+            // Nothing to compare with; whoever loaded it reports why.
+            Err(_) => return Ok(false),
+        };
+        let digest = hex(&sha256(local.to_string().as_bytes()));
+        let mut stored = self.stored.lock().unwrap();
+        if stored.local_sha256 == digest {
+            return Ok(false);
+        }
+        stored.local_sha256 = digest;
+        stored.local_version += 1;
+        let policy = self.config.borrow().shadow.conflict;
+        let mut dropped = false;
+        let mut conflicts = BTreeMap::new();
+        stored.fields.retain_mut(|field| {
+            let now = at(&local, &field.path).cloned().unwrap_or(Value::Null);
+            if now == field.local {
+                return true;
+            }
+            let key = field.key();
+            let kept = match policy {
+                Conflict::Local => "local",
+                Conflict::Remote => "remote",
+            };
+            warn!("{key} was changed locally after it was set remotely, keeping the {kept} value");
+            conflicts.insert(
+                key,
+                Collision {
+                    local: now.clone(),
+                    remote: field.value.clone(),
+                    kept: policy,
+                },
+            );
+            match policy {
+                Conflict::Local => {
+                    dropped = true;
+                    false
+                }
+                Conflict::Remote => {
+                    field.local = now;
+                    true
+                }
+            }
+        });
+        stored.conflicts.extend(conflicts);
+        let before = self.config.borrow().clone();
+        self.finish(&mut stored, dropped.then_some(&*before))
+    }
+
+    // When the remote settings changed since `before`, reloads the
+    // configuration with them. Stores the outcome, publishes the reported
+    // document and returns whether the service has to restart.
+    fn finish(&self, stored: &mut Stored, before: Option<&Config>) -> Result<bool> {
+        stored.updated_at = Some(Utc::now());
+        let mut restart = false;
+        if let Some(before) = before {
+            match (self.reload)(Some(&stored.overlay())) {
+                Ok(()) => {
+                    let after = self.config.borrow().clone();
+                    let changed = restart_sections(before, &after)?;
+                    if !changed.is_empty() {
+                        restart = self.config.borrow().shadow.restart;
+                        match restart {
+                            true => info!("restarting to apply {}", changed.join(", ")),
+                            false => info!("{} change on the next restart", changed.join(", ")),
+                        }
+                    }
+                    for section in changed {
+                        if !stored.pending_restart.contains(&section) {
+                            stored.pending_restart.push(section);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("cannot apply remote configuration: {e:#}");
+                    stored.rejected = Some(format!("cannot apply: {e:#}"));
+                }
+            }
+        }
+        stored.write(&self.dir)?;
+        self.report(stored, restart);
+        Ok(restart)
+    }
+
+    fn report(&self, stored: &Stored, restarting: bool) {
+        let reported = Reported {
+            version: stored.version,
+            local_version: stored.local_version,
+            applied: stored
+                .fields
+                .iter()
+                .map(|field| (field.key(), &field.value))
+                .collect(),
+            errors: &stored.errors,
+            conflicts: &stored.conflicts,
+            pending_restart: &stored.pending_restart,
+            restarting,
+            rejected: stored.rejected.as_deref(),
+            timestamp: Utc::now(),
+        };
+        let Ok(payload) = serde_json::to_vec(&reported) else {
+            return;
+        };
+        let _ = self.publications.send(Publication {
+            topic: self.config.borrow().shadow.reported_topic.clone(),
+            payload,
+            retain: true,
+        });
+    }
+}
+
+#[async_trait]
+impl Subsystem for Shadow {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    // The reported document goes out through MQTT, which has to be
+    // listening before anything is published.
+    fn dependencies(&self) -> Vec<&'static str> {
+        vec!["mqtt"]
+    }
+
+    async fn start(&self, mut shutdown: Shutdown) -> Result<()> {
+        let mut config = self.config.clone();
+        config.borrow_and_update();
+        // Everything changed remotely so far is in effect after a start.
+        self.stored.lock().unwrap().pending_restart.clear();
+        let mut restart = self.check_local()?;
+        if !restart {
+            let stored = self.stored.lock().unwrap();
+            self.report(&stored, false);
+        }
+        let mut desired = self.desired.lock().await;
+        while !restart {
+            restart = tokio::select! {
+                payload = desired.recv() => match payload {
+                    Some(payload) => self.apply(&payload)?,
+                    None => return Ok(()),
+                },
+                Ok(()) = config.changed() => self.check_local()?,
+                _ = shutdown.wait() => return Ok(()),
+            };
+        }
+        shutdown.restart("remote configuration changed");
+        Ok(())
+    }
+}
+
+fn overlay_of(fields: &[Field]) -> Value {
+    let mut tree = Value::Object(Map::new());
+    for field in fields {
+        insert(&mut tree, &field.path, field.value.clone());
+    }
+    tree
+}
+
+// Splits a document into settings: leaves, and whole ENTRIES.
+fn flatten(path: &mut Vec<String>, value: Value, out: &mut Vec<(Vec<String>, Value)>) {
+    let entry = path.len() > 1 && ENTRIES.iter().any(|map| path[..path.len() - 1] == **map);
+    match value {
+        Value::Object(map) if !entry => {
+            for (key, value) in map {
+                path.push(key);
+                flatten(path, value, out);
+                path.pop();
+            }
+        }
+        value => out.push((path.clone(), value)),
+    }
+}
+
+fn at<'a>(tree: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(tree, |node, key| node.get(key))
+}
+
+fn insert(tree: &mut Value, path: &[String], value: Value) {
+    let mut node = tree;
+    for key in path {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        node = node
+            .as_object_mut()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert(Value::Null);
+    }
+    *node = value;
+}
+
+// Whether `effective` has `value`, or at least the fields of it that are
+// given, which is all a partial entry sets.
+fn contains(effective: Option<&Value>, value: &Value) -> bool {
+    match (effective, value) {
+        (Some(Value::Object(effective)), Value::Object(value)) => value
+            .iter()
+            .all(|(key, value)| contains(effective.get(key), value)),
+        (Some(effective), value) => effective == value,
+        (None, _) => false,
+    }
+}
+
+// Sections outside HOT_SECTIONS that differ between the two configurations.
+fn restart_sections(before: &Config, after: &Config) -> Result<Vec<String>> {
+    let (Value::Object(before), Value::Object(after)) =
+        (serde_json::to_value(before)?, serde_json::to_value(after)?)
+    else {
+        unreachable!("the configuration is a table");
+    };
+    Ok(after
+        .into_iter()
+        .filter(|(section, value)| {
+            !HOT_SECTIONS.contains(&section.as_str()) && before.get(section) != Some(value)
+        })
+        .map(|(section, _)| section)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::RunArgs;
+    use serde_json::json;
+    use tokio::sync::watch;
+
+    // A shadow over a config file with mqtt and the shadow enabled;
+    // `settings` continues the [shadow] table and may add others.
+    fn shadow_with(settings: &str) -> (Shadow, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("pis-shadow-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let file = format!(
+            "[service]\nstate_dir = \"{}\"\n[mqtt]\nenabled = true\n[shadow]\nenabled = true\n{settings}",
+            dir.display()
+        );
+        fs::write(&path, file).unwrap();
+        let args = Args {
+            config: Some(path),
+            run: RunArgs::default(),
+            command: None,
+        };
+        let (config_tx, config) = watch::channel(Arc::new(config::load(&args).unwrap()));
+        let reload: Reload = {
+            let args = args.clone();
+            Arc::new(move |remote| {
+                config_tx.send_replace(Arc::new(config::load_with(&args, remote)?));
+                Ok(())
+            })
+        };
+        let (publications, _) = broadcast::channel(16);
+        let (_, desired) = mpsc::channel(1);
+        let shadow = Shadow::new(args, config, reload, publications, desired);
+        (shadow, dir)
+    }
+
+    fn apply(shadow: &Shadow, document: Value) -> bool {
+        shadow.apply(document.to_string().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn partial_documents_are_merged_with_the_local_configuration() {
+        let (shadow, dir) = shadow_with("[ticker]\ninterval_ms = 1000\n[log]\nlevel = \"debug\"\n");
+        let desired = json!({"version": 1, "config": {"ticker": {"interval_ms": 500}}});
+        assert!(!apply(&shadow, desired));
+        assert_eq!(shadow.config.borrow().ticker.interval_ms, 500);
+        assert_eq!(shadow.config.borrow().log.level, "debug");
+
+        // What the next document leaves out returns to the local value.
+        let desired = json!({"version": 2, "config": {"log": {"level": "warn"}}});
+        assert!(!apply(&shadow, desired));
+        assert_eq!(shadow.config.borrow().ticker.interval_ms, 1000);
+        assert_eq!(shadow.config.borrow().log.level, "warn");
+
+        // An old version is ignored, and the settings survive a restart.
+        let desired = json!({"version": 2, "config": {"log": {"level": "error"}}});
+        assert!(!apply(&shadow, desired));
+        assert_eq!(shadow.config.borrow().log.level, "warn");
+        assert_eq!(overlay(&dir), Some(json!({"log": {"level": "warn"}})));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_values_are_rejected_alone() {
+        let (shadow, dir) = shadow_with("");
+        let desired = json!({
+            "version": 1,
+            "config": {"ticker": {"interval_ms": 500}, "watchdog": {"timeout_secs": 1}},
+        });
+        assert!(!apply(&shadow, desired));
+        assert_eq!(shadow.config.borrow().ticker.interval_ms, 500);
+        assert_eq!(shadow.config.borrow().watchdog.timeout_secs, 30);
+        let stored = shadow.stored.lock().unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(stored.fields.len(), 1);
+        assert!(stored.errors["watchdog.timeout_secs"].contains("at least 3"));
+        drop(stored);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sections_outside_shadow_sections_are_refused() {
+        let (shadow, dir) = shadow_with("sections = [\"ticker\"]\n");
+        let desired = json!({
+            "version": 1,
+            "config": {"ticker": {"interval_ms": 500}, "log": {"level": "debug"}},
+        });
+        assert!(!apply(&shadow, desired));
+        assert_eq!(shadow.config.borrow().ticker.interval_ms, 500);
+        assert_eq!(shadow.config.borrow().log.level, "info");
+        let stored = shadow.stored.lock().unwrap();
+        assert_eq!(stored.errors["log.level"], "not configurable remotely");
+        drop(stored);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_settings_read_at_startup_need_a_restart() {
+        let (shadow, dir) = shadow_with("");
+        let desired = json!({"version": 1, "config": {"ticker": {"interval_ms": 500}}});
+        assert!(!apply(&shadow, desired));
+        let desired = json!({"version": 2, "config": {"watchdog": {"timeout_secs": 60}}});
+        assert!(apply(&shadow, desired));
+        assert_eq!(shadow.stored.lock().unwrap().pending_restart, ["watchdog"]);
+        fs::remove_dir_all(dir).unwrap();
+
+        // With shadow.restart off the change waits for the next restart.
+        let (shadow, dir) = shadow_with("restart = false\n");
+        let desired = json!({"version": 1, "config": {"watchdog": {"timeout_secs": 60}}});
+        assert!(!apply(&shadow, desired));
+        assert_eq!(shadow.stored.lock().unwrap().pending_restart, ["watchdog"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
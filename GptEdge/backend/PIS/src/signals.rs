@@ -0,0 +1,42 @@
+use std::io;
+
+use tokio::signal::unix::{signal, Signal as UnixSignal, SignalKind};
+
+pub enum Signal {
+    Shutdown(&'static str),
+    Reload,
+}
+
+pub struct Signals {
+    term: UnixSignal,
+    int: UnixSignal,
+    hup: UnixSignal,
+}
+
+impl Signals {
+    // Installing the handlers replaces the default disposition, so this must
+    // happen before the service starts doing work.
+    pub fn new() -> io::Result<Self> {
+        Ok(Signals {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+            hup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.term.recv() => Signal::Shutdown("SIGTERM"),
+            _ = self.int.recv() => Signal::Shutdown("SIGINT"),
+            _ = self.hup.recv() => Signal::Reload,
+        }
+    }
+
+    pub async fn recv_shutdown(&mut self) -> &'static str {
+        loop {
+            if let Signal::Shutdown(name) = self.recv().await {
+                return name;
+            }
+        }
+    }
+}
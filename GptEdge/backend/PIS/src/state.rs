@@ -0,0 +1,289 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use openssl::sha::sha256;
+use serde::de::DeserializeOwned;
+use serde::ser::Serialize;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::config::ConfigWatch;
+use crate::supervisor::Shutdown;
+
+// The store alternates between two files. Each write replaces the one
+// holding the older generation, so the newest complete generation is never
+// touched while the next one is written.
+const SLOTS: [&str; 2] = ["state-0.json", "state-1.json"];
+
+// First line of a state file, followed by the JSON body it describes.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    generation: u64,
+    sha256: String,
+}
+
+struct Inner {
+    values: BTreeMap<String, Value>,
+    generation: u64,
+    dirty: bool,
+}
+
+// Small key-value store for what has to survive restarts and power loss,
+// such as counters and last-known data. Values change in memory and reach
+// the disk on `flush`, which the state subsystem calls periodically and once
+// more after shutdown. A file that fails its checksum is ignored in favour
+// of the previous generation.
+pub struct StateStore {
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+impl StateStore {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        let mut newest: Option<(u64, BTreeMap<String, Value>)> = None;
+        for slot in SLOTS {
+            let path = dir.join(slot);
+            match read(&path) {
+                Ok(Some((generation, values))) => {
+                    if newest.as_ref().map_or(true, |(g, _)| generation > *g) {
+                        newest = Some((generation, values));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("ignoring {}: {e:#}", path.display()),
+            }
+        }
+        let (generation, values) = newest.unwrap_or_default();
+        if generation > 0 {
+            info!("loaded state generation {generation}");
+        }
+        Ok(StateStore {
+            dir: dir.to_path_buf(),
+            inner: Mutex::new(Inner {
+                values,
+                generation,
+                dirty: false,
+            }),
+        })
+    }
+
+    // None when the key is missing or holds a value of another shape, e.g.
+    // after an upgrade changed it.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.inner.lock().unwrap().values.get(key)?.clone();
+        match serde_json::from_value(value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("ignoring stored {key}: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        let value = serde_json::to_value(value)?;
+        let mut inner = self.inner.lock().unwrap();
+        if inner.values.get(key) != Some(&value) {
+            inner.values.insert(key.to_string(), value);
+            inner.dirty = true;
+        }
+        Ok(())
+    }
+
+    // Writes the next generation if anything changed since the last flush.
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.dirty {
+            return Ok(());
+        }
+        let generation = inner.generation + 1;
+        let body = serde_json::to_vec(&inner.values)?;
+        let header = Header {
+            generation,
+            sha256: hex(&sha256(&body)),
+        };
+        let mut data = serde_json::to_vec(&header)?;
+        data.push(b'\n');
+        data.extend_from_slice(&body);
+
+        write_atomic(
+            &self.dir.join(SLOTS[(generation % 2) as usize]),
+            &data,
+            0o644,
+        )?;
+
+        inner.generation = generation;
+        inner.dirty = false;
+        Ok(())
+    }
+}
+
+// None when the file does not exist yet.
+fn read(path: &Path) -> Result<Option<(u64, BTreeMap<String, Value>)>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let split = data
+        .iter()
+        .position(|&b| b == b'\n')
+        .context("missing header")?;
+    let header: Header = serde_json::from_slice(&data[..split]).context("malformed header")?;
+    let body = &data[split + 1..];
+    if hex(&sha256(body)) != header.sha256 {
+        bail!("checksum mismatch in generation {}", header.generation);
+    }
+    let values = serde_json::from_slice(body).context("malformed body")?;
+    Ok(Some((header.generation, values)))
+}
+
+// Replaces the file so that a power cut leaves either the old or the new
+// contents, creating it with `mode`.
+pub fn write_atomic(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    // A leftover from an interrupted write could have other permissions.
+    let _ = fs::remove_file(&tmp);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&tmp)
+        .with_context(|| format!("cannot create {}", tmp.display()))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("cannot write {}", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02x}");
+        out
+    })
+}
+
+// Flushes the store every `state.flush_interval_secs`. The final flush
+// happens after all subsystems have stopped.
+pub async fn run(
+    config: ConfigWatch,
+    store: Arc<StateStore>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let period = config.borrow().state.flush_interval();
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => return Ok(()),
+        }
+        if let Err(e) = store.flush() {
+            error!("cannot save state: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pis-state-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn values_survive_reopening() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.set("ticks", &41u64).unwrap();
+        store.flush().unwrap();
+        store.set("ticks", &42u64).unwrap();
+        store.flush().unwrap();
+
+        let store = StateStore::open(&dir).unwrap();
+        assert_eq!(store.get::<u64>("ticks"), Some(42));
+        assert_eq!(store.inner.lock().unwrap().generation, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unchanged_values_are_not_written() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.set("ticks", &1u64).unwrap();
+        store.flush().unwrap();
+        store.set("ticks", &1u64).unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.inner.lock().unwrap().generation, 1);
+        assert!(!dir.join(SLOTS[0]).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_generation_falls_back_to_the_previous_one() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.set("ticks", &1u64).unwrap();
+        store.flush().unwrap();
+        store.set("ticks", &2u64).unwrap();
+        store.flush().unwrap();
+
+        // Generation 2 lives in the first slot; flip a byte of its body.
+        let path = dir.join(SLOTS[0]);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 2;
+        data[last] ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(read(&path)
+            .unwrap_err()
+            .to_string()
+            .contains("checksum mismatch"));
+
+        let store = StateStore::open(&dir).unwrap();
+        assert_eq!(store.get::<u64>("ticks"), Some(1));
+        // The next write replaces the corrupt file rather than the good one.
+        store.set("ticks", &3u64).unwrap();
+        store.flush().unwrap();
+        assert_eq!(read(&path).unwrap().unwrap().0, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_and_missing_files_are_handled() {
+        let dir = temp_dir();
+        assert!(read(&dir.join(SLOTS[0])).unwrap().is_none());
+        fs::write(dir.join(SLOTS[1]), b"{\"generation\":1").unwrap();
+        assert!(read(&dir.join(SLOTS[1])).is_err());
+
+        let store = StateStore::open(&dir).unwrap();
+        assert_eq!(store.get::<u64>("ticks"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn values_of_another_shape_are_ignored() {
+        let dir = temp_dir();
+        let store = StateStore::open(&dir).unwrap();
+        store.set("ticks", &"many").unwrap();
+        assert_eq!(store.get::<u64>("ticks"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde_derive::Serialize;
+use tokio::time::Instant as TickInstant;
+
+use crate::buildinfo::{self, Build, Image};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Starting,
+    Running,
+    // Up, but a dependency such as the broker connection is missing.
+    Degraded,
+    Restarting,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubsystemStatus {
+    pub state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub since: DateTime<Utc>,
+    pub restarts: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub version: &'static str,
+    pub profile: &'static str,
+    pub build: Build,
+    pub image: &'static Image,
+    pub started_at: DateTime<Utc>,
+    pub uptime_secs: u64,
+    pub ticks: u64,
+    pub ready: bool,
+    pub subsystems: BTreeMap<&'static str, SubsystemStatus>,
+}
+
+// Slack on top of two tick intervals before the tick loop counts as hung.
+const STALL_GRACE: Duration = Duration::from_secs(1);
+
+// What the service is doing right now, shared by the subsystems that update
+// it and the HTTP server that reports it.
+pub struct Status {
+    started: Instant,
+    started_at: DateTime<Utc>,
+    ticks: AtomicU64,
+    // A tokio instant, so that tests on paused time can stall the ticks.
+    last_tick: Mutex<TickInstant>,
+    all_started: AtomicBool,
+    subsystems: Mutex<BTreeMap<&'static str, SubsystemStatus>>,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Status {
+            started: Instant::now(),
+            started_at: Utc::now(),
+            ticks: AtomicU64::new(0),
+            last_tick: Mutex::new(TickInstant::now()),
+            all_started: AtomicBool::new(false),
+            subsystems: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set(&self, name: &'static str, state: State, detail: Option<String>) {
+        let mut subsystems = self.subsystems.lock().unwrap();
+        let entry = subsystems.entry(name).or_insert_with(|| SubsystemStatus {
+            state,
+            detail: None,
+            since: Utc::now(),
+            restarts: 0,
+        });
+        if entry.state != state {
+            entry.since = Utc::now();
+        }
+        if state == State::Restarting {
+            entry.restarts += 1;
+        }
+        entry.state = state;
+        entry.detail = detail;
+    }
+
+    // Set by the supervisor once it has started every subsystem, whether or
+    // not they are up yet.
+    pub fn set_all_started(&self) {
+        self.all_started.store(true, Ordering::Relaxed);
+    }
+
+    // Whether every subsystem has been started and is past its startup, such
+    // as the HTTP server binding its address. Waits on the network, such as
+    // the first broker connection, count as degraded rather than starting.
+    pub fn all_up(&self) -> bool {
+        self.all_started.load(Ordering::Relaxed)
+            && self
+                .subsystems
+                .lock()
+                .unwrap()
+                .values()
+                .all(|s| s.state != State::Starting)
+    }
+
+    pub fn state(&self, name: &str) -> Option<State> {
+        self.subsystems.lock().unwrap().get(name).map(|s| s.state)
+    }
+
+    pub fn set_ticks(&self, ticks: u64) {
+        self.ticks.store(ticks, Ordering::Relaxed);
+        *self.last_tick.lock().unwrap() = TickInstant::now();
+    }
+
+    // Time since the tick loop last made progress, or since startup before
+    // the first tick.
+    pub fn since_last_tick(&self) -> Duration {
+        self.last_tick.lock().unwrap().elapsed()
+    }
+
+    // How long the tick loop has been overdue when ticking every `interval`,
+    // or None while it keeps up.
+    pub fn tick_stall(&self, interval: Duration) -> Option<Duration> {
+        let since = self.since_last_tick();
+        since.checked_sub(interval * 2 + STALL_GRACE)
+    }
+
+    // Subsystems that failed and are waiting to be restarted.
+    pub fn failing(&self) -> Vec<&'static str> {
+        self.subsystems
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| s.state == State::Restarting)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    pub fn all_stopped_except(&self, except: &str) -> bool {
+        self.subsystems
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|(name, s)| *name == except || s.state == State::Stopped)
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    // Subsystems that are neither fully running nor finished.
+    pub fn not_ready(&self) -> Vec<&'static str> {
+        self.subsystems
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| !matches!(s.state, State::Running | State::Stopped))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.not_ready().is_empty()
+    }
+
+    pub fn report(&self) -> Report {
+        let build = buildinfo::build();
+        Report {
+            version: build.version,
+            profile: build.profile,
+            build,
+            image: buildinfo::image(),
+            started_at: self.started_at,
+            uptime_secs: self.uptime_secs(),
+            ticks: self.ticks.load(Ordering::Relaxed),
+            ready: self.is_ready(),
+            subsystems: self.subsystems.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,329 @@
+use std::ffi::CString;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use crate::buildinfo;
+use crate::config::{Config, LogBackend};
+use crate::error::Error;
+use crate::identity::{Credentials, IdentityStore, ProvisionError, Provisioning};
+use crate::mqtt;
+
+// For `status` and the self-test, which talk to a local instance.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn check_config(config: &Config, effective: bool) -> Result<(), Error> {
+    if effective {
+        print(toml::to_string(&config.redacted()).context("cannot serialize configuration")?);
+    }
+    print("configuration ok");
+    Ok(())
+}
+
+pub fn print_default_config(json: bool) -> Result<(), Error> {
+    let config = Config::default();
+    let text = match json {
+        true => serde_json::to_string_pretty(&config).context("cannot serialize configuration")?,
+        false => toml::to_string(&config).context("cannot serialize configuration")?,
+    };
+    print(text.trim_end());
+    Ok(())
+}
+
+pub fn version(json: bool) -> Result<(), Error> {
+    let info = buildinfo::info();
+    if json {
+        print(serde_json::to_string_pretty(&info).context("cannot print version")?);
+        return Ok(());
+    }
+    let build = &info.build;
+    print(format_args!(
+        "{} {} ({})",
+        build.name, build.version, build.profile
+    ));
+    print(format_args!("commit    {}", build.git_commit));
+    print(format_args!("built     {}", build.built_at.to_rfc3339()));
+    print(format_args!("target    {}", build.target));
+    let features = match build.features.is_empty() {
+        true => "none".to_string(),
+        false => build.features.join(", "),
+    };
+    print(format_args!("features  {features}"));
+    if let Some(os) = info.image.os() {
+        print(format_args!("os        {os}"));
+    }
+    let yocto = |key: &str| info.image.yocto.get(key).map(String::as_str);
+    if let Some(distro) = yocto("DISTRO") {
+        let version = yocto("DISTRO_VERSION").unwrap_or_default();
+        print(format_args!("distro    {distro} {version}"));
+    }
+    if let Some(image) = yocto("IMAGE_BASENAME") {
+        print(format_args!("image     {image}"));
+    }
+    if let Some(machine) = yocto("MACHINE") {
+        print(format_args!("machine   {machine}"));
+    }
+    Ok(())
+}
+
+pub async fn mqtt_publish(
+    config: &Config,
+    topic: &str,
+    qos: Option<i32>,
+    retain: bool,
+    message: &str,
+) -> Result<(), Error> {
+    let payload = match message {
+        "-" => {
+            let mut payload = Vec::new();
+            io::stdin()
+                .read_to_end(&mut payload)
+                .context("cannot read stdin")?;
+            payload
+        }
+        _ => message.as_bytes().to_vec(),
+    };
+    let qos = qos.unwrap_or(config.mqtt.qos);
+    let topic = mqtt::publish_once(config, topic, payload, qos, retain).await?;
+    print(format_args!("published to {topic}"));
+    Ok(())
+}
+
+// Prints the /status report of a running instance. Fails when the instance
+// cannot be reached or is not ready, so scripts can test the exit code.
+pub async fn status(config: &Config, address: Option<SocketAddr>) -> Result<(), Error> {
+    let address = address.unwrap_or_else(|| local_address(config.http.listen));
+    let (code, body) = http_get(address, "/status")
+        .await
+        .with_context(|| format!("cannot query PIS at {address}"))?;
+    if code != 200 {
+        return Err(anyhow!("PIS at {address} answered {code}").into());
+    }
+    let report: Value = serde_json::from_str(&body).context("malformed status report")?;
+    print(serde_json::to_string_pretty(&report).context("cannot print status report")?);
+    match report.get("ready").and_then(Value::as_bool) {
+        Some(true) => Ok(()),
+        _ => Err(anyhow!("PIS at {address} is not ready").into()),
+    }
+}
+
+pub fn identity(config: &Config) -> Result<(), Error> {
+    let store =
+        IdentityStore::open(&config.service.state_dir).context("cannot load device identity")?;
+    print(serde_json::to_string_pretty(&*store.current()).context("cannot print identity")?);
+    Ok(())
+}
+
+pub fn provision(
+    config: &Config,
+    fleet_id: String,
+    vehicle_id: String,
+    username: Option<String>,
+    password_file: Option<&Path>,
+    force: bool,
+) -> Result<(), Error> {
+    let password = match password_file {
+        Some(path) if path == Path::new("-") => {
+            let mut password = String::new();
+            io::stdin()
+                .read_to_string(&mut password)
+                .context("cannot read stdin")?;
+            Some(password)
+        }
+        Some(path) => Some(
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?,
+        ),
+        None => None,
+    };
+    // A trailing newline from `echo` or an editor is not part of it.
+    let password = password.map(|password| password.trim_end_matches(['\r', '\n']).to_string());
+    let store =
+        IdentityStore::open(&config.service.state_dir).context("cannot load device identity")?;
+    let identity = store
+        .provision(Provisioning {
+            fleet_id,
+            vehicle_id,
+            credentials: Credentials { username, password },
+            force,
+        })
+        .map_err(|e| match e {
+            ProvisionError::AlreadyProvisioned { .. } => anyhow!("{e}, pass --force to reassign"),
+            e => e.into(),
+        })?;
+    print(serde_json::to_string_pretty(&*identity).context("cannot print identity")?);
+    Ok(())
+}
+
+// Checks everything the configuration refers to and prints one line per
+// check. The configuration itself has already been validated by loading it.
+pub async fn self_test(config: &Config) -> Result<(), Error> {
+    let mut checks: Vec<(&str, Result<String>)> = vec![
+        ("configuration", Ok("valid".to_string())),
+        (
+            "state directory",
+            check_writable_dir(&config.service.state_dir),
+        ),
+        ("device identity", check_identity(config)),
+    ];
+    if config.mqtt.enabled {
+        let broker = mqtt::probe(config)
+            .await
+            .map(|()| format!("connected to {}", config.mqtt.uri))
+            .map_err(anyhow::Error::from);
+        checks.push(("mqtt broker", broker));
+    }
+    if config.http.enabled {
+        checks.push((
+            "http listen address",
+            check_listen(config.http.listen).await,
+        ));
+    }
+    if config.watchdog.enabled {
+        checks.push(("watchdog device", check_watchdog(&config.watchdog.device)));
+    }
+    checks.push(("log backend", check_log_backend(config)));
+
+    let mut failed = 0;
+    for (name, result) in &checks {
+        match result {
+            Ok(detail) => print(format_args!("ok    {name}: {detail}")),
+            Err(e) => {
+                print(format_args!("FAIL  {name}: {e:#}"));
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(anyhow!("{failed} of {} checks failed", checks.len()).into()),
+    }
+}
+
+fn check_writable_dir(dir: &Path) -> Result<String> {
+    fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let probe = dir.join(".self-test");
+    fs::write(&probe, b"ok").with_context(|| format!("cannot write to {}", dir.display()))?;
+    fs::remove_file(&probe)?;
+    Ok(format!("{} is writable", dir.display()))
+}
+
+fn check_identity(config: &Config) -> Result<String> {
+    let identity = IdentityStore::open(&config.service.state_dir)?.current();
+    Ok(match (&identity.fleet_id, &identity.vehicle_id) {
+        (Some(fleet_id), Some(vehicle_id)) => format!(
+            "device {}, fleet {fleet_id}, vehicle {vehicle_id}",
+            identity.device_id
+        ),
+        _ => format!("device {}, not provisioned", identity.device_id),
+    })
+}
+
+// The address is normally held by the running service, in which case its
+// health check has to answer.
+async fn check_listen(listen: SocketAddr) -> Result<String> {
+    match TcpListener::bind(listen) {
+        Ok(_) => Ok(format!("{listen} is free")),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            let address = local_address(listen);
+            match http_get(address, "/healthz").await {
+                Ok((200, _)) => Ok(format!("{listen} is served by a running instance")),
+                _ => bail!("{listen} is in use by another program"),
+            }
+        }
+        Err(e) => Err(e).with_context(|| format!("cannot bind {listen}")),
+    }
+}
+
+// Opening the device would arm the watchdog, so only the access rights of
+// this user are checked.
+fn check_watchdog(device: &Path) -> Result<String> {
+    fs::metadata(device).with_context(|| format!("cannot access {}", device.display()))?;
+    let path = CString::new(device.as_os_str().as_bytes())?;
+    // SAFETY: `path` is a NUL-terminated string that outlives the call, and
+    // access(2) only reads it.
+    if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("{} is not writable", device.display()));
+    }
+    Ok(format!("{} present and writable", device.display()))
+}
+
+fn check_log_backend(config: &Config) -> Result<String> {
+    let log = &config.log;
+    match log.backend {
+        LogBackend::Stderr => Ok("stderr".to_string()),
+        LogBackend::Journald => {
+            let socket = Path::new("/run/systemd/journal/socket");
+            fs::metadata(socket).context("systemd journal socket not found")?;
+            Ok("journald".to_string())
+        }
+        LogBackend::Syslog => {
+            fs::metadata(&log.syslog_socket)
+                .with_context(|| format!("{} not found", log.syslog_socket.display()))?;
+            Ok(format!("syslog at {}", log.syslog_socket.display()))
+        }
+        LogBackend::File => {
+            let dir = log.file.path.parent().unwrap_or(Path::new("."));
+            check_writable_dir(dir)?;
+            Ok(format!("file {}", log.file.path.display()))
+        }
+    }
+}
+
+// Output goes to stdout; a closed pipe, as with `| head`, is not an error.
+fn print(text: impl Display) {
+    let _ = writeln!(io::stdout().lock(), "{text}");
+}
+
+// A service listening on all interfaces is reached over loopback.
+fn local_address(listen: SocketAddr) -> SocketAddr {
+    match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listen.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), listen.port())
+        }
+        _ => listen,
+    }
+}
+
+// Minimal HTTP/1.1 GET, enough for the local API which always sends a
+// Content-Length and closes the connection when asked to.
+async fn http_get(address: SocketAddr, path: &str) -> Result<(u16, String)> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(address).await?;
+        stream
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        anyhow::Ok(response)
+    };
+    let response = time::timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .context("timed out")??;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("malformed HTTP response")?;
+    let code = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .context("malformed HTTP status line")?;
+    Ok((code, body.to_string()))
+}
@@ -0,0 +1,760 @@
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::config::ConfigWatch;
+use crate::error::Error;
+use crate::signals::{Signal, Signals};
+use crate::status::{State, Status};
+
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+// How often `Subsystem::health` is asked, and how long it may take.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+// How long a subsystem waits for its dependencies to be running before it
+// starts anyway.
+const DEPENDENCY_WAIT: Duration = Duration::from_secs(30);
+
+// Why the service is asked to restart, if it is.
+type RestartReason = Arc<Mutex<Option<String>>>;
+
+// Handed to every subsystem so it can notice when it has to stop, or ask
+// for the whole service to stop or restart. Subsystems are told to stop one
+// after the other, so a subsystem may still be running when the service is
+// stopping.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+    restart: RestartReason,
+}
+
+impl Shutdown {
+    fn new(tx: &Arc<watch::Sender<bool>>, restart: &RestartReason) -> Self {
+        Shutdown {
+            tx: tx.clone(),
+            rx: tx.subscribe(),
+            restart: restart.clone(),
+        }
+    }
+
+    // Observes `stop` instead of the service-wide signal.
+    fn with_stop(
+        tx: &Arc<watch::Sender<bool>>,
+        restart: &RestartReason,
+        stop: &watch::Sender<bool>,
+    ) -> Self {
+        Shutdown {
+            tx: tx.clone(),
+            rx: stop.subscribe(),
+            restart: restart.clone(),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    // Stops the service like `trigger`, but exits with EXIT_RESTART so that
+    // the service manager starts it again, even with Restart=on-failure.
+    pub fn restart(&self, reason: &str) {
+        self.restart
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| reason.to_string());
+        self.trigger();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    pub async fn wait(&mut self) {
+        // An error means the supervisor is gone, which is a shutdown as well.
+        let _ = self.rx.wait_for(|stopping| *stopping).await;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    // Working, but not fully, e.g. without its upstream. Reported in the
+    // status until it is healthy again.
+    Degraded(String),
+    // Beyond repair: the subsystem is stopped and restarted.
+    Failed(String),
+}
+
+// A long-running part of the service. `start` runs until the shutdown it is
+// given fires, and is called again with a backoff whenever it fails or
+// panics. Subsystems start after the ones they depend on are running and
+// are stopped before them.
+#[async_trait]
+pub trait Subsystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn dependencies(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    async fn start(&self, shutdown: Shutdown) -> Result<()>;
+
+    // Asked periodically while the subsystem runs.
+    async fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    // Whether `start` sets the subsystem Running in the status itself once it
+    // is up, e.g. after binding a socket. Otherwise it counts as running as
+    // soon as it is started.
+    fn reports_running(&self) -> bool {
+        false
+    }
+
+    // Called when the subsystem is told to stop, next to its shutdown
+    // firing, for work that cannot wait for `start` to notice.
+    async fn stop(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+type Factory = Box<dyn Fn(Shutdown) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+type ReloadFn = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+// A subsystem that is nothing but its `start`.
+struct FnSubsystem {
+    name: &'static str,
+    factory: Factory,
+    reports_running: bool,
+}
+
+#[async_trait]
+impl Subsystem for FnSubsystem {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn start(&self, shutdown: Shutdown) -> Result<()> {
+        (self.factory)(shutdown).await
+    }
+
+    fn reports_running(&self) -> bool {
+        self.reports_running
+    }
+}
+
+pub struct Supervisor {
+    subsystems: Vec<Arc<dyn Subsystem>>,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    restart: RestartReason,
+    config: ConfigWatch,
+    status: Arc<Status>,
+    reload: Option<ReloadFn>,
+}
+
+impl Supervisor {
+    pub fn new(config: ConfigWatch, status: Arc<Status>) -> Self {
+        let (shutdown_tx, _) = watch::channel(false);
+        Supervisor {
+            subsystems: Vec::new(),
+            shutdown_tx: Arc::new(shutdown_tx),
+            restart: RestartReason::default(),
+            config,
+            status,
+            reload: None,
+        }
+    }
+
+    // Called on SIGHUP. Errors are logged and the service keeps running with
+    // its current configuration.
+    pub fn on_reload<F>(&mut self, reload: F)
+    where
+        F: Fn() -> Result<()> + Send + Sync + 'static,
+    {
+        self.reload = Some(Box::new(reload));
+    }
+
+    // Registers a subsystem without dependencies that is only a `start`.
+    // The factory is called again every time the subsystem has to be
+    // restarted after a failure.
+    pub fn add<F, Fut>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.add_fn(name, factory, false);
+    }
+
+    // Like `add`, for a subsystem that sets itself Running once it is up,
+    // see `Subsystem::reports_running`.
+    pub fn add_reporting<F, Fut>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.add_fn(name, factory, true);
+    }
+
+    fn add_fn<F, Fut>(&mut self, name: &'static str, factory: F, reports_running: bool)
+    where
+        F: Fn(Shutdown) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.subsystems.push(Arc::new(FnSubsystem {
+            name,
+            factory: Box::new(move |shutdown| Box::pin(factory(shutdown))),
+            reports_running,
+        }));
+    }
+
+    pub fn add_subsystem(&mut self, subsystem: Arc<dyn Subsystem>) {
+        self.subsystems.push(subsystem);
+    }
+
+    // Lets code outside the subsystems stop the service.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown::new(&self.shutdown_tx, &self.restart)
+    }
+
+    // Runs until SIGTERM/SIGINT, a subsystem asks for shutdown or a restart,
+    // or a subsystem fails with a fatal error, which is then returned; a
+    // restart is returned as `Error::Restart`. Subsystems are stopped once
+    // everything depending on them has stopped, and get
+    // service.shutdown_timeout_ms in total to finish in-flight work before they
+    // are abandoned; a second SIGTERM/SIGINT abandons them immediately.
+    pub async fn run(self) -> Result<(), Error> {
+        let order = start_order(&self.subsystems).map_err(Error::Config)?;
+        let mut signals = Signals::new().map_err(anyhow::Error::from)?;
+        // Every subsystem is known to the status before any runs, so that
+        // none looks up merely because it has not been seen yet.
+        for subsystem in &order {
+            self.status.set(subsystem.name(), State::Starting, None);
+        }
+        let mut running = Vec::new();
+        for subsystem in order {
+            let name = subsystem.name();
+            info!(subsystem = name; "starting {name}");
+            let (stop_tx, _) = watch::channel(false);
+            let shutdown = Shutdown::with_stop(&self.shutdown_tx, &self.restart, &stop_tx);
+            let status = self.status.clone();
+            let handle = tokio::spawn(supervise(subsystem.clone(), status, shutdown));
+            running.push((subsystem, stop_tx, handle));
+        }
+        self.status.set_all_started();
+
+        let mut shutdown = self.shutdown_handle();
+        loop {
+            tokio::select! {
+                signal = signals.recv() => match signal {
+                    Signal::Reload => match &self.reload {
+                        Some(reload) => match reload() {
+                            Ok(()) => info!("configuration reloaded"),
+                            Err(e) => error!(
+                                "reload failed, keeping current configuration: {e:#}"
+                            ),
+                        },
+                        None => warn!("ignoring SIGHUP, reload is not supported"),
+                    },
+                    Signal::Shutdown(name) => {
+                        info!("received {name}, shutting down");
+                        break;
+                    }
+                },
+                _ = shutdown.wait() => {
+                    info!("shutting down");
+                    break;
+                }
+            }
+        }
+        shutdown.trigger();
+
+        let deadline = self.config.borrow().service.shutdown_timeout();
+        let aborts: Vec<_> = running
+            .iter()
+            .map(|(subsystem, _, handle)| (subsystem.name(), handle.abort_handle()))
+            .collect();
+        let mut fatal = None;
+        let drain = stop_all(running, &mut fatal);
+        tokio::select! {
+            _ = time::timeout(deadline, drain) => {}
+            name = signals.recv_shutdown() => warn!("received {name} again, not waiting"),
+        }
+
+        for (name, abort) in aborts {
+            if !abort.is_finished() {
+                warn!(subsystem = name; "{name} did not stop in time, abandoning it");
+                abort.abort();
+            }
+        }
+        info!("all subsystems stopped");
+        let restart = self.restart.lock().unwrap().take();
+        match (fatal, restart) {
+            (Some(error), _) => Err(error),
+            (None, Some(reason)) => Err(Error::Restart(reason)),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+// Aborts the task when dropped, so that it cannot outlive whoever waits for
+// it.
+pub struct AbortOnDrop<T>(pub JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+type Running = (
+    Arc<dyn Subsystem>,
+    watch::Sender<bool>,
+    JoinHandle<Option<Error>>,
+);
+
+// Registration order, except that dependencies come first.
+fn start_order(subsystems: &[Arc<dyn Subsystem>]) -> anyhow::Result<Vec<Arc<dyn Subsystem>>> {
+    let names: BTreeSet<&str> = subsystems.iter().map(|s| s.name()).collect();
+    if names.len() != subsystems.len() {
+        return Err(anyhow!("subsystem names must be unique"));
+    }
+    for subsystem in subsystems {
+        for dependency in subsystem.dependencies() {
+            if !names.contains(dependency) {
+                return Err(anyhow!(
+                    "{} depends on {dependency}, which does not exist or is disabled",
+                    subsystem.name()
+                ));
+            }
+        }
+    }
+    let mut order: Vec<Arc<dyn Subsystem>> = Vec::new();
+    let mut started = BTreeSet::new();
+    while order.len() < subsystems.len() {
+        let next = subsystems.iter().find(|subsystem| {
+            !started.contains(subsystem.name())
+                && subsystem
+                    .dependencies()
+                    .iter()
+                    .all(|dependency| started.contains(dependency))
+        });
+        match next {
+            Some(subsystem) => {
+                started.insert(subsystem.name());
+                order.push(subsystem.clone());
+            }
+            None => {
+                let cycle: Vec<_> = subsystems
+                    .iter()
+                    .map(|s| s.name())
+                    .filter(|name| !started.contains(name))
+                    .collect();
+                return Err(anyhow!("dependency cycle between {}", cycle.join(", ")));
+            }
+        }
+    }
+    Ok(order)
+}
+
+// Stops every subsystem once all subsystems depending on it have stopped,
+// keeping the first fatal error.
+async fn stop_all(running: Vec<Running>, fatal: &mut Option<Error>) {
+    let mut dependents: BTreeMap<&'static str, Vec<&'static str>> = BTreeMap::new();
+    for (subsystem, _, _) in &running {
+        for dependency in subsystem.dependencies() {
+            dependents
+                .entry(dependency)
+                .or_default()
+                .push(subsystem.name());
+        }
+    }
+    let mut waiting = running;
+    let mut stopped = BTreeSet::new();
+    let mut pending = FuturesUnordered::new();
+    loop {
+        let (ready, rest): (Vec<_>, Vec<_>) = waiting.into_iter().partition(|(subsystem, _, _)| {
+            dependents.get(subsystem.name()).map_or(true, |names| {
+                names.iter().all(|name| stopped.contains(name))
+            })
+        });
+        waiting = rest;
+        for (subsystem, stop_tx, handle) in ready {
+            let name = subsystem.name();
+            stop_tx.send_replace(true);
+            pending.push(async move {
+                match AssertUnwindSafe(subsystem.stop()).catch_unwind().await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!(subsystem = name; "cannot stop {name}: {e:#}"),
+                    Err(_) => error!(subsystem = name; "{name} panicked while stopping"),
+                }
+                (name, handle.await)
+            });
+        }
+        let Some((name, result)) = pending.next().await else {
+            return;
+        };
+        stopped.insert(name);
+        match result {
+            Ok(Some(error)) => *fatal = fatal.take().or(Some(error)),
+            Ok(None) => {}
+            Err(_) => error!(subsystem = name; "{name} supervisor aborted"),
+        }
+    }
+}
+
+// Waits until the dependencies are running, degraded ones included, or gives
+// up waiting after DEPENDENCY_WAIT. Returns false if the subsystem was told
+// to stop meanwhile.
+async fn await_dependencies(
+    subsystem: &dyn Subsystem,
+    status: &Status,
+    shutdown: &mut Shutdown,
+) -> bool {
+    let name = subsystem.name();
+    let dependencies = subsystem.dependencies();
+    let deadline = Instant::now() + DEPENDENCY_WAIT;
+    loop {
+        let waiting: Vec<_> = dependencies
+            .iter()
+            .filter(|dependency| {
+                !matches!(
+                    status.state(dependency),
+                    Some(State::Running | State::Degraded)
+                )
+            })
+            .copied()
+            .collect();
+        if waiting.is_empty() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            warn!(subsystem = name; "starting {name} without {}", waiting.join(", "));
+            return true;
+        }
+        status.set(
+            name,
+            State::Starting,
+            Some(format!("waiting for {}", waiting.join(", "))),
+        );
+        tokio::select! {
+            _ = time::sleep(Duration::from_millis(100)) => {}
+            _ = shutdown.wait() => return false,
+        }
+    }
+}
+
+// Runs the subsystem until it finishes, checking its health meanwhile. A
+// failed health check aborts it like an error would.
+async fn run_once(
+    subsystem: &Arc<dyn Subsystem>,
+    status: &Status,
+    shutdown: Shutdown,
+) -> Result<Result<()>, tokio::task::JoinError> {
+    let name = subsystem.name();
+    // Abandoning the supervisor at the shutdown deadline drops this future,
+    // which must take the subsystem down with it.
+    let mut task = AbortOnDrop(tokio::spawn({
+        let subsystem = subsystem.clone();
+        async move { subsystem.start(shutdown).await }
+    }));
+    let mut checks = time::interval_at(time::Instant::now() + HEALTH_INTERVAL, HEALTH_INTERVAL);
+    let mut degraded = false;
+    loop {
+        tokio::select! {
+            result = &mut task.0 => return result,
+            _ = checks.tick() => {}
+        }
+        let health = AssertUnwindSafe(time::timeout(HEALTH_INTERVAL, subsystem.health()))
+            .catch_unwind()
+            .await;
+        let health = match health {
+            Ok(Ok(health)) => health,
+            Ok(Err(_)) => Health::Failed("health check timed out".to_string()),
+            Err(_) => Health::Failed("health check panicked".to_string()),
+        };
+        match health {
+            Health::Healthy if degraded => {
+                info!(subsystem = name; "{name} is healthy again");
+                status.set(name, State::Running, None);
+                degraded = false;
+            }
+            Health::Healthy => {}
+            Health::Degraded(reason) => {
+                if !degraded {
+                    warn!(subsystem = name; "{name} is degraded: {reason}");
+                }
+                status.set(name, State::Degraded, Some(reason));
+                degraded = true;
+            }
+            Health::Failed(reason) => {
+                task.0.abort();
+                let _ = (&mut task.0).await;
+                return Ok(Err(anyhow!("unhealthy: {reason}")));
+            }
+        }
+    }
+}
+
+// Subsystems count as running from the moment they are started; those with
+// a more precise idea, such as a broker connection, report it themselves.
+async fn supervise(
+    subsystem: Arc<dyn Subsystem>,
+    status: Arc<Status>,
+    mut shutdown: Shutdown,
+) -> Option<Error> {
+    let name = subsystem.name();
+    if !await_dependencies(&*subsystem, &status, &mut shutdown).await {
+        status.set(name, State::Stopped, None);
+        return None;
+    }
+    let mut backoff = RESTART_BACKOFF_MIN;
+    loop {
+        let started = Instant::now();
+        let state = match subsystem.reports_running() {
+            true => State::Starting,
+            false => State::Running,
+        };
+        status.set(name, state, None);
+        let failure = match run_once(&subsystem, &status, shutdown.clone()).await {
+            Ok(Ok(())) => {
+                info!(subsystem = name; "{name} stopped");
+                status.set(name, State::Stopped, None);
+                return None;
+            }
+            Ok(Err(e)) => match e.downcast::<Error>() {
+                Ok(error) if error.is_fatal() => {
+                    error!(subsystem = name; "{name} failed fatally: {error}");
+                    status.set(name, State::Stopped, Some(error.to_string()));
+                    shutdown.trigger();
+                    return Some(error);
+                }
+                Ok(error) => error.to_string(),
+                Err(e) => format!("{e:#}"),
+            },
+            Err(e) if e.is_panic() => panic_message(e.into_panic()),
+            Err(e) => format!("cancelled: {e}"),
+        };
+        error!(subsystem = name; "{name} failed: {failure}");
+        if shutdown.is_triggered() {
+            status.set(name, State::Stopped, Some(failure));
+            return None;
+        }
+        if started.elapsed() > RESTART_BACKOFF_MAX {
+            backoff = RESTART_BACKOFF_MIN;
+        }
+        info!(subsystem = name, backoff_ms = backoff.as_millis() as u64; "restarting {name} in {backoff:?}");
+        status.set(name, State::Restarting, Some(failure));
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.wait() => {
+                status.set(name, State::Stopped, None);
+                return None;
+            }
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned());
+    match message {
+        Some(message) => format!("panicked: {message}"),
+        None => "panicked".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    enum Behaviour {
+        // Runs until told to stop.
+        Run,
+        Fail,
+        // Ignores being told to stop.
+        Hang,
+    }
+
+    struct Fake {
+        name: &'static str,
+        dependencies: Vec<&'static str>,
+        behaviour: Behaviour,
+        starts: Mutex<Vec<time::Instant>>,
+    }
+
+    impl Fake {
+        fn new(
+            name: &'static str,
+            dependencies: &[&'static str],
+            behaviour: Behaviour,
+        ) -> Arc<Self> {
+            Arc::new(Fake {
+                name,
+                dependencies: dependencies.to_vec(),
+                behaviour,
+                starts: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl Subsystem for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn dependencies(&self) -> Vec<&'static str> {
+            self.dependencies.clone()
+        }
+
+        async fn start(&self, mut shutdown: Shutdown) -> Result<()> {
+            self.starts.lock().unwrap().push(time::Instant::now());
+            match self.behaviour {
+                Behaviour::Run => shutdown.wait().await,
+                Behaviour::Fail => return Err(anyhow!("broken")),
+                Behaviour::Hang => std::future::pending().await,
+            }
+            Ok(())
+        }
+    }
+
+    fn supervisor(shutdown_timeout_ms: u64) -> Supervisor {
+        let mut config = Config::default();
+        config.service.shutdown_timeout_ms = shutdown_timeout_ms;
+        let (_, config) = watch::channel(Arc::new(config));
+        Supervisor::new(config, Arc::new(Status::new()))
+    }
+
+    #[test]
+    fn dependencies_start_first() {
+        let subsystems: Vec<Arc<dyn Subsystem>> = vec![
+            Fake::new("http", &["mqtt", "state"], Behaviour::Run),
+            Fake::new("mqtt", &["state"], Behaviour::Run),
+            Fake::new("ticker", &[], Behaviour::Run),
+            Fake::new("state", &[], Behaviour::Run),
+        ];
+        let order: Vec<_> = start_order(&subsystems)
+            .unwrap()
+            .iter()
+            .map(|s| s.name())
+            .collect();
+        assert_eq!(order, ["ticker", "state", "mqtt", "http"]);
+
+        let missing: Vec<Arc<dyn Subsystem>> = vec![Fake::new("mqtt", &["state"], Behaviour::Run)];
+        assert!(start_order(&missing)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("depends on state"));
+        let cycle: Vec<Arc<dyn Subsystem>> = vec![
+            Fake::new("a", &["b"], Behaviour::Run),
+            Fake::new("b", &["a"], Behaviour::Run),
+        ];
+        assert!(start_order(&cycle)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("dependency cycle between a, b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_are_restarted_with_a_growing_delay() {
+        let supervisor = supervisor(10_000);
+        let fake = Fake::new("flaky", &[], Behaviour::Fail);
+        let status = supervisor.status.clone();
+        let task = tokio::spawn(supervise(
+            fake.clone(),
+            status.clone(),
+            supervisor.shutdown_handle(),
+        ));
+        time::sleep(Duration::from_secs(45)).await;
+        supervisor.shutdown_handle().trigger();
+        assert!(task.await.unwrap().is_none());
+
+        let starts = fake.starts.lock().unwrap();
+        let delays: Vec<_> = starts
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16]);
+        let report = status.report();
+        assert_eq!(report.subsystems["flaky"].state, State::Stopped);
+        assert_eq!(report.subsystems["flaky"].restarts, 6);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_abandons_hung_subsystems_at_the_deadline() {
+        let mut supervisor = supervisor(5_000);
+        supervisor.add_subsystem(Fake::new("stuck", &[], Behaviour::Hang));
+        supervisor.add_subsystem(Fake::new("worker", &["stuck"], Behaviour::Run));
+        let shutdown = supervisor.shutdown_handle();
+        let status = supervisor.status.clone();
+        let started = time::Instant::now();
+        let run = tokio::spawn(supervisor.run());
+        time::sleep(Duration::from_secs(1)).await;
+        shutdown.trigger();
+        assert!(run.await.unwrap().is_ok());
+        assert_eq!(started.elapsed(), Duration::from_secs(6));
+        assert_eq!(status.state("worker"), Some(State::Stopped));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subsystems_that_report_running_hold_back_readiness() {
+        let mut supervisor = supervisor(5_000);
+        let status = supervisor.status.clone();
+        let (up_tx, up) = watch::channel(false);
+        supervisor.add_subsystem(Fake::new("worker", &[], Behaviour::Run));
+        supervisor.add_reporting("server", move |mut shutdown| {
+            let (status, mut up) = (status.clone(), up.clone());
+            async move {
+                up.wait_for(|up| *up).await?;
+                status.set("server", State::Running, None);
+                shutdown.wait().await;
+                Ok(())
+            }
+        });
+        let status = supervisor.status.clone();
+        let shutdown = supervisor.shutdown_handle();
+        let run = tokio::spawn(supervisor.run());
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(status.state("worker"), Some(State::Running));
+        assert_eq!(status.state("server"), Some(State::Starting));
+        assert!(!status.all_up());
+        up_tx.send_replace(true);
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(status.all_up());
+        shutdown.trigger();
+        assert!(run.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_are_returned_once_everything_stopped() {
+        let mut supervisor = supervisor(5_000);
+        supervisor.add_subsystem(Fake::new("worker", &[], Behaviour::Run));
+        supervisor.add("restarter", |shutdown| async move {
+            shutdown.restart("asked to");
+            Ok(())
+        });
+        match supervisor.run().await {
+            Err(Error::Restart(reason)) => assert_eq!(reason, "asked to"),
+            other => panic!("expected a restart, got {other:?}"),
+        }
+    }
+}
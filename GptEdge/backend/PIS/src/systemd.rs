@@ -0,0 +1,327 @@
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::config::ConfigWatch;
+use crate::error::Error;
+use crate::status::{State, Status};
+use crate::supervisor::Shutdown;
+
+// How often readiness and STATUS= are re-evaluated when the watchdog does
+// not ask for more frequent pings.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Talks to systemd over the socket named by $NOTIFY_SOCKET, see
+// sd_notify(3). Works with any datagram socket, so a test can point
+// NOTIFY_SOCKET at its own.
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    // None when not started by systemd with Type=notify.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        let path = path.to_string_lossy();
+        // A leading '@' names a socket in the abstract namespace.
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&*path),
+        }
+        .with_context(|| format!("invalid NOTIFY_SOCKET {path}"))?;
+        Ok(Some(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        }))
+    }
+
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+// The interval systemd expects WATCHDOG=1 at, from WatchdogSec= in the unit.
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+// Reports readiness once every subsystem is up, keeps
+// STATUS= current and, when the unit has WatchdogSec= set, pings the
+// watchdog for as long as the tick loop makes progress. Once the pings have
+// been held back for half the watchdog period the service stops with
+// EXIT_WATCHDOG_TIMEOUT, before systemd would resort to SIGABRT at the full
+// period. Readiness does not wait for the broker connection, or a unit that
+// boots without network would be killed at TimeoutStartSec over and over;
+// STATUS= and /readyz report the connection instead.
+pub async fn run(
+    config: ConfigWatch,
+    notifier: Arc<Notifier>,
+    status: Arc<Status>,
+    shutdown: Shutdown,
+) -> Result<()> {
+    notify(config, &notifier, &status, shutdown, watchdog_timeout()).await
+}
+
+async fn notify(
+    config: ConfigWatch,
+    notifier: &Notifier,
+    status: &Status,
+    mut shutdown: Shutdown,
+    watchdog: Option<Duration>,
+) -> Result<()> {
+    // Checked four times per watchdog period, so that a stall is noticed
+    // well before systemd gives up.
+    let period = watchdog.map_or(POLL_INTERVAL, |timeout| (timeout / 4).min(POLL_INTERVAL));
+    let mut interval = time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    if let Some(timeout) = watchdog {
+        info!("systemd watchdog enabled, timeout {timeout:?}");
+    }
+
+    let mut ready = false;
+    let mut last_summary = String::new();
+    let mut stalled = false;
+    let mut last_ping = Instant::now();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => {
+                notifier.notify("STOPPING=1\nSTATUS=shutting down")?;
+                return Ok(());
+            }
+        }
+
+        let summary = summary(status);
+        if summary != last_summary {
+            notifier.notify(&format!("STATUS={summary}"))?;
+            last_summary = summary;
+        }
+        if !ready && status.all_up() {
+            notifier.notify("READY=1")?;
+            info!("notified systemd of readiness");
+            ready = true;
+        }
+
+        let Some(timeout) = watchdog else {
+            continue;
+        };
+        let since_tick = status.since_last_tick();
+        match status.tick_stall(config.borrow().ticker.interval()) {
+            None => {
+                if stalled {
+                    info!("tick loop recovered, resuming watchdog pings");
+                    stalled = false;
+                }
+                notifier.notify("WATCHDOG=1")?;
+                last_ping = Instant::now();
+            }
+            Some(_) if last_ping.elapsed() >= timeout / 2 => {
+                return Err(Error::WatchdogTimeout(format!(
+                    "no tick for {}s",
+                    since_tick.as_secs()
+                ))
+                .into());
+            }
+            Some(_) if !stalled => {
+                warn!(
+                    "no tick for {}s, withholding watchdog pings",
+                    since_tick.as_secs()
+                );
+                stalled = true;
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+// One line for `systemctl status`.
+fn summary(status: &Status) -> String {
+    let report = status.report();
+    let waiting: Vec<String> = report
+        .subsystems
+        .iter()
+        .filter(|(_, s)| !matches!(s.state, State::Running | State::Stopped))
+        .map(|(name, s)| match &s.detail {
+            Some(detail) => format!("{name}: {detail}"),
+            None => name.to_string(),
+        })
+        .collect();
+    match waiting.is_empty() {
+        true => format!("running, {} ticks", report.ticks),
+        false => format!("waiting for {}", waiting.join(", ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::supervisor::Supervisor;
+    use tokio::sync::watch;
+
+    // A notifier and the socket it sends to, standing in for systemd's. The
+    // socket only queues a few messages before sending blocks, so tests read
+    // them as they go.
+    struct Fake {
+        dir: PathBuf,
+        socket: UnixDatagram,
+    }
+
+    impl Fake {
+        fn new() -> (Self, Notifier) {
+            let dir = std::env::temp_dir().join(format!("pis-systemd-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("notify");
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            let notifier = Notifier {
+                socket: UnixDatagram::unbound().unwrap(),
+                addr: SocketAddr::from_pathname(&path).unwrap(),
+            };
+            (Fake { dir, socket }, notifier)
+        }
+
+        // Everything sent since the last call.
+        fn received(&self) -> Vec<String> {
+            let mut messages = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(len) = self.socket.recv(&mut buf) {
+                messages.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+            }
+            messages
+        }
+    }
+
+    impl Drop for Fake {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn config(tick_interval_ms: u64) -> ConfigWatch {
+        let mut config = Config::default();
+        config.ticker.interval_ms = tick_interval_ms;
+        watch::channel(Arc::new(config)).1
+    }
+
+    fn start(
+        config: ConfigWatch,
+        notifier: Notifier,
+        status: Arc<Status>,
+        watchdog: Option<Duration>,
+    ) -> (tokio::task::JoinHandle<Result<()>>, Shutdown) {
+        let shutdown = Supervisor::new(config.clone(), status.clone()).shutdown_handle();
+        let task = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { notify(config, &notifier, &status, shutdown, watchdog).await }
+        });
+        (task, shutdown)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn readiness_waits_for_subsystems_that_are_starting() {
+        let (fake, notifier) = Fake::new();
+        let status = Arc::new(Status::new());
+        status.set("http", State::Starting, None);
+        status.set("mqtt", State::Degraded, Some("connecting".to_string()));
+        status.set_all_started();
+        let (task, shutdown) = start(config(1000), notifier, status.clone(), None);
+
+        time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(
+            fake.received(),
+            ["STATUS=waiting for http, mqtt: connecting"]
+        );
+        status.set("http", State::Running, None);
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(
+            fake.received(),
+            ["STATUS=waiting for mqtt: connecting", "READY=1"]
+        );
+        status.set("mqtt", State::Running, None);
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(fake.received(), ["STATUS=running, 0 ticks"]);
+
+        shutdown.trigger();
+        task.await.unwrap().unwrap();
+        assert_eq!(fake.received(), ["STOPPING=1\nSTATUS=shutting down"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_pings_follow_the_tick_loop() {
+        let (fake, notifier) = Fake::new();
+        let status = Arc::new(Status::new());
+        status.set_all_started();
+        let timeout = Duration::from_secs(8);
+        let (task, shutdown) = start(config(1000), notifier, status.clone(), Some(timeout));
+        let pings = |messages: Vec<String>| messages.iter().filter(|m| *m == "WATCHDOG=1").count();
+
+        let mut sent = 0;
+        for tick in 1..=5 {
+            time::sleep(Duration::from_millis(1000)).await;
+            status.set_ticks(tick);
+            sent += pings(fake.received());
+        }
+        assert!(sent >= 5, "{sent} pings");
+
+        // The tick is overdue three seconds after the last one.
+        time::sleep(Duration::from_millis(3500)).await;
+        fake.received();
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(pings(fake.received()), 0);
+
+        status.set_ticks(6);
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(pings(fake.received()) > 0);
+        shutdown.trigger();
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_stalled_tick_loop_stops_the_service_before_systemd_does() {
+        let (fake, notifier) = Fake::new();
+        let status = Arc::new(Status::new());
+        let timeout = Duration::from_secs(8);
+        let (task, _shutdown) = start(config(1000), notifier, status.clone(), Some(timeout));
+        let mut received = Vec::new();
+        for tick in 1..=5 {
+            time::sleep(Duration::from_secs(1)).await;
+            status.set_ticks(tick);
+            received.extend(fake.received());
+        }
+        assert!(received.iter().any(|message| message == "WATCHDOG=1"));
+        let last_tick = Instant::now();
+        let e = task.await.unwrap().unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<Error>(),
+            Some(Error::WatchdogTimeout(_))
+        ));
+
+        // With a tick every second the last ping goes out two seconds after
+        // the last tick, before the tick is overdue. The service stops half a
+        // timeout later, where systemd would wait a whole one.
+        let waited = last_tick.elapsed();
+        assert_eq!(waited, Duration::from_secs(2) + timeout / 2);
+    }
+}
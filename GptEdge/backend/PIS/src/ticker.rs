@@ -0,0 +1,133 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::config::{CatchUp, Config, ConfigWatch, Overlap};
+use crate::metrics::Metrics;
+use crate::scheduler::{Run, Schedule, Timing};
+use crate::state::StateStore;
+use crate::status::Status;
+use crate::supervisor::Shutdown;
+
+// Name of the tick job in the scheduler.
+pub const JOB: &str = "tick";
+
+// Key of the persisted tick counter in the state store.
+const STATE_KEY: &str = "ticker";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tick {
+    pub counter: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+// The tick job: counts ticks, persists the counter and hands every tick to
+// the MQTT heartbeat. The counter carries on across restarts; the
+// iteration limit applies to this run only.
+pub struct Ticker {
+    config: ConfigWatch,
+    ticks: broadcast::Sender<Tick>,
+    status: Arc<Status>,
+    metrics: Arc<Metrics>,
+    state: Arc<StateStore>,
+    shutdown: Shutdown,
+    progress: Mutex<Progress>,
+}
+
+struct Progress {
+    counter: u64,
+    iterations: u64,
+    // When the last tick ran and when it was due.
+    last: Option<(Instant, DateTime<Utc>)>,
+}
+
+impl Ticker {
+    pub fn new(
+        config: ConfigWatch,
+        ticks: broadcast::Sender<Tick>,
+        status: Arc<Status>,
+        metrics: Arc<Metrics>,
+        state: Arc<StateStore>,
+        shutdown: Shutdown,
+    ) -> Self {
+        let counter = state.get::<Tick>(STATE_KEY).map_or(0, |last| last.counter);
+        Ticker {
+            config,
+            ticks,
+            status,
+            metrics,
+            state,
+            shutdown,
+            progress: Mutex::new(Progress {
+                counter,
+                iterations: 0,
+                last: None,
+            }),
+        }
+    }
+
+    // Every ticker.interval_ms. Ticks missed while the device slept are not
+    // made up for.
+    pub fn timing(config: &Config) -> Timing {
+        Timing {
+            schedule: Schedule::Rate(config.ticker.interval()),
+            timeout: None,
+            overlap: Overlap::Skip,
+            catch_up: CatchUp::Skip,
+        }
+    }
+
+    pub fn tick(&self, run: &Run) -> Result<()> {
+        // Subsystems stop one after the other, so a tick may still be due
+        // after the iteration limit was reached.
+        if self.shutdown.is_triggered() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let late = (Utc::now() - run.scheduled).to_std().unwrap_or_default();
+        self.metrics.tick_latency.observe(late);
+        let mut progress = self.progress.lock().unwrap();
+        if let Some((last, last_scheduled)) = progress.last {
+            let period = (run.scheduled - last_scheduled)
+                .to_std()
+                .unwrap_or_default();
+            let elapsed = now - last;
+            let jitter = elapsed.max(period) - elapsed.min(period);
+            self.metrics
+                .tick_jitter_us
+                .store(jitter.as_micros() as u64, Ordering::Relaxed);
+        }
+        progress.last = Some((now, run.scheduled));
+
+        progress.counter += 1;
+        progress.iterations += 1;
+        let (counter, iterations) = (progress.counter, progress.iterations);
+        info!(counter; "tick {counter}");
+        self.status.set_ticks(counter);
+        self.metrics.ticks.fetch_add(1, Ordering::Relaxed);
+        let tick = Tick {
+            counter,
+            timestamp: Utc::now(),
+        };
+        self.state.set(STATE_KEY, &tick)?;
+        // Nobody listening is fine, e.g. when MQTT is disabled.
+        let _ = self.ticks.send(tick);
+        if self
+            .config
+            .borrow()
+            .ticker
+            .iteration_limit()
+            .is_some_and(|limit| iterations >= limit)
+        {
+            info!("reached {iterations} iterations");
+            self.shutdown.trigger();
+        }
+        Ok(())
+    }
+}
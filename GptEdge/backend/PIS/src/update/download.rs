@@ -0,0 +1,520 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use openssl::ssl::{HandshakeError, SslConnector, SslMethod, SslStream};
+use url::Url;
+
+const MAX_REDIRECTS: usize = 5;
+const BUFFER_SIZE: usize = 64 * 1024;
+// Blocked reads wake up this often to notice a cancelled download.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+// A connection that gives up once `cancel` is set, or when a read has
+// waited `timeout` without receiving anything.
+struct Stream<'a> {
+    transport: Transport,
+    cancel: &'a AtomicBool,
+    timeout: Duration,
+}
+
+impl Read for Stream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            if self.cancel.load(Ordering::Relaxed) {
+                return Err(cancelled());
+            }
+            let result = match &mut self.transport {
+                Transport::Plain(stream) => stream.read(buf),
+                Transport::Tls(stream) => stream.read(buf),
+            };
+            match result {
+                Err(e) if is_timeout(&e) && started.elapsed() < self.timeout => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Write for Stream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn cancelled() -> io::Error {
+    io::Error::other("download cancelled")
+}
+
+struct Response<'a> {
+    code: u16,
+    headers: Vec<(String, String)>,
+    body: BufReader<Stream<'a>>,
+}
+
+impl Response<'_> {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Downloads `url` to `path` over HTTP/1.1, resuming a partial file with a
+// Range request. Servers that ignore the range send the whole file, which
+// then replaces the partial one. `progress` receives the bytes on disk and
+// the total size when known. Blocking; run it on a blocking thread and set
+// `cancel` to make it return early, within POLL_INTERVAL once connected.
+pub fn fetch(
+    url: &str,
+    path: &Path,
+    timeout: Duration,
+    ca_file: Option<&Path>,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<u64> {
+    let mut url = Url::parse(url).with_context(|| format!("invalid url {url}"))?;
+    let offset = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e).with_context(|| format!("cannot access {}", path.display())),
+    };
+
+    let mut redirects = 0;
+    let mut response = loop {
+        let response = get(&url, offset, timeout, ca_file, cancel)?;
+        if !matches!(response.code, 301 | 302 | 303 | 307 | 308) {
+            break response;
+        }
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            bail!("too many redirects");
+        }
+        let location = response
+            .header("location")
+            .context("redirect without location")?;
+        url = url
+            .join(location)
+            .with_context(|| format!("invalid redirect to {location}"))?;
+    };
+
+    let (mut file, mut written, total) = match response.code {
+        200 => {
+            let total = response
+                .header("content-length")
+                .and_then(|v| v.parse().ok());
+            (File::create(path)?, 0, total)
+        }
+        206 => {
+            let (start, total) = response
+                .header("content-range")
+                .and_then(content_range)
+                .context("partial response without a valid content-range")?;
+            if start != offset {
+                bail!("server resumed at byte {start} instead of {offset}");
+            }
+            let file = OpenOptions::new().append(true).open(path)?;
+            (file, offset, total)
+        }
+        // The partial file is already complete.
+        416 if offset > 0 => {
+            progress(offset, Some(offset));
+            return Ok(offset);
+        }
+        code => bail!("{url} answered {code}"),
+    };
+    progress(written, total);
+
+    let chunked = response
+        .header("transfer-encoding")
+        .is_some_and(|value| value.eq_ignore_ascii_case("chunked"));
+    let length = match chunked {
+        true => None,
+        false => response
+            .header("content-length")
+            .and_then(|value| value.parse::<u64>().ok()),
+    };
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut copy = |reader: &mut dyn Read, mut remaining: Option<u64>| -> Result<()> {
+        loop {
+            let want = remaining.map_or(buffer.len(), |r| r.min(buffer.len() as u64) as usize);
+            if want == 0 {
+                return Ok(());
+            }
+            let n = reader.read(&mut buffer[..want])?;
+            if n == 0 {
+                match remaining {
+                    Some(_) => bail!("connection closed after {written} bytes"),
+                    None => return Ok(()),
+                }
+            }
+            file.write_all(&buffer[..n])?;
+            written += n as u64;
+            remaining = remaining.map(|r| r - n as u64);
+            progress(written, total);
+        }
+    };
+    if chunked {
+        loop {
+            let mut line = String::new();
+            if response.body.read_line(&mut line)? == 0 {
+                bail!("connection closed before the last chunk");
+            }
+            // Chunk extensions after ';' are ignored, as are trailers.
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .with_context(|| format!("malformed chunk size {:?}", line.trim()))?;
+            if size == 0 {
+                break;
+            }
+            copy(&mut response.body, Some(size))?;
+            line.clear();
+            response.body.read_line(&mut line)?;
+        }
+    } else {
+        copy(&mut response.body, length)?;
+    }
+    file.sync_all()?;
+    if let Some(total) = total {
+        if written != total {
+            bail!("received {written} of {total} bytes");
+        }
+    }
+    Ok(written)
+}
+
+fn get<'a>(
+    url: &Url,
+    offset: u64,
+    timeout: Duration,
+    ca_file: Option<&Path>,
+    cancel: &'a AtomicBool,
+) -> Result<Response<'a>> {
+    let host = url.host_str().context("url without host")?;
+    let port = url.port_or_known_default().context("url without port")?;
+    let tcp = connect(host.trim_matches(['[', ']']), port, timeout, cancel)
+        .with_context(|| format!("cannot connect to {host}:{port}"))?;
+    tcp.set_read_timeout(Some(POLL_INTERVAL.min(timeout)))?;
+    tcp.set_write_timeout(Some(timeout))?;
+    let transport = match url.scheme() {
+        "http" => Transport::Plain(tcp),
+        "https" => {
+            let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+            if let Some(ca_file) = ca_file {
+                builder
+                    .set_ca_file(ca_file)
+                    .with_context(|| format!("cannot load {}", ca_file.display()))?;
+            }
+            let started = Instant::now();
+            let mut handshake = builder.build().connect(host, tcp);
+            let stream = loop {
+                match handshake {
+                    Ok(stream) => break stream,
+                    Err(HandshakeError::WouldBlock(mid))
+                        if !cancel.load(Ordering::Relaxed) && started.elapsed() < timeout =>
+                    {
+                        handshake = mid.handshake();
+                    }
+                    Err(e) => bail!("TLS handshake with {host} failed: {e}"),
+                }
+            };
+            Transport::Tls(Box::new(stream))
+        }
+        scheme => bail!("unsupported url scheme {scheme}"),
+    };
+    let mut stream = Stream {
+        transport,
+        cancel,
+        timeout,
+    };
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let authority = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let mut request = format!(
+        "GET {target} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: pis/{}\r\nConnection: close\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    if offset > 0 {
+        request.push_str(&format!("Range: bytes={offset}-\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut body = BufReader::new(stream);
+    let mut line = String::new();
+    body.read_line(&mut line)?;
+    let code = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("malformed status line {:?}", line.trim()))?;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if body.read_line(&mut line)? == 0 {
+            bail!("connection closed in response headers");
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = header.split_once(':') {
+            headers.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(Response {
+        code,
+        headers,
+        body,
+    })
+}
+
+// Tries each address of the host in turn. An attempt in progress is not
+// interrupted by `cancel`, but takes at most `timeout`.
+fn connect(host: &str, port: u16, timeout: Duration, cancel: &AtomicBool) -> io::Result<TcpStream> {
+    let mut last = None;
+    for addr in (host, port).to_socket_addrs()? {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(cancelled());
+        }
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last = Some(e),
+        }
+    }
+    Err(last.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "no address found")))
+}
+
+// `bytes 100-199/1000` gives (100, Some(1000)); the total may be `*`.
+fn content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let range = value.strip_prefix("bytes ")?;
+    let (span, total) = range.split_once('/')?;
+    let start = span.split_once('-')?.0.parse().ok()?;
+    Some((start, total.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // Answers one connection after the other with the given responses and
+    // returns the request heads it received.
+    fn serve(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/artifact", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                requests.push(head);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn temp_file() -> PathBuf {
+        std::env::temp_dir().join(format!("pis-download-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn download(url: &str, path: &Path) -> Result<u64> {
+        fetch(url, path, TIMEOUT, None, &AtomicBool::new(false), |_, _| {})
+    }
+
+    #[test]
+    fn chunked_bodies_with_extensions_and_trailers() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5;name=value\r\nhello\r\n\
+             6 ; last\r\n world\r\n\
+             0\r\nX-Checksum: abc\r\n\r\n",
+        ]);
+        let path = temp_file();
+        assert_eq!(download(&url, &path).unwrap(), 11);
+        assert_eq!(fs::read(&path).unwrap(), b"hello world");
+        server.join().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn truncated_bodies_fail() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\na\r\nhello",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n",
+        ]);
+        let path = temp_file();
+        for expected in [
+            "connection closed after 5 bytes",
+            "connection closed after 5 bytes",
+            "connection closed before the last chunk",
+        ] {
+            let error = download(&url, &path).unwrap_err().to_string();
+            assert_eq!(error, expected);
+            // The partial file is kept for the next attempt to resume.
+            fs::remove_file(&path).unwrap();
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn partial_files_resume() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-10/11\r\n\
+             Content-Length: 6\r\n\r\n world",
+        ]);
+        let path = temp_file();
+        fs::write(&path, "hello").unwrap();
+        let mut reported = Vec::new();
+        let cancel = AtomicBool::new(false);
+        fetch(&url, &path, TIMEOUT, None, &cancel, |done, total| {
+            reported.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"hello world");
+        assert_eq!(reported.first(), Some(&(5, Some(11))));
+        assert_eq!(reported.last(), Some(&(11, Some(11))));
+        let requests = server.join().unwrap();
+        assert!(
+            requests[0].contains("Range: bytes=5-\r\n"),
+            "{}",
+            requests[0]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn redirects_are_followed_up_to_the_limit() {
+        let redirect = "HTTP/1.1 302 Found\r\nLocation: /again\r\nContent-Length: 0\r\n\r\n";
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        let mut responses = vec![redirect; MAX_REDIRECTS];
+        responses.push(ok);
+        let (url, server) = serve(responses);
+        let path = temp_file();
+        assert_eq!(download(&url, &path).unwrap(), 2);
+        server.join().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (url, server) = serve(vec![redirect; MAX_REDIRECTS + 1]);
+        let error = download(&url, &path).unwrap_err().to_string();
+        assert_eq!(error, "too many redirects");
+        assert_eq!(server.join().unwrap().len(), MAX_REDIRECTS + 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn relative_locations_resolve_against_the_request() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: files/v2?sig=1\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        ]);
+        let path = temp_file();
+        download(&format!("{url}/old"), &path).unwrap();
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /artifact/old HTTP/1.1\r\n"));
+        assert!(
+            requests[1].starts_with("GET /artifact/files/v2?sig=1 HTTP/1.1\r\n"),
+            "{}",
+            requests[1]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn error_statuses_fail() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found",
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let path = temp_file();
+        for code in [404, 500] {
+            let error = download(&url, &path).unwrap_err().to_string();
+            assert_eq!(error, format!("{url} answered {code}"));
+            assert!(!path.exists());
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn cancelling_stops_a_stalled_download() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/artifact", listener.local_addr().unwrap());
+        let cancel = Arc::new(AtomicBool::new(false));
+        {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            });
+        }
+        let started = Instant::now();
+        let path = temp_file();
+        // The server accepts the connection but never answers.
+        let error = fetch(
+            &url,
+            &path,
+            Duration::from_secs(60),
+            None,
+            &cancel,
+            |_, _| {},
+        )
+        .unwrap_err();
+        assert!(
+            format!("{error:#}").contains("download cancelled"),
+            "{error:#}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use log::debug;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use super::Job;
+use crate::config::{InstallerKind, UpdateConfig};
+
+// Called with the percentage done whenever the installer reports one.
+pub type Progress<'a> = &'a (dyn Fn(u8) + Send + Sync);
+
+// Writes a verified artifact to the inactive slot and manages which slot is
+// booted. After `install` the new slot is tentative: `confirm` makes it
+// permanent once the health check passed, `rollback` returns to the old one.
+#[async_trait]
+pub trait Installer: Send + Sync {
+    // Whether the new version only runs after a reboot.
+    fn needs_reboot(&self) -> bool;
+    async fn install(&self, job: &Job, artifact: &Path, progress: Progress<'_>) -> Result<()>;
+    async fn confirm(&self) -> Result<()>;
+    async fn rollback(&self) -> Result<()>;
+}
+
+pub fn from_config(config: &UpdateConfig) -> Box<dyn Installer> {
+    let command = |configured: &Option<Vec<String>>, default: &[&str]| {
+        configured
+            .clone()
+            .unwrap_or_else(|| default.iter().map(|s| s.to_string()).collect())
+    };
+    match config.installer {
+        InstallerKind::Rauc => Box::new(CommandInstaller {
+            install: command(&config.install_command, &["rauc", "install", "{artifact}"]),
+            confirm: command(&config.confirm_command, &["rauc", "status", "mark-good"]),
+            rollback: command(&config.rollback_command, &["rauc", "status", "mark-bad"]),
+        }),
+        // Bootloader state as used by SWUpdate: 0 ok, 3 failed.
+        InstallerKind::Swupdate => Box::new(CommandInstaller {
+            install: command(&config.install_command, &["swupdate", "-i", "{artifact}"]),
+            confirm: command(&config.confirm_command, &["fw_setenv", "ustate", "0"]),
+            rollback: command(&config.rollback_command, &["fw_setenv", "ustate", "3"]),
+        }),
+        InstallerKind::Directory => Box::new(DirectoryInstaller {
+            root: config.slots_dir.clone(),
+        }),
+    }
+}
+
+// Drives an installer CLI such as RAUC or SWUpdate. Percentages the tool
+// prints, like `40% Copying image`, are passed on as progress.
+struct CommandInstaller {
+    install: Vec<String>,
+    confirm: Vec<String>,
+    rollback: Vec<String>,
+}
+
+#[async_trait]
+impl Installer for CommandInstaller {
+    fn needs_reboot(&self) -> bool {
+        true
+    }
+
+    async fn install(&self, _job: &Job, artifact: &Path, progress: Progress<'_>) -> Result<()> {
+        let artifact = artifact.to_string_lossy();
+        let argv: Vec<String> = self
+            .install
+            .iter()
+            .map(|arg| arg.replace("{artifact}", &artifact))
+            .collect();
+        let (program, args) = argv.split_first().context("empty install command")?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("cannot run {program}"))?;
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+        let mut last_error = None;
+        let (mut stdout_open, mut stderr_open) = (true, true);
+        while stdout_open || stderr_open {
+            tokio::select! {
+                line = stdout.next_line(), if stdout_open => match line? {
+                    Some(line) => {
+                        debug!("{program}: {line}");
+                        if let Some(percent) = percent(&line) {
+                            progress(percent);
+                        }
+                    }
+                    None => stdout_open = false,
+                },
+                line = stderr.next_line(), if stderr_open => match line? {
+                    Some(line) => {
+                        debug!("{program}: {line}");
+                        if !line.trim().is_empty() {
+                            last_error = Some(line);
+                        }
+                    }
+                    None => stderr_open = false,
+                },
+            }
+        }
+        let status = child.wait().await?;
+        if !status.success() {
+            match last_error {
+                Some(line) => bail!("{program} failed with {status}: {line}"),
+                None => bail!("{program} failed with {status}"),
+            }
+        }
+        Ok(())
+    }
+
+    async fn confirm(&self) -> Result<()> {
+        run(&self.confirm).await
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        run(&self.rollback).await
+    }
+}
+
+// The last `NN%` in a line of installer output.
+fn percent(line: &str) -> Option<u8> {
+    line.split(|c: char| !c.is_ascii_digit() && c != '%')
+        .filter_map(|word| word.strip_suffix('%'))
+        .filter_map(|digits| digits.parse::<u8>().ok())
+        .rfind(|percent| *percent <= 100)
+}
+
+// A/B slots as plain directories, for development and tests: `root/a` and
+// `root/b` hold the artifacts, `root/current` links to the active slot and
+// `root/pending` names the previous one until the update is confirmed.
+struct DirectoryInstaller {
+    root: PathBuf,
+}
+
+const SLOTS: [&str; 2] = ["a", "b"];
+
+impl DirectoryInstaller {
+    fn active(&self) -> Result<&'static str> {
+        match fs::read_link(self.root.join("current")) {
+            Ok(target) => SLOTS
+                .into_iter()
+                .find(|slot| target == Path::new(slot))
+                .with_context(|| format!("current links to unknown slot {}", target.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(SLOTS[0]),
+            Err(e) => Err(e).context("cannot read current slot"),
+        }
+    }
+
+    // Points `current` at `slot` atomically.
+    fn activate(&self, slot: &str) -> Result<()> {
+        let tmp = self.root.join("current.tmp");
+        let _ = fs::remove_file(&tmp);
+        symlink(slot, &tmp)?;
+        fs::rename(&tmp, self.root.join("current"))?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Installer for DirectoryInstaller {
+    fn needs_reboot(&self) -> bool {
+        false
+    }
+
+    async fn install(&self, job: &Job, artifact: &Path, progress: Progress<'_>) -> Result<()> {
+        let active = self.active()?;
+        let target = SLOTS.into_iter().find(|slot| *slot != active).unwrap();
+        let dir = self.root.join(target);
+        match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("cannot clear {}", dir.display()))
+            }
+            _ => {}
+        }
+        fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        fs::copy(artifact, dir.join("artifact"))
+            .with_context(|| format!("cannot copy artifact to {}", dir.display()))?;
+        fs::write(dir.join("version"), &job.version)?;
+        progress(100);
+        fs::write(self.root.join("pending"), active)?;
+        self.activate(target)
+    }
+
+    async fn confirm(&self) -> Result<()> {
+        match fs::remove_file(self.root.join("pending")) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn rollback(&self) -> Result<()> {
+        let previous = fs::read_to_string(self.root.join("pending"))
+            .context("no pending update to roll back")?;
+        let previous = SLOTS
+            .into_iter()
+            .find(|slot| *slot == previous.trim())
+            .with_context(|| format!("pending names unknown slot {previous}"))?;
+        self.activate(previous)?;
+        fs::remove_file(self.root.join("pending"))?;
+        Ok(())
+    }
+}
+
+// Runs a command to completion, failing unless it exits successfully.
+pub async fn run(argv: &[String]) -> Result<()> {
+    let (program, args) = argv.split_first().context("empty command")?;
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .await
+        .with_context(|| format!("cannot run {program}"))?;
+    if !status.success() {
+        bail!("{program} failed with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,670 @@
+pub mod download;
+pub mod installer;
+mod verify;
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use openssl::pkey::{PKey, Public};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+
+use crate::buildinfo;
+use crate::config::{ConfigWatch, UpdateConfig};
+use crate::mqtt::command::CommandHandler;
+use crate::mqtt::Publication;
+use crate::state::StateStore;
+use crate::status::Status;
+use crate::supervisor::Shutdown;
+use installer::Installer;
+pub use verify::load_keys;
+
+pub const NAME: &str = "update";
+
+const STATE_KEY: &str = "update";
+// Downloads are kept here, per job, until the job has finished.
+const DOWNLOAD_DIR: &str = "update";
+const BOOT_ID: &str = "/proc/sys/kernel/random/boot_id";
+const RETRY_MIN: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(300);
+// Download progress is reported at most this often.
+const REPORT_INTERVAL: Duration = Duration::from_secs(2);
+
+// An update as sent by the back office.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub id: String,
+    pub version: String,
+    pub url: String,
+    // Hex SHA-256 digest of the artifact.
+    pub sha256: String,
+    // Base64 Ed25519 signature of the manifest, see `verify::manifest`.
+    pub signature: String,
+}
+
+impl Job {
+    fn validate(&self) -> Result<()> {
+        if self.id.is_empty()
+            || !self
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            bail!("id must be non-empty and consist of letters, digits, '-', '_' and '.'");
+        }
+        let Some(version) = Version::parse(&self.version) else {
+            bail!("version must look like 1.2.3, optionally with a -suffix");
+        };
+        // Every artifact ever signed stays valid, so installing older ones
+        // would let anyone roll the unit back to a vulnerable version.
+        let running = buildinfo::build().version;
+        if Version::parse(running).is_some_and(|running| version <= running) {
+            bail!(
+                "version {} is not newer than the running {running}",
+                self.version
+            );
+        }
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            bail!("url must be an http:// or https:// url");
+        }
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("sha256 must be 64 hex digits");
+        }
+        match openssl::base64::decode_block(self.signature.trim()) {
+            Ok(signature) if signature.len() == 64 => Ok(()),
+            _ => bail!("signature must be a base64 Ed25519 signature"),
+        }
+    }
+}
+
+// A release number such as 1.4.2 or 2.0.0-rc1. Missing components count as
+// 0, and a pre-release sorts before the release it precedes.
+#[derive(Debug)]
+struct Version {
+    numbers: Vec<u64>,
+    pre: Option<String>,
+}
+
+impl Version {
+    fn parse(text: &str) -> Option<Self> {
+        let text = text.split('+').next()?;
+        let (numbers, pre) = match text.split_once('-') {
+            Some((numbers, pre)) if !pre.is_empty() => (numbers, Some(pre.to_string())),
+            Some(_) => return None,
+            None => (text, None),
+        };
+        let numbers = numbers
+            .split('.')
+            .map(|n| match n.bytes().all(|b| b.is_ascii_digit()) {
+                true => n.parse().ok(),
+                false => None,
+            })
+            .collect::<Option<Vec<u64>>>()?;
+        Some(Version { numbers, pre })
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.numbers.len().max(other.numbers.len());
+        let number = |version: &Version, i| version.numbers.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| number(self, i).cmp(&number(other, i)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| match (&self.pre, &other.pre) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Idle,
+    Queued,
+    Downloading,
+    Verifying,
+    Installing,
+    // Waiting for the unit to boot the new slot.
+    Rebooting,
+    // Running the new version, which is confirmed if it stays healthy.
+    HealthCheck,
+    Confirmed,
+    RolledBack,
+    Failed,
+}
+
+impl Phase {
+    fn is_active(self) -> bool {
+        !matches!(
+            self,
+            Phase::Idle | Phase::Confirmed | Phase::RolledBack | Phase::Failed
+        )
+    }
+}
+
+// Published on update.progress_topic and served at GET /update.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Progress {
+    pub phase: Phase,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// What survives restarts and reboots in the state store.
+#[derive(Clone, Serialize, Deserialize)]
+struct Saved {
+    progress: Progress,
+    job: Option<Job>,
+    // The boot the artifact was installed in, to tell when the unit has
+    // rebooted into the new slot.
+    boot_id: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum SubmitError {
+    #[error("updates are disabled")]
+    Disabled,
+    #[error("invalid update job: {0:#}")]
+    Invalid(anyhow::Error),
+    #[error("update {0} is in progress")]
+    Busy(String),
+}
+
+// Accepts jobs from the MQTT command and the HTTP API and keeps the progress
+// of the current one. The update subsystem takes the jobs from here.
+pub struct Updater {
+    enabled: bool,
+    progress_topic: String,
+    state: Arc<StateStore>,
+    publications: broadcast::Sender<Publication>,
+    saved: Mutex<Saved>,
+    jobs: mpsc::Sender<Job>,
+    queue: tokio::sync::Mutex<mpsc::Receiver<Job>>,
+}
+
+impl Updater {
+    pub fn new(
+        config: &UpdateConfig,
+        state: Arc<StateStore>,
+        publications: broadcast::Sender<Publication>,
+    ) -> Self {
+        let saved = state.get(STATE_KEY).unwrap_or(Saved {
+            progress: Progress {
+                phase: Phase::Idle,
+                job_id: None,
+                version: None,
+                percent: None,
+                error: None,
+                updated_at: Utc::now(),
+            },
+            job: None,
+            boot_id: None,
+        });
+        let (jobs, queue) = mpsc::channel(1);
+        Updater {
+            enabled: config.enabled,
+            progress_topic: config.progress_topic.clone(),
+            state,
+            publications,
+            saved: Mutex::new(saved),
+            jobs,
+            queue: tokio::sync::Mutex::new(queue),
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.saved.lock().unwrap().progress.clone()
+    }
+
+    pub fn submit(&self, job: Job) -> Result<(), SubmitError> {
+        if !self.enabled {
+            return Err(SubmitError::Disabled);
+        }
+        job.validate().map_err(SubmitError::Invalid)?;
+        let mut saved = self.saved.lock().unwrap();
+        if saved.progress.phase.is_active() {
+            let id = saved.progress.job_id.clone().unwrap_or_default();
+            return Err(SubmitError::Busy(id));
+        }
+        self.jobs
+            .try_send(job.clone())
+            .map_err(|_| SubmitError::Busy(job.id.clone()))?;
+        info!("update {} to {} queued", job.id, job.version);
+        saved.job = Some(job);
+        saved.boot_id = None;
+        saved.progress.phase = Phase::Queued;
+        saved.progress.percent = None;
+        saved.progress.error = None;
+        self.store(&mut saved, true);
+        Ok(())
+    }
+
+    fn saved(&self) -> Saved {
+        self.saved.lock().unwrap().clone()
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Saved)) {
+        let mut saved = self.saved.lock().unwrap();
+        let phase = saved.progress.phase;
+        change(&mut saved);
+        let phase_changed = saved.progress.phase != phase;
+        self.store(&mut saved, phase_changed);
+    }
+
+    // Stores the progress and publishes it. A new phase is written to disk
+    // right away, a new percentage with the next periodic flush. Called with
+    // the lock held so that stored and published progress stay in order.
+    fn store(&self, saved: &mut Saved, flush: bool) {
+        if let Some(job) = &saved.job {
+            saved.progress.job_id = Some(job.id.clone());
+            saved.progress.version = Some(job.version.clone());
+        }
+        saved.progress.updated_at = Utc::now();
+        let stored = self
+            .state
+            .set(STATE_KEY, &*saved)
+            .and_then(|()| match flush {
+                true => self.state.flush(),
+                false => Ok(()),
+            });
+        if let Err(e) = stored {
+            error!("cannot save update progress: {e:#}");
+        }
+        match serde_json::to_vec(&saved.progress) {
+            Ok(payload) => {
+                // Nobody listens while MQTT is disabled.
+                let _ = self.publications.send(Publication {
+                    topic: self.progress_topic.clone(),
+                    payload,
+                    retain: true,
+                });
+            }
+            Err(e) => error!("cannot serialize update progress: {e}"),
+        }
+    }
+
+    fn report(&self, phase: Phase, percent: Option<u8>) {
+        self.update(|saved| {
+            saved.progress.phase = phase;
+            saved.progress.percent = percent;
+            saved.progress.error = None;
+        });
+    }
+
+    fn fail(&self, phase: Phase, error: &anyhow::Error) {
+        self.update(|saved| {
+            saved.progress.phase = phase;
+            saved.progress.percent = None;
+            saved.progress.error = Some(format!("{error:#}"));
+        });
+    }
+}
+
+// The `update` MQTT command; its params are a `Job`.
+pub struct UpdateCommand {
+    pub updater: Arc<Updater>,
+}
+
+#[async_trait]
+impl CommandHandler for UpdateCommand {
+    async fn handle(&self, params: Value) -> Result<Value> {
+        let job: Job = serde_json::from_value(params).context("malformed update job")?;
+        let id = job.id.clone();
+        self.updater.submit(job)?;
+        Ok(json!({ "job_id": id, "phase": Phase::Queued }))
+    }
+}
+
+struct Agent {
+    settings: UpdateConfig,
+    installer: Box<dyn Installer>,
+    keys: Vec<PKey<Public>>,
+    dir: PathBuf,
+    reboot_command: Vec<String>,
+    tick_interval: Duration,
+    updater: Arc<Updater>,
+    status: Arc<Status>,
+}
+
+// Runs update jobs one at a time: download with resume and retries, verify,
+// install, reboot if the installer needs it, then confirm the new version
+// once it passed the health check, or roll back. A job interrupted by a
+// restart or reboot continues where it left off.
+pub async fn run(
+    config: ConfigWatch,
+    updater: Arc<Updater>,
+    status: Arc<Status>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let (settings, state_dir, reboot_command, tick_interval) = {
+        let config = config.borrow();
+        (
+            config.update.clone(),
+            config.service.state_dir.clone(),
+            config.service.reboot_command.clone(),
+            config.ticker.interval(),
+        )
+    };
+    let dir = state_dir.join(DOWNLOAD_DIR);
+    fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let agent = Agent {
+        keys: verify::load_keys(&settings.public_keys)?,
+        installer: installer::from_config(&settings),
+        settings,
+        dir,
+        reboot_command,
+        tick_interval,
+        updater: updater.clone(),
+        status,
+    };
+
+    let saved = updater.saved();
+    if let Some(job) = saved.job.filter(|_| saved.progress.phase.is_active()) {
+        info!(
+            "resuming update {} to {}, {:?}",
+            job.id, job.version, saved.progress.phase
+        );
+        let resumed = match saved.progress.phase {
+            Phase::Rebooting if saved.boot_id.as_deref() == boot_id().as_deref() => {
+                agent.reboot().await
+            }
+            Phase::Rebooting | Phase::HealthCheck => agent.check_health(&job, &mut shutdown).await,
+            _ => agent.execute(&job, &mut shutdown).await,
+        };
+        if let Err(e) = resumed {
+            error!("update {} failed: {e:#}", job.id);
+        }
+    }
+
+    let mut queue = updater.queue.lock().await;
+    loop {
+        let job = tokio::select! {
+            Some(job) = queue.recv() => job,
+            _ = shutdown.wait() => return Ok(()),
+        };
+        if let Err(e) = agent.execute(&job, &mut shutdown).await {
+            error!("update {} failed: {e:#}", job.id);
+        }
+    }
+}
+
+impl Agent {
+    async fn execute(&self, job: &Job, shutdown: &mut Shutdown) -> Result<()> {
+        let artifact = self.dir.join(format!("{}.artifact", job.id));
+        self.clean(Some(&artifact));
+
+        let downloaded = tokio::select! {
+            result = self.download(job, &artifact) => result,
+            _ = shutdown.wait() => {
+                info!("update {} interrupted, resumes after restart", job.id);
+                return Ok(());
+            }
+        };
+        if let Err(e) = downloaded {
+            self.updater.fail(Phase::Failed, &e);
+            return Err(e);
+        }
+
+        self.updater.report(Phase::Verifying, None);
+        let verified = {
+            let (artifact, job, keys) = (artifact.clone(), job.clone(), self.keys.clone());
+            tokio::task::spawn_blocking(move || verify::check(&artifact, &job, &keys)).await?
+        };
+        if let Err(e) = verified {
+            // A corrupt download must not be resumed.
+            let _ = fs::remove_file(&artifact);
+            let e = e.context("verification failed");
+            self.updater.fail(Phase::Failed, &e);
+            return Err(e);
+        }
+
+        // Installing is not interrupted by shutdown: a half-written slot is
+        // worse than a late exit.
+        self.updater.report(Phase::Installing, Some(0));
+        let progress = |percent| self.updater.report(Phase::Installing, Some(percent));
+        if let Err(e) = self.installer.install(job, &artifact, &progress).await {
+            let e = e.context("installation failed");
+            self.updater.fail(Phase::Failed, &e);
+            return Err(e);
+        }
+        let _ = fs::remove_file(&artifact);
+        info!("update {} to {} installed", job.id, job.version);
+
+        if self.installer.needs_reboot() {
+            self.updater.update(|saved| {
+                saved.boot_id = boot_id();
+                saved.progress.phase = Phase::Rebooting;
+                saved.progress.percent = None;
+            });
+            return self.reboot().await;
+        }
+        self.check_health(job, shutdown).await
+    }
+
+    // Retries with backoff, each attempt resuming the partial file. The
+    // transfer runs on a blocking thread, which the runtime waits for on
+    // exit, so dropping this future, e.g. on shutdown, cancels it.
+    async fn download(&self, job: &Job, artifact: &Path) -> Result<()> {
+        self.updater.report(Phase::Downloading, Some(0));
+        let cancel = CancelOnDrop(Arc::new(AtomicBool::new(false)));
+        let mut delay = RETRY_MIN;
+        let mut attempt = 1;
+        loop {
+            let (url, path, updater, cancel) = (
+                job.url.clone(),
+                artifact.to_path_buf(),
+                self.updater.clone(),
+                cancel.0.clone(),
+            );
+            let (timeout, ca_file) = (
+                self.settings.download_timeout(),
+                self.settings.ca_file.clone(),
+            );
+            let result = tokio::task::spawn_blocking(move || {
+                let mut last = Instant::now();
+                let ca_file = ca_file.as_deref();
+                download::fetch(&url, &path, timeout, ca_file, &cancel, |done, total| {
+                    let percent = total.filter(|&t| t > 0).map(|t| (done * 100 / t) as u8);
+                    if last.elapsed() >= REPORT_INTERVAL || percent == Some(100) {
+                        updater.report(Phase::Downloading, percent);
+                        last = Instant::now();
+                    }
+                })
+            })
+            .await?;
+            match result {
+                Ok(bytes) => {
+                    info!("update {} downloaded, {bytes} bytes", job.id);
+                    return Ok(());
+                }
+                Err(e) if attempt >= self.settings.download_attempts => {
+                    return Err(e.context(format!("download failed after {attempt} attempts")));
+                }
+                Err(e) => warn!(
+                    "download of update {} failed, retrying in {delay:?}: {e:#}",
+                    job.id
+                ),
+            }
+            time::sleep(delay).await;
+            delay = (delay * 2).min(RETRY_MAX);
+            attempt += 1;
+        }
+    }
+
+    async fn reboot(&self) -> Result<()> {
+        info!("rebooting into the new version");
+        installer::run(&self.reboot_command)
+            .await
+            .context("cannot reboot")
+    }
+
+    // The new version has to be ready, with no subsystem failing and ticks
+    // flowing, at the end of update.health_check_secs.
+    async fn check_health(&self, job: &Job, shutdown: &mut Shutdown) -> Result<()> {
+        self.updater.report(Phase::HealthCheck, None);
+        info!(
+            "checking health of {} for {:?}",
+            job.version,
+            self.settings.health_check()
+        );
+        tokio::select! {
+            _ = time::sleep(self.settings.health_check()) => {}
+            _ = shutdown.wait() => {
+                info!("health check interrupted, repeats after restart");
+                return Ok(());
+            }
+        }
+        let failing = self.status.failing();
+        let problem = if !failing.is_empty() {
+            Some(format!("failing: {}", failing.join(", ")))
+        } else if !self.status.is_ready() {
+            Some(format!("not ready: {}", self.status.not_ready().join(", ")))
+        } else if self.status.tick_stall(self.tick_interval).is_some() {
+            Some("tick loop stalled".to_string())
+        } else {
+            None
+        };
+
+        match problem {
+            None => {
+                if let Err(e) = self.installer.confirm().await {
+                    self.updater
+                        .fail(Phase::Failed, &anyhow!("cannot confirm: {e:#}"));
+                    return Err(e);
+                }
+                self.updater.report(Phase::Confirmed, None);
+                info!("update {} to {} confirmed", job.id, job.version);
+                Ok(())
+            }
+            Some(problem) => {
+                warn!("update {} failed its health check, {problem}", job.id);
+                if let Err(e) = self.installer.rollback().await {
+                    self.updater
+                        .fail(Phase::Failed, &anyhow!("cannot roll back: {e:#}"));
+                    return Err(e);
+                }
+                self.updater.fail(
+                    Phase::RolledBack,
+                    &anyhow!("health check failed, {problem}"),
+                );
+                if self.installer.needs_reboot() {
+                    self.reboot().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Removes downloads of earlier jobs.
+    fn clean(&self, keep: Option<&Path>) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if Some(path.as_path()) != keep {
+                if let Err(e) = fs::remove_file(&path) {
+                    if e.kind() != ErrorKind::NotFound {
+                        warn!("cannot remove {}: {e}", path.display());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Sets the flag when dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+}
+
+fn boot_id() -> Option<String> {
+    fs::read_to_string(BOOT_ID)
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    #[test]
+    fn versions_compare_by_number() {
+        assert!(version("0.10.0") > version("0.9.9"));
+        assert!(version("1.2") == version("1.2.0"));
+        assert!(version("1.2.1") > version("1.2"));
+        assert!(version("2.0.0-rc1") < version("2.0.0"));
+        assert!(version("2.0.0-rc2") > version("2.0.0-rc1"));
+        assert!(version("1.0.0+build5") == version("1.0.0"));
+    }
+
+    #[test]
+    fn malformed_versions_are_rejected() {
+        for text in ["", "1..2", "v1.2", "1.2-", "1.2.x", "1.2\n"] {
+            assert!(Version::parse(text).is_none(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn only_newer_versions_are_accepted() {
+        let mut job = Job {
+            id: "job-1".to_string(),
+            version: "999.0.0".to_string(),
+            url: "https://updates.example.com/pis.bundle".to_string(),
+            sha256: "0".repeat(64),
+            signature: openssl::base64::encode_block(&[0; 64]),
+        };
+        job.validate().unwrap();
+        for version in [env!("CARGO_PKG_VERSION"), "0.0.1"] {
+            job.version = version.to_string();
+            let error = job.validate().unwrap_err().to_string();
+            assert!(error.contains("is not newer"), "{error}");
+        }
+    }
+}
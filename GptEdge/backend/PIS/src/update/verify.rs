@@ -0,0 +1,126 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sha::Sha256;
+use openssl::sign::Verifier;
+
+use super::Job;
+use crate::state::hex;
+
+// Reads the PEM Ed25519 public keys that update artifacts may be signed
+// with.
+pub fn load_keys(paths: &[PathBuf]) -> Result<Vec<PKey<Public>>> {
+    paths
+        .iter()
+        .map(|path| {
+            let pem = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+            let key = PKey::public_key_from_pem(&pem)
+                .with_context(|| format!("{} is not a PEM public key", path.display()))?;
+            if key.id() != Id::ED25519 {
+                bail!("{} is not an Ed25519 key", path.display());
+            }
+            Ok(key)
+        })
+        .collect()
+}
+
+// Checks the artifact against the job's SHA-256 digest, then the job's
+// signature of its manifest. Signing the manifest rather than the bare
+// digest binds the artifact to the id and version it was released as, so
+// an old artifact cannot be offered as a newer version.
+pub fn check(artifact: &Path, job: &Job, keys: &[PKey<Public>]) -> Result<()> {
+    let digest = sha256(artifact)?;
+    if hex(&digest) != job.sha256.to_lowercase() {
+        bail!("sha256 mismatch, artifact is {}", hex(&digest));
+    }
+    let signature = openssl::base64::decode_block(job.signature.trim())
+        .context("signature is not valid base64")?;
+    let manifest = manifest(job);
+    for key in keys {
+        let mut verifier = Verifier::new_without_digest(key)?;
+        if verifier
+            .verify_oneshot(&signature, manifest.as_bytes())
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+    }
+    bail!("signature does not match any trusted key")
+}
+
+// What a job's signature covers: its id, version and lowercase hex digest,
+// each followed by a newline. Signed with
+// `openssl pkeyutl -sign -rawin -inkey key.pem -in manifest`.
+pub fn manifest(job: &Job) -> String {
+    format!(
+        "{}\n{}\n{}\n",
+        job.id,
+        job.version,
+        job.sha256.to_lowercase()
+    )
+}
+
+fn sha256(path: &Path) -> Result<[u8; 32]> {
+    let mut file = File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    use super::*;
+
+    fn sign(key: &PKey<Private>, job: &Job) -> String {
+        let mut signer = Signer::new_without_digest(key).unwrap();
+        let signature = signer
+            .sign_oneshot_to_vec(manifest(job).as_bytes())
+            .unwrap();
+        openssl::base64::encode_block(&signature)
+    }
+
+    #[test]
+    fn signature_covers_id_version_and_digest() {
+        let artifact = std::env::temp_dir().join(format!("pis-artifact-{}", uuid::Uuid::new_v4()));
+        fs::write(&artifact, b"new image").unwrap();
+        let key = PKey::generate_ed25519().unwrap();
+        let public =
+            PKey::public_key_from_raw_bytes(&key.raw_public_key().unwrap(), Id::ED25519).unwrap();
+        let mut job = Job {
+            id: "job-1".to_string(),
+            version: "2.0.0".to_string(),
+            url: "https://updates.example.com/pis.bundle".to_string(),
+            sha256: hex(&sha256(&artifact).unwrap()).to_uppercase(),
+            signature: String::new(),
+        };
+        job.signature = sign(&key, &job);
+        check(&artifact, &job, std::slice::from_ref(&public)).unwrap();
+
+        // The same artifact and signature offered as another release.
+        for change in [
+            |job: &mut Job| job.version = "3.0.0".to_string(),
+            |job: &mut Job| job.id = "job-2".to_string(),
+        ] {
+            let mut replayed = job.clone();
+            change(&mut replayed);
+            assert!(check(&artifact, &replayed, std::slice::from_ref(&public)).is_err());
+        }
+
+        let other = PKey::generate_ed25519().unwrap();
+        job.signature = sign(&other, &job);
+        assert!(check(&artifact, &job, &[public]).is_err());
+        fs::remove_file(artifact).unwrap();
+    }
+}
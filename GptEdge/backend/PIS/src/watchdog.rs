@@ -0,0 +1,300 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::config::ConfigWatch;
+use crate::status::Status;
+use crate::supervisor::Shutdown;
+
+// _IOWR('W', 6, int) and _IOR('W', 7, int) from <linux/watchdog.h>, in the
+// asm-generic encoding used on arm, aarch64 and x86.
+const WDIOC_SETTIMEOUT: u32 = 0xc004_5706;
+const WDIOC_GETTIMEOUT: u32 = 0x8004_5707;
+
+// Written before closing to tell the driver the close is intentional.
+const MAGIC_CLOSE: &[u8] = b"V";
+
+// Name under which the keeper registers with the supervisor; it does not
+// wait for itself to stop.
+pub const NAME: &str = "watchdog";
+
+struct Device {
+    file: File,
+}
+
+impl Device {
+    // Opening arms the watchdog. Any path is accepted so that a plain file
+    // or the softdog module can stand in for real hardware; a device that
+    // does not support the timeout ioctls keeps its own timeout.
+    fn open(path: &Path, timeout_secs: u32) -> Result<(Self, Duration)> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("cannot open watchdog {}", path.display()))?;
+        let device = Device { file };
+        let timeout = match device.set_timeout(timeout_secs) {
+            Ok(actual) => actual,
+            Err(e) => {
+                warn!(
+                    "cannot set timeout of watchdog {}, assuming {timeout_secs}s: {e}",
+                    path.display()
+                );
+                timeout_secs
+            }
+        };
+        Ok((device, Duration::from_secs(timeout.into())))
+    }
+
+    // Returns the timeout the driver actually applied, which may be rounded.
+    fn set_timeout(&self, secs: u32) -> io::Result<u32> {
+        let mut value = secs as libc::c_int;
+        self.ioctl(WDIOC_SETTIMEOUT, &mut value)?;
+        self.ioctl(WDIOC_GETTIMEOUT, &mut value)?;
+        Ok(value as u32)
+    }
+
+    fn ioctl(&self, request: u32, value: &mut libc::c_int) -> io::Result<()> {
+        // SAFETY: both requests take a pointer to an int, which outlives the
+        // call.
+        let ret = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                request as libc::Ioctl,
+                value as *mut libc::c_int,
+            )
+        };
+        match ret {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn pet(&mut self) -> io::Result<()> {
+        self.file.write_all(b"\0")?;
+        self.file.flush()
+    }
+
+    // Disarms the watchdog. Drivers built with nowayout ignore this and the
+    // unit reboots once petting stops.
+    fn close(mut self) -> io::Result<()> {
+        self.file.write_all(MAGIC_CLOSE)?;
+        self.file.flush()
+    }
+}
+
+// Pets the watchdog while every subsystem is healthy: none of them waiting
+// to be restarted after a failure and the tick loop making progress. When
+// something hangs, petting stops and the hardware reboots the unit.
+//
+// On shutdown it keeps petting until the other subsystems have stopped and
+// then disarms the device. If they do not stop within the shutdown timeout
+// the device is closed without disarming it, so the unit reboots.
+pub async fn run(config: ConfigWatch, status: Arc<Status>, mut shutdown: Shutdown) -> Result<()> {
+    let (settings, shutdown_timeout) = {
+        let config = config.borrow();
+        (config.watchdog.clone(), config.service.shutdown_timeout())
+    };
+    let (mut device, timeout) = Device::open(&settings.device, settings.timeout_secs)?;
+    info!(
+        "watchdog {} armed, timeout {timeout:?}",
+        settings.device.display()
+    );
+    let mut interval = time::interval(timeout / 3);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut healthy = true;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.wait() => break,
+        }
+        let failing = status.failing();
+        let stalled = status
+            .tick_stall(config.borrow().ticker.interval())
+            .is_some();
+        let was_healthy = healthy;
+        healthy = failing.is_empty() && !stalled;
+        if healthy {
+            if !was_healthy {
+                info!("all subsystems healthy again, petting watchdog");
+            }
+            device.pet().context("cannot pet watchdog")?;
+        } else if was_healthy {
+            let reason = match failing.is_empty() {
+                true => "tick loop stalled".to_string(),
+                false => format!("failing: {}", failing.join(", ")),
+            };
+            warn!("{reason}, no longer petting watchdog");
+        }
+    }
+
+    let deadline = Instant::now() + shutdown_timeout;
+    while !status.all_stopped_except(NAME) {
+        if Instant::now() >= deadline {
+            warn!("shutdown did not complete, leaving watchdog armed");
+            return Ok(());
+        }
+        device.pet().context("cannot pet watchdog")?;
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    device.close().context("cannot disarm watchdog")?;
+    info!("watchdog disarmed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::status::State;
+    use crate::supervisor::Supervisor;
+    use tokio::sync::watch;
+
+    // A plain file standing in for the device. It does not support the
+    // timeout ioctls, so the configured timeout applies as is.
+    struct Fake {
+        dir: PathBuf,
+        device: PathBuf,
+    }
+
+    impl Fake {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("pis-watchdog-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let device = dir.join("watchdog");
+            fs::write(&device, "").unwrap();
+            Fake { dir, device }
+        }
+
+        // Everything written to the device so far.
+        fn written(&self) -> Vec<u8> {
+            fs::read(&self.device).unwrap()
+        }
+
+        fn pets(&self) -> usize {
+            self.written().iter().filter(|b| **b == 0).count()
+        }
+    }
+
+    impl Drop for Fake {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Pets every second, and the tick loop counts as stalled three seconds
+    // after its last tick.
+    fn start(fake: &Fake, status: Arc<Status>) -> (tokio::task::JoinHandle<Result<()>>, Shutdown) {
+        let mut config = Config::default();
+        config.watchdog.device = fake.device.clone();
+        config.watchdog.timeout_secs = 3;
+        config.ticker.interval_ms = 1000;
+        config.service.shutdown_timeout_ms = 2000;
+        let config = watch::channel(Arc::new(config)).1;
+        let shutdown = Supervisor::new(config.clone(), status.clone()).shutdown_handle();
+        let task = tokio::spawn(run(config, status, shutdown.clone()));
+        (task, shutdown)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pets_while_healthy_and_disarms_on_clean_shutdown() {
+        let fake = Fake::new();
+        let status = Arc::new(Status::new());
+        status.set("mqtt", State::Running, None);
+        let (task, shutdown) = start(&fake, status.clone());
+        for tick in 1..=3 {
+            time::sleep(Duration::from_secs(1)).await;
+            status.set_ticks(tick);
+        }
+        assert!(fake.pets() >= 3, "{:?}", fake.written());
+        assert!(!fake.written().contains(&b'V'));
+
+        // Petting goes on until the other subsystems have stopped.
+        let pets = fake.pets();
+        shutdown.trigger();
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(fake.pets() > pets);
+        assert!(!fake.written().contains(&b'V'));
+        status.set("mqtt", State::Stopped, None);
+        task.await.unwrap().unwrap();
+        assert_eq!(fake.written().last(), Some(&b'V'));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaves_the_watchdog_armed_when_shutdown_hangs() {
+        let fake = Fake::new();
+        let status = Arc::new(Status::new());
+        status.set("mqtt", State::Running, None);
+        let (task, shutdown) = start(&fake, status.clone());
+        time::sleep(Duration::from_millis(500)).await;
+        shutdown.trigger();
+        task.await.unwrap().unwrap();
+        assert!(fake.pets() > 0);
+        assert!(!fake.written().contains(&b'V'));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_petting_while_a_subsystem_is_failing() {
+        let fake = Fake::new();
+        let status = Arc::new(Status::new());
+        status.set(
+            "mqtt",
+            State::Restarting,
+            Some("broker unreachable".to_string()),
+        );
+        let (task, shutdown) = start(&fake, status.clone());
+        for tick in 1..=3 {
+            time::sleep(Duration::from_secs(1)).await;
+            status.set_ticks(tick);
+        }
+        assert_eq!(fake.pets(), 0);
+
+        status.set("mqtt", State::Running, None);
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(fake.pets() > 0);
+        shutdown.trigger();
+        status.set("mqtt", State::Stopped, None);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_petting_while_ticks_are_stalled() {
+        let fake = Fake::new();
+        let status = Arc::new(Status::new());
+        let (task, shutdown) = start(&fake, status.clone());
+        for tick in 1..=2 {
+            time::sleep(Duration::from_secs(1)).await;
+            status.set_ticks(tick);
+        }
+
+        // Overdue from three seconds after the last tick.
+        time::sleep(Duration::from_millis(3500)).await;
+        let pets = fake.pets();
+        time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(fake.pets(), pets);
+
+        status.set_ticks(3);
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(fake.pets() > pets);
+        shutdown.trigger();
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn a_missing_device_is_an_error() {
+        let fake = Fake::new();
+        let missing = fake.dir.join("missing");
+        let e = Device::open(&missing, 3).err().unwrap();
+        assert!(e.to_string().starts_with("cannot open watchdog"), "{e}");
+    }
+}